@@ -8,6 +8,7 @@ fn main() {
     let shader_paths = vec![
         "shaders/shader.vert",
         "shaders/shader.frag",
+        "shaders/translucent.frag",
         "shaders/wire.frag",
     ];
 
@@ -7,6 +7,8 @@ layout(location=3) in vec4 model_matrix_1;
 layout(location=4) in vec4 model_matrix_2;
 layout(location=5) in vec4 model_matrix_3;
 layout(location=6) in uint frame_id;
+layout(location=7) in uvec2 flip;
+layout(location=8) in vec2 pivot_offset;
 
 layout(location=0) out vec2 v_tex_coords;
 layout(location=1) flat out uint v_tex_id;
@@ -27,6 +29,12 @@ void main() {
     );
 
     v_tex_coords = a_tex_coords;
+    if (flip.x != 0u) {
+        v_tex_coords.x = 1.0 - v_tex_coords.x;
+    }
+    if (flip.y != 0u) {
+        v_tex_coords.y = 1.0 - v_tex_coords.y;
+    }
     v_tex_id = frame_id;
 
     vec4 centre = vec4(vec3(0.0), 1.0);
@@ -34,7 +42,10 @@ void main() {
     vec4 p_c = persp * model_matrix * centre;
 
     vec4 o_c = ortho * model_matrix * centre;
-    vec4 o_pos = ortho * model_matrix * a_position;
+    // The pivot is applied to the quad rather than the centre so it is offset
+    // orthographically like the rest of the quad.
+    vec4 local_pos = a_position + vec4(pivot_offset, 0.0, 0.0);
+    vec4 o_pos = ortho * model_matrix * local_pos;
 
     vec4 o_c_ndc = o_c/o_c.w;
     vec4 p_c_ndc =  p_c/p_c.w;
@@ -1,13 +1,121 @@
 use crate::asset::SpriteRegistry;
 use crate::{renderer::Renderer, Game};
+use glam::Vec2;
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 use winit::{
     dpi::LogicalSize,
     event::{self, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
+    window::Fullscreen,
 };
 
 pub const WINDOW_SIZE: LogicalSize<u32> = LogicalSize::new(1280, 720);
 
+/// Size of the window's drawable area in physical pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WindowSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl WindowSize {
+    /// Converts a position in physical pixels from the top left of the window into normalised
+    /// device coordinates.
+    pub fn to_ndc(&self, position: Vec2) -> Vec2 {
+        Vec2::new(
+            2.0 * position.x / self.width as f32 - 1.0,
+            1.0 - 2.0 * position.y / self.height as f32,
+        )
+    }
+}
+
+/// How the window covers the screen. Games change it through the resource of the same name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WindowMode {
+    #[default]
+    Windowed,
+    /// A window without decorations covering the monitor it is on.
+    Borderless,
+    /// Takes over the monitor at its largest video mode.
+    Fullscreen,
+}
+
+impl WindowMode {
+    /// The mode F11 switches to, between windowed and borderless.
+    pub fn toggled(self) -> Self {
+        match self {
+            WindowMode::Windowed => WindowMode::Borderless,
+            WindowMode::Borderless | WindowMode::Fullscreen => WindowMode::Windowed,
+        }
+    }
+}
+
+/// Keys the app handles itself rather than passing to the game.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Hotkey {
+    ToggleFullscreen,
+    Screenshot,
+}
+
+impl Hotkey {
+    /// The hotkey an event presses or releases. Releases are matched too so the game never
+    /// sees half of a key press.
+    fn from_event(event: &WindowEvent<'_>) -> Option<(Hotkey, event::ElementState)> {
+        match event {
+            WindowEvent::KeyboardInput {
+                input:
+                    event::KeyboardInput {
+                        virtual_keycode: Some(key),
+                        state,
+                        ..
+                    },
+                ..
+            } => match key {
+                event::VirtualKeyCode::F11 => Some((Hotkey::ToggleFullscreen, *state)),
+                event::VirtualKeyCode::F12 => Some((Hotkey::Screenshot, *state)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Records the new window size for the game and the swap chain. Returns whether the swap chain
+/// and depth texture need rebuilding, which they can not be while the window is minimised and
+/// has no size.
+fn resize(
+    game: &mut Game,
+    sc_desc: &mut wgpu::SwapChainDescriptor,
+    size: winit::dpi::PhysicalSize<u32>,
+) -> bool {
+    game.world.insert_resource(WindowSize {
+        width: size.width,
+        height: size.height,
+    });
+    if size.width == 0 || size.height == 0 {
+        return false;
+    }
+    sc_desc.width = size.width;
+    sc_desc.height = size.height;
+    true
+}
+
+impl Default for WindowSize {
+    fn default() -> Self {
+        WindowSize {
+            width: WINDOW_SIZE.width,
+            height: WINDOW_SIZE.height,
+        }
+    }
+}
+
+/// Files to save the next drawn frame to, queued with `Game::save_screenshot`.
+#[derive(Default)]
+pub(crate) struct Screenshots(pub Vec<PathBuf>);
+
 pub struct App {
     window: winit::window::Window,
     instance: wgpu::Instance,
@@ -45,35 +153,9 @@ impl App {
             .await
             .unwrap();
 
-        let optional_features = wgpu::Features::empty()
-            | wgpu::Features::SAMPLED_TEXTURE_BINDING_ARRAY
-            | wgpu::Features::SAMPLED_TEXTURE_ARRAY_DYNAMIC_INDEXING
-            | wgpu::Features::NON_FILL_POLYGON_MODE;
-        let required_features = wgpu::Features::empty();
-        let adapter_features = adapter.features();
-        assert!(
-            adapter_features.contains(required_features),
-            "Adapter does not support required features for this example: {:?}",
-            required_features - adapter_features
-        );
-
-        let limits = wgpu::Limits {
-            max_sampled_textures_per_shader_stage: 1024,
-            ..Default::default()
-        };
-
-        let trace_dir = std::env::var("WGPU_TRACE");
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    label: wgpu::Label::None,
-                    features: (optional_features & adapter_features) | required_features,
-                    limits,
-                },
-                trace_dir.ok().as_ref().map(std::path::Path::new),
-            )
+        let (device, queue) = request_device(&adapter)
             .await
-            .unwrap();
+            .expect("Failed to create the device");
 
         App {
             window,
@@ -86,8 +168,34 @@ impl App {
         }
     }
 
+    fn fullscreen(&self, mode: WindowMode) -> Option<Fullscreen> {
+        match mode {
+            WindowMode::Windowed => None,
+            WindowMode::Borderless => Some(Fullscreen::Borderless(None)),
+            WindowMode::Fullscreen => {
+                let video_mode = self.window.current_monitor().and_then(|monitor| {
+                    monitor.video_modes().max_by_key(|mode| {
+                        let size = mode.size();
+                        (
+                            size.width * size.height,
+                            mode.refresh_rate(),
+                            mode.bit_depth(),
+                        )
+                    })
+                });
+                match video_mode {
+                    Some(video_mode) => Some(Fullscreen::Exclusive(video_mode)),
+                    None => {
+                        log::warn!("No video modes for fullscreen, using borderless instead");
+                        Some(Fullscreen::Borderless(None))
+                    }
+                }
+            }
+        }
+    }
+
     pub fn run(mut self, event_loop: EventLoop<()>, mut game: Game, sprites: SpriteRegistry) {
-        let sc_desc = wgpu::SwapChainDescriptor {
+        let mut sc_desc = wgpu::SwapChainDescriptor {
             usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
             width: self.size.width,
             height: self.size.height,
@@ -97,7 +205,22 @@ impl App {
         };
         let mut swap_chain = self.device.create_swap_chain(&self.surface, &sc_desc);
 
+        game.set_sprite_catalog(sprites.catalog());
+        game.world.insert_resource(WindowSize {
+            width: self.size.width,
+            height: self.size.height,
+        });
+
+        #[cfg(feature = "gamepad")]
+        if game.gamepads.is_none() {
+            match crate::gamepad::GilrsSource::new() {
+                Ok(source) => game.set_gamepad_source(source),
+                Err(err) => log::warn!("Gamepads are unavailable: {}", err),
+            }
+        }
+
         let mut renderer = Renderer::init(&sc_desc, &mut self.device, &self.queue, sprites);
+        let mut window_mode = WindowMode::Windowed;
 
         log::info!("Entering render loop...");
         event_loop.run(move |event, _, control_flow| {
@@ -105,6 +228,11 @@ impl App {
 
             match event {
                 event::Event::MainEventsCleared => {
+                    let mode = *game.world.get_resource::<WindowMode>().unwrap();
+                    if mode != window_mode {
+                        self.window.set_fullscreen(self.fullscreen(mode));
+                        window_mode = mode;
+                    }
                     self.window.request_redraw();
                 }
                 event::Event::WindowEvent { event, .. } => match event {
@@ -118,11 +246,45 @@ impl App {
                         ..
                     }
                     | WindowEvent::CloseRequested => {
+                        if let Err(err) = game.stop_recording() {
+                            log::error!("Failed to write input recording: {}", err);
+                        }
                         *control_flow = ControlFlow::Exit;
                     }
+                    WindowEvent::Resized(size)
+                    | WindowEvent::ScaleFactorChanged {
+                        new_inner_size: &mut size,
+                        ..
+                    } => {
+                        self.size = size;
+                        // A minimised window has no size and nothing is drawn until it returns.
+                        if resize(&mut game, &mut sc_desc, size) {
+                            swap_chain = self.device.create_swap_chain(&self.surface, &sc_desc);
+                            renderer.resize(&self.device, &sc_desc);
+                        }
+                    }
+                    event if Hotkey::from_event(&event).is_some() => {
+                        match Hotkey::from_event(&event) {
+                            Some((Hotkey::ToggleFullscreen, event::ElementState::Pressed)) => {
+                                let mut mode = game.world.get_resource_mut::<WindowMode>().unwrap();
+                                *mode = mode.toggled();
+                            }
+                            Some((Hotkey::Screenshot, event::ElementState::Pressed)) => {
+                                let millis = SystemTime::now()
+                                    .duration_since(UNIX_EPOCH)
+                                    .map(|time| time.as_millis())
+                                    .unwrap_or_default();
+                                game.save_screenshot(format!("screenshot-{}.png", millis));
+                            }
+                            _ => (),
+                        }
+                    }
                     _ => game.capture_input_event(event),
                 },
                 event::Event::RedrawRequested(_) => {
+                    if self.size.width == 0 || self.size.height == 0 {
+                        return;
+                    }
                     let frame = match swap_chain.get_current_frame() {
                         Ok(frame) => frame,
                         Err(_) => {
@@ -134,7 +296,29 @@ impl App {
                     };
 
                     let scene = game.run();
-                    game.clear_pressed_with_frame();
+
+                    let screenshots = std::mem::take(
+                        &mut game.world.get_resource_mut::<Screenshots>().unwrap().0,
+                    );
+                    if !screenshots.is_empty() {
+                        let image = renderer.capture(
+                            &self.device,
+                            &self.queue,
+                            &scene,
+                            self.size.width,
+                            self.size.height,
+                        );
+                        for path in screenshots {
+                            match image.save(&path) {
+                                Ok(()) => log::info!("Saved screenshot to {}", path.display()),
+                                Err(err) => log::error!(
+                                    "Failed to save screenshot to {}: {}",
+                                    path.display(),
+                                    err
+                                ),
+                            }
+                        }
+                    }
 
                     renderer.render(&frame.output, &self.device, &self.queue, &sc_desc, scene);
                 }
@@ -143,3 +327,185 @@ impl App {
         });
     }
 }
+
+/// Draws a game without a window, eg. to capture frames on a machine without a display.
+pub struct Headless {
+    _instance: wgpu::Instance,
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    renderer: Renderer,
+    size: WindowSize,
+}
+
+impl Headless {
+    /// Draws frames of `width` x `height` on the first adapter found, preferring GPUs over
+    /// software adapters. Returns `None` when there is no adapter at all or it can not create a
+    /// device.
+    pub async fn new(
+        game: &mut Game,
+        sprites: SpriteRegistry,
+        width: u32,
+        height: u32,
+    ) -> Option<Headless> {
+        let instance = wgpu::Instance::new(wgpu::BackendBit::all());
+        let adapter = instance
+            .enumerate_adapters(wgpu::BackendBit::all())
+            .min_by_key(|adapter| match adapter.get_info().device_type {
+                wgpu::DeviceType::DiscreteGpu => 0,
+                wgpu::DeviceType::IntegratedGpu => 1,
+                wgpu::DeviceType::VirtualGpu => 2,
+                wgpu::DeviceType::Other => 3,
+                wgpu::DeviceType::Cpu => 4,
+            })?;
+        let info = adapter.get_info();
+        log::info!("Drawing headless with {} ({:?})", info.name, info.backend);
+
+        let (mut device, queue) = match request_device(&adapter).await {
+            Ok(device) => device,
+            Err(err) => {
+                log::warn!("Failed to create a device on {}: {}", info.name, err);
+                return None;
+            }
+        };
+
+        let sc_desc = wgpu::SwapChainDescriptor {
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
+            width,
+            height,
+            present_mode: wgpu::PresentMode::Fifo,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+        };
+
+        let size = WindowSize { width, height };
+        game.set_sprite_catalog(sprites.catalog());
+        game.world.insert_resource(size);
+
+        let renderer = Renderer::init(&sc_desc, &mut device, &queue, sprites);
+
+        Some(Headless {
+            _instance: instance,
+            device,
+            queue,
+            renderer,
+            size,
+        })
+    }
+
+    /// Draws the game as it is now, without advancing it.
+    pub fn capture(&mut self, game: &mut Game) -> RgbaImage {
+        let scene = game.build_scene();
+        self.renderer.capture(
+            &self.device,
+            &self.queue,
+            &scene,
+            self.size.width,
+            self.size.height,
+        )
+    }
+}
+
+/// Requests a device with the features the renderer draws with, where the adapter has them.
+async fn request_device(
+    adapter: &wgpu::Adapter,
+) -> Result<(wgpu::Device, wgpu::Queue), wgpu::RequestDeviceError> {
+    let optional_features = wgpu::Features::empty()
+        | wgpu::Features::SAMPLED_TEXTURE_BINDING_ARRAY
+        | wgpu::Features::SAMPLED_TEXTURE_ARRAY_DYNAMIC_INDEXING
+        | wgpu::Features::NON_FILL_POLYGON_MODE;
+    let required_features = wgpu::Features::empty();
+    let adapter_features = adapter.features();
+    assert!(
+        adapter_features.contains(required_features),
+        "Adapter does not support required features for this example: {:?}",
+        required_features - adapter_features
+    );
+
+    let limits = wgpu::Limits {
+        max_sampled_textures_per_shader_stage: 1024,
+        ..Default::default()
+    };
+
+    let trace_dir = std::env::var("WGPU_TRACE");
+    adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                label: wgpu::Label::None,
+                features: (optional_features & adapter_features) | required_features,
+                limits,
+            },
+            trace_dir.ok().as_ref().map(std::path::Path::new),
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use winit::dpi::PhysicalSize;
+    use winit::event::{DeviceId, ElementState, KeyboardInput, VirtualKeyCode};
+
+    #[allow(deprecated)]
+    fn key(key: VirtualKeyCode, state: ElementState) -> WindowEvent<'static> {
+        WindowEvent::KeyboardInput {
+            // Never passed to winit.
+            device_id: unsafe { DeviceId::dummy() },
+            input: KeyboardInput {
+                scancode: 0,
+                state,
+                virtual_keycode: Some(key),
+                modifiers: Default::default(),
+            },
+            is_synthetic: false,
+        }
+    }
+
+    #[test]
+    fn hotkeys_are_matched_when_pressed_and_released() {
+        for state in [ElementState::Pressed, ElementState::Released].iter() {
+            assert_eq!(
+                Hotkey::from_event(&key(VirtualKeyCode::F11, *state)),
+                Some((Hotkey::ToggleFullscreen, *state))
+            );
+            assert_eq!(
+                Hotkey::from_event(&key(VirtualKeyCode::F12, *state)),
+                Some((Hotkey::Screenshot, *state))
+            );
+            assert_eq!(Hotkey::from_event(&key(VirtualKeyCode::A, *state)), None);
+        }
+        assert_eq!(Hotkey::from_event(&WindowEvent::Focused(true)), None);
+    }
+
+    #[test]
+    fn full_screen_toggles_back_to_a_window() {
+        assert_eq!(WindowMode::Windowed.toggled(), WindowMode::Borderless);
+        assert_eq!(WindowMode::Borderless.toggled(), WindowMode::Windowed);
+        assert_eq!(WindowMode::Fullscreen.toggled(), WindowMode::Windowed);
+    }
+
+    #[test]
+    fn resizing_rebuilds_the_swap_chain_unless_minimised() {
+        let mut game = Game::new();
+        let mut sc_desc = wgpu::SwapChainDescriptor {
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
+            width: 1280,
+            height: 720,
+            present_mode: wgpu::PresentMode::Fifo,
+            format: wgpu::TextureFormat::Bgra8UnormSrgb,
+        };
+
+        assert!(resize(&mut game, &mut sc_desc, PhysicalSize::new(800, 600)));
+        assert_eq!((sc_desc.width, sc_desc.height), (800, 600));
+        assert_eq!(
+            *game.world.get_resource::<WindowSize>().unwrap(),
+            WindowSize {
+                width: 800,
+                height: 600
+            }
+        );
+
+        // The last size is kept for the swap chain, the game sees the window has no size.
+        assert!(!resize(&mut game, &mut sc_desc, PhysicalSize::new(0, 0)));
+        assert_eq!((sc_desc.width, sc_desc.height), (800, 600));
+        assert_eq!(game.world.get_resource::<WindowSize>().unwrap().width, 0);
+    }
+}
@@ -1,5 +1,9 @@
-use crate::sprite::AnimTimeline;
+use crate::renderer::sprite::PIXELS_PER_METRE;
+use crate::sprite::{AnimTimeline, KeyFrame, Pivot};
+use crate::text::{FontError, FontFile, FontMetrics};
+use glam::Vec2;
 use image::{GenericImage, RgbaImage};
+use serde::Deserialize;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
@@ -17,6 +21,36 @@ impl SpriteRegistry {
         self.0.push(data);
         self.0.len() - 1
     }
+
+    pub fn find(&self, id: &str) -> Option<SpriteId> {
+        self.0.iter().position(|data| data.id == id)
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, SpriteData> {
+        self.0.iter()
+    }
+
+    pub fn catalog(&self) -> SpriteCatalog {
+        SpriteCatalog(
+            self.0
+                .iter()
+                .map(|data| {
+                    let (width, height) = data
+                        .frames
+                        .first()
+                        .map(|frame| frame.dimensions())
+                        .unwrap_or((0, 0));
+                    SpriteInfo {
+                        id: data.id.clone(),
+                        width,
+                        height,
+                        pivots: data.pivots.clone(),
+                        font: data.font.clone(),
+                    }
+                })
+                .collect(),
+        )
+    }
 }
 
 impl IntoIterator for SpriteRegistry {
@@ -35,15 +69,69 @@ pub struct View {
     pub height: u32,
 }
 
+/// Everything about a sprite except its images. The game keeps these so it can place
+/// instances without access to the renderer.
+#[derive(Clone, Debug)]
+pub struct SpriteInfo {
+    pub id: String,
+    /// Size of the quad in pixels, taken from the first frame.
+    pub width: u32,
+    pub height: u32,
+    /// Pivot of each frame, see [`Pivot`].
+    pub pivots: Vec<Vec2>,
+    /// Set for sprites loaded with [`SpriteData::load_font`].
+    pub font: Option<FontMetrics>,
+}
+
+impl SpriteInfo {
+    /// Offset in metres that moves the pivot of `frame` onto the entity position.
+    pub fn pivot_offset(&self, frame: u8) -> Vec2 {
+        let pivot = self
+            .pivots
+            .get(frame as usize)
+            .copied()
+            .unwrap_or_else(|| Pivot::centre().into());
+        let size = Vec2::new(self.width as f32, self.height as f32) / PIXELS_PER_METRE as f32;
+        Vec2::new((0.5 - pivot.x) * size.x, (pivot.y - 0.5) * size.y)
+    }
+}
+
+pub struct SpriteCatalog(pub Vec<SpriteInfo>);
+
+impl SpriteCatalog {
+    pub fn get(&self, id: SpriteId) -> Option<&SpriteInfo> {
+        self.0.get(id)
+    }
+
+    pub fn find(&self, id: &str) -> Option<SpriteId> {
+        self.0.iter().position(|info| info.id == id)
+    }
+}
+
 pub struct SpriteData {
     pub id: String,
     pub frames: Vec<RgbaImage>,
+    pub pivots: Vec<Vec2>,
+    pub font: Option<FontMetrics>,
+}
+
+/// An animated sprite file is either a bare list of animations, or an object that also sets a
+/// pivot for every frame that does not define its own.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AnimatedSpriteFile {
+    Animations(Vec<Vec<KeyFrame>>),
+    WithPivot {
+        pivot: Pivot,
+        animations: Vec<Vec<KeyFrame>>,
+    },
 }
 
 impl SpriteData {
     pub fn load(id: &str, frames: Vec<&str>) -> Self {
         SpriteData {
             id: id.to_string(),
+            pivots: vec![Pivot::centre().into(); frames.len()],
             frames: frames
                 .iter()
                 .map(|path| {
@@ -52,9 +140,76 @@ impl SpriteData {
                         .into_rgba8()
                 })
                 .collect(),
+            font: None,
         }
     }
 
+    /// Slices a tileset image into `tile_width` x `tile_height` frames, row by row from the top
+    /// left, so a tile's frame is its index in the tileset.
+    pub fn load_tileset(id: &str, file: &str, tile_width: u32, tile_height: u32) -> Self {
+        let image = image::open(file)
+            .expect("valid tileset path provided")
+            .into_rgba8();
+        Self::from_tileset_image(id, image, tile_width, tile_height)
+    }
+
+    /// Slices an already loaded tileset image, like [`SpriteData::load_tileset`].
+    pub fn from_tileset_image(
+        id: &str,
+        mut image: RgbaImage,
+        tile_width: u32,
+        tile_height: u32,
+    ) -> Self {
+        let columns = image.width() / tile_width;
+        let rows = image.height() / tile_height;
+
+        let frames: Vec<RgbaImage> = (0..rows)
+            .flat_map(|row| (0..columns).map(move |column| (column, row)))
+            .map(|(column, row)| {
+                image
+                    .sub_image(
+                        column * tile_width,
+                        row * tile_height,
+                        tile_width,
+                        tile_height,
+                    )
+                    .to_image()
+            })
+            .collect();
+
+        SpriteData {
+            id: id.to_string(),
+            pivots: vec![Pivot::centre().into(); frames.len()],
+            frames,
+            font: None,
+        }
+    }
+
+    /// Loads a bitmap font from a metrics file naming an image of equally sized character
+    /// cells. Each character becomes a frame of the sprite.
+    pub fn load_font(id: &str, file: &str) -> Result<Self, FontError> {
+        let mut s = String::new();
+        File::open(file)?.read_to_string(&mut s)?;
+        let metrics: FontFile = serde_json::from_str(&s)?;
+        let font = FontMetrics::new(&metrics)?;
+
+        let glyphs = metrics.characters.chars().count();
+        let image = image::open(&metrics.image)?.into_rgba8();
+        let mut sprite =
+            Self::from_tileset_image(id, image, metrics.cell_width, metrics.cell_height);
+        sprite.frames.truncate(glyphs);
+        sprite.pivots.truncate(glyphs);
+        sprite.font = Some(font);
+        Ok(sprite)
+    }
+
+    /// Use the same pivot for every frame, eg. `Vec2::new(0.5, 1.0)` to anchor on the bottom
+    /// edge.
+    pub fn with_pivot(mut self, pivot: Vec2) -> Self {
+        self.pivots = vec![pivot; self.frames.len()];
+        self
+    }
+
     pub fn load_from_json(id: &str, file: &str) -> (AnimTimeline, SpriteData) {
         let path = Path::new(file);
 
@@ -63,7 +218,24 @@ impl SpriteData {
         let mut s = String::new();
         file.read_to_string(&mut s).unwrap();
 
-        let deserialized: AnimTimeline = serde_json::from_str(&s).unwrap();
+        let (default_pivot, animations) = match serde_json::from_str(&s).unwrap() {
+            AnimatedSpriteFile::Animations(animations) => (None, animations),
+            AnimatedSpriteFile::WithPivot { pivot, animations } => (Some(pivot), animations),
+        };
+
+        let deserialized = AnimTimeline(animations);
+
+        let pivots = deserialized
+            .0
+            .iter()
+            .flatten()
+            .map(|i| {
+                i.pivot
+                    .or(default_pivot)
+                    .unwrap_or_else(Pivot::centre)
+                    .into()
+            })
+            .collect();
 
         let mut frames = Vec::new();
 
@@ -83,6 +255,8 @@ impl SpriteData {
         let sprite_data = SpriteData {
             id: id.to_string(),
             frames: frames.into_iter().flatten().collect(),
+            pivots,
+            font: None,
         };
         (deserialized, sprite_data)
     }
@@ -93,3 +267,101 @@ impl Default for SpriteRegistry {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(width: u32, height: u32, pivot: Vec2) -> SpriteInfo {
+        SpriteInfo {
+            id: "sprite".to_string(),
+            width,
+            height,
+            pivots: vec![pivot],
+            font: None,
+        }
+    }
+
+    #[test]
+    fn pivots_move_the_quad_so_the_pivot_sits_on_the_position() {
+        // 1 x 2 metres.
+        assert_eq!(
+            info(32, 64, Pivot::centre().into()).pivot_offset(0),
+            Vec2::zero()
+        );
+        assert_eq!(
+            info(32, 64, Vec2::new(0.5, 1.0)).pivot_offset(0),
+            Vec2::new(0.0, 1.0)
+        );
+        assert_eq!(
+            info(32, 64, Vec2::new(0.0, 0.0)).pivot_offset(0),
+            Vec2::new(0.5, -1.0)
+        );
+        // Frames without a pivot of their own use the centre.
+        assert_eq!(
+            info(32, 64, Vec2::new(0.0, 0.0)).pivot_offset(3),
+            Vec2::zero()
+        );
+    }
+
+    #[test]
+    fn frames_use_their_own_pivot_or_the_file_default() {
+        let dir = std::env::temp_dir();
+        let image = dir.join("erlking_asset_pivots.png");
+        RgbaImage::new(64, 32).save(&image).unwrap();
+        let file = dir.join("erlking_asset_pivots.json");
+        let frame = |x: u32, pivot: &str| {
+            format!(
+                r#"{{"png": {:?}, "time": 0.1, "view": {{"x": {}, "y": 0, "width": 32, "height": 32}}{}}}"#,
+                image, x, pivot
+            )
+        };
+        std::fs::write(
+            &file,
+            format!(
+                r#"{{"pivot": {{"x": 0.5, "y": 1.0}}, "animations": [[{}, {}]]}}"#,
+                frame(0, ""),
+                frame(32, r#", "pivot": {"x": 0.0, "y": 0.25}"#)
+            ),
+        )
+        .unwrap();
+
+        let (_, sprite) = SpriteData::load_from_json("sprite", file.to_str().unwrap());
+        assert_eq!(sprite.frames.len(), 2);
+        assert_eq!(
+            sprite.pivots,
+            vec![Vec2::new(0.5, 1.0), Vec2::new(0.0, 0.25)]
+        );
+    }
+
+    #[test]
+    fn fonts_are_sliced_into_one_frame_per_character() {
+        let dir = std::env::temp_dir();
+        let image = dir.join("erlking_font.png");
+        RgbaImage::new(32, 20).save(&image).unwrap();
+        let file = dir.join("erlking_font.json");
+        let metrics = |characters: &str| {
+            format!(
+                r#"{{"image": {:?}, "cell_width": 8, "cell_height": 10, "characters": {:?}, "advance": 7}}"#,
+                image, characters
+            )
+        };
+
+        std::fs::write(&file, metrics("abcde")).unwrap();
+        let font = SpriteData::load_font("font", file.to_str().unwrap()).unwrap();
+        assert_eq!(font.frames.len(), 5);
+        assert_eq!(font.pivots.len(), 5);
+        assert_eq!(font.font.unwrap().measure("abc"), Vec2::new(21.0, 10.0));
+
+        let too_many = "x".repeat(crate::TEXTURE_ARRAY_SIZE + 1);
+        std::fs::write(&file, metrics(&too_many)).unwrap();
+        assert!(matches!(
+            SpriteData::load_font("font", file.to_str().unwrap()),
+            Err(FontError::TooManyCharacters(_))
+        ));
+        assert!(matches!(
+            SpriteData::load_font("font", "missing.json"),
+            Err(FontError::Io(_))
+        ));
+    }
+}
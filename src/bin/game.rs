@@ -1,29 +1,30 @@
 #![allow(clippy::single_match)]
 extern crate erlking;
 
-use bevy_ecs::prelude::IntoSystem;
-use erlking::asset::{SpriteId, SpriteRegistry};
-use erlking::camera::update_camera_position;
+use bevy_ecs::prelude::{IntoSystem, ParallelSystemDescriptorCoercion, Query, Res};
+use erlking::asset::SpriteRegistry;
+use erlking::input::{Action, ActionState};
 use erlking::player::{
-    flip_sprite, get_input_from_keystate, move_players, update_animation_state,
+    flip_sprite, get_input_from_actions, move_players, update_animation_state,
     update_player_state_machine, PlayerInput, PlayerState,
 };
+use erlking::pointer::Mouse;
 use erlking::sprite::Sprite;
+use erlking::text::ScreenText;
+use erlking::tiled::TiledMap;
+use erlking::ui::{Anchor, NineSlice, UiRect};
 use erlking::{
     asset::SpriteData,
-    camera::{ActiveCamera, ParallaxCamera},
+    camera::{ActiveCamera, CameraFollow, CameraShake, CameraZoom, ParallaxCamera},
     App, Collider, Game, MoveSpeed, Position, Rotation, Scale, Terrain, Velocity,
 };
-use glam::{Quat, Vec3};
+use glam::{Quat, Vec2, Vec3};
 use parry2d::na::Vector2;
 use parry2d::shape::Cuboid;
 use std::time::Instant;
 use winit::event_loop::EventLoop;
 
 fn main() {
-    let event_loop = EventLoop::new();
-    let app = futures::executor::block_on(App::new("erlking", &event_loop));
-
     let mut sprite_registry = SpriteRegistry::new();
 
     let apple_sprite = sprite_registry.insert(SpriteData::load("apple", vec!["assets/apple.png"]));
@@ -32,9 +33,17 @@ fn main() {
     let baobab_sprite =
         sprite_registry.insert(SpriteData::load("baobab", vec!["assets/baobab.png"]));
     let beech_sprite = sprite_registry.insert(SpriteData::load("beech", vec!["assets/beech.png"]));
-    let dark_block_sprite = sprite_registry.insert(SpriteData::load(
-        "dark_block",
-        vec!["assets/dark_block.png"],
+
+    let font_sprite = sprite_registry.insert(
+        SpriteData::load_font("font", "assets/font/dejavu_sans_mono.json")
+            .expect("valid font provided"),
+    );
+
+    let panel_sprite = sprite_registry.insert(SpriteData::load_tileset(
+        "ui_panel",
+        "assets/ui_panel.png",
+        8,
+        8,
     ));
 
     let (anim_timeline, player_sprite_data) =
@@ -61,10 +70,11 @@ fn main() {
         Position(Vec3::new(0.0, 0.2, 20.0)),
         Rotation(Quat::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), 0.0)),
         Velocity(Vec3::new(0.0, 0.0, 0.0)),
-        Scale(1),
+        Scale(Vec3::one()),
         Sprite::new(player_sprite),
         anim_timeline,
         PlayerInput::None,
+        ActionState::default(),
         PlayerState::Standing(Instant::now()),
         Collider(Cuboid::new(Vector2::new(0.4, 0.6))),
         movespeed,
@@ -73,7 +83,7 @@ fn main() {
     let apple = (
         Position(Vec3::new(-2.0, 0.0, 20.0)),
         Rotation(Quat::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), 0.0)),
-        Scale(1),
+        Scale(Vec3::one()),
         Sprite::new(apple_sprite),
         Collider(Cuboid::new(Vector2::new(0.5, 0.5))),
         Terrain,
@@ -82,7 +92,7 @@ fn main() {
     let ashberry = (
         Position(Vec3::new(2.0, 0.0, 20.0)),
         Rotation(Quat::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), 0.0)),
-        Scale(1),
+        Scale(Vec3::one()),
         Sprite::new(ashberry_sprite),
         Collider(Cuboid::new(Vector2::new(0.5, 0.5))),
         Terrain,
@@ -91,7 +101,7 @@ fn main() {
     let baobab = (
         Position(Vec3::new(3.0, 0.0, 55.0)),
         Rotation(Quat::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), 0.0)),
-        Scale(1),
+        Scale(Vec3::one()),
         Sprite::new(baobab_sprite),
         Collider(Cuboid::new(Vector2::new(0.5, 0.5))),
         Terrain,
@@ -100,42 +110,103 @@ fn main() {
     let beech = (
         Position(Vec3::new(-3.5, 0.0, 95.0)),
         Rotation(Quat::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), 0.0)),
-        Scale(1),
+        Scale(Vec3::one()),
         Sprite::new(beech_sprite),
         Collider(Cuboid::new(Vector2::new(0.5, 0.5))),
         Terrain,
     );
 
-    game.spawn(player);
+    let player = game.spawn(player);
     game.spawn(apple);
     game.spawn(ashberry);
     game.spawn(baobab);
     game.spawn(beech);
-    game.spawn(camera);
+    let camera = game.spawn(camera);
+    game.insert(
+        camera,
+        CameraFollow::new(player)
+            .with_offset(Vec2::new(0.0, 2.8))
+            .with_dead_zone(Vec2::new(1.0, 1.5))
+            .with_smoothing(0.25)
+            .with_look_ahead(2.0),
+    );
+    game.insert(camera, CameraShake::new(Vec2::new(0.3, 0.2)));
+    game.insert(camera, CameraZoom::new(1.0, 0.15));
+    game.spawn((
+        UiRect::new(
+            Anchor::TopLeft,
+            Vec2::new(16.0, 16.0),
+            Vec2::new(248.0, 34.0),
+        ),
+        NineSlice::new(panel_sprite),
+        ScreenText::new(
+            font_sprite,
+            "Left/Right: move  A: attack",
+            Vec2::new(12.0, 8.0),
+        ),
+    ));
 
-    game.spawn_batch(floor(dark_block_sprite));
+    TiledMap::load("assets/level.tmj")
+        .expect("Failed to read level")
+        .spawn(&mut game, &mut sprite_registry, 20.0)
+        .expect("Failed to spawn level");
+
+    // Ordered so replays of recorded input play out the same way.
+    game.add_system(get_input_from_actions.system().label("input"));
+    game.add_system(
+        update_player_state_machine
+            .system()
+            .label("state")
+            .after("input"),
+    );
+    game.add_system(update_animation_state.system().after("state"));
+    game.add_system(flip_sprite.system().after("state"));
+    game.add_system(move_players.system().after("state"));
+    game.add_system(shake_on_attack.system());
+    game.add_system(zoom_with_wheel.system());
+
+    game.load_key_bindings("assets/bindings.json")
+        .expect("Failed to read key bindings");
+
+    if let Ok(file) = std::env::var("ERLKING_RECORD") {
+        game.record_input(file);
+    }
+
+    if let Ok(file) = std::env::var("ERLKING_REPLAY") {
+        game.replay_input(file)
+            .expect("Failed to read input recording");
+
+        if std::env::var("ERLKING_HEADLESS").is_ok() {
+            game.set_sprite_catalog(sprite_registry.catalog());
+            game.run_headless();
+            game.stop_recording()
+                .expect("Failed to write input recording");
+            return;
+        }
+    }
 
-    game.add_system(get_input_from_keystate.system());
-    game.add_system(update_player_state_machine.system());
-    game.add_system(update_camera_position.system());
-    game.add_system(update_animation_state.system());
-    game.add_system(flip_sprite.system());
-    game.add_system(move_players.system());
+    let event_loop = EventLoop::new();
+    let app = futures::executor::block_on(App::new("erlking", &event_loop));
 
     app.run(event_loop, game, sprite_registry);
 }
 
-fn floor(sprite_id: SpriteId) -> Vec<(Position, Rotation, Scale, Sprite, Collider, Terrain)> {
-    (-5..5)
-        .map(|i| {
-            (
-                Position(Vec3::new(1.0 * i as f32, -1.0, 20.0)),
-                Rotation(Quat::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), 0.0)),
-                Scale(1),
-                Sprite::new(sprite_id),
-                Collider(Cuboid::new(Vector2::new(0.5, 0.5))),
-                Terrain,
-            )
-        })
-        .collect()
+fn shake_on_attack(actions: Query<&ActionState>, mut cameras: Query<&mut CameraShake>) {
+    if actions
+        .iter()
+        .any(|actions| actions.just_pressed(Action::Attack))
+    {
+        for mut shake in cameras.iter_mut() {
+            shake.add_trauma(0.3);
+        }
+    }
+}
+
+fn zoom_with_wheel(mouse: Res<Mouse>, mut cameras: Query<&mut CameraZoom>) {
+    let wheel = mouse.wheel().y;
+    if wheel != 0.0 {
+        for mut zoom in cameras.iter_mut() {
+            zoom.target = (zoom.target * 1.1f32.powf(wheel)).clamp(0.5, 3.0);
+        }
+    }
 }
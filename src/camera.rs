@@ -1,24 +1,46 @@
-use crate::app::WINDOW_SIZE;
-use crate::player::PlayerState;
+use crate::app::{WindowSize, WINDOW_SIZE};
+use crate::asset::SpriteId;
 use crate::renderer::gpu_primitives::CameraUniform;
 use crate::renderer::sprite::PIXELS_PER_METRE;
-use crate::Position;
-use bevy_ecs::prelude::Query;
-use glam::{Mat4, Vec3, Vec4};
+use crate::time::Timer;
+use crate::transform::GlobalTransform;
+use crate::{Position, Velocity};
+use bevy_ecs::entity::{EntityMap, MapEntities, MapEntitiesError};
+use bevy_ecs::prelude::{Entity, Query, Res};
+use glam::{Mat4, Vec2, Vec3, Vec4};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::BufReader;
+use std::ops::{Add, Mul, Sub};
+use std::path::Path;
 
+#[derive(Serialize, Deserialize)]
 pub struct ActiveCamera;
 
 pub trait Camera {
-    fn generate_matrix(&self) -> CameraUniform;
+    /// Projections for a view showing `size` pixels of the world.
+    fn generate_matrix(&self, size: Vec2) -> CameraUniform;
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct ParallaxCamera {
     pub eye: glam::Vec3,
     pub look_dir: glam::Vec3,
     pub fov_y: f32,
     pub near: f32,
     pub far: f32,
+    /// Added to `eye` when drawing, so effects like `CameraShake` can move the view without
+    /// disturbing whatever positions the eye.
+    #[serde(default)]
+    pub offset: glam::Vec3,
+    /// Magnifies both projections about the centre of the view, above 1 zooms in.
+    #[serde(default = "default_zoom")]
+    pub zoom: f32,
+}
+
+fn default_zoom() -> f32 {
+    1.0
 }
 
 impl ParallaxCamera {
@@ -29,42 +51,327 @@ impl ParallaxCamera {
             fov_y,
             near,
             far,
+            offset: Vec3::zero(),
+            zoom: 1.0,
         }
     }
-    pub fn generate_ortho(&self) -> glam::Mat4 {
-        let h = (WINDOW_SIZE.height / PIXELS_PER_METRE) as f32;
-        let w = (WINDOW_SIZE.width / PIXELS_PER_METRE) as f32;
+    /// Orthographic projection for a view showing `size` pixels of the world, at
+    /// `PIXELS_PER_METRE` before zooming. See [`ScreenScaling`] for how many pixels the window
+    /// shows.
+    pub fn generate_ortho(&self, size: Vec2) -> glam::Mat4 {
+        let h = size.y / PIXELS_PER_METRE as f32 / self.zoom;
+        let w = size.x / PIXELS_PER_METRE as f32 / self.zoom;
 
         let mx_ortho =
             glam::Mat4::orthographic_lh(-w / 2.0, w / 2.0, -h / 2.0, h / 2.0, self.near, self.far);
 
-        let mx_view = look_to_lh(self.eye, self.look_dir, Vec3::unit_y());
+        let mx_view = look_to_lh(self.eye + self.offset, self.look_dir, Vec3::unit_y());
 
         mx_ortho * mx_view
     }
 
-    pub fn generate_perspective(&self) -> glam::Mat4 {
-        let mx_perspective = glam::Mat4::perspective_lh(
-            self.fov_y,
-            WINDOW_SIZE.width as f32 / WINDOW_SIZE.height as f32,
-            self.near,
-            self.far,
-        );
+    pub fn generate_perspective(&self, size: Vec2) -> glam::Mat4 {
+        // Narrowing the field of view by the zoom scales the view about its centre by the same
+        // amount as the orthographic projection, so sprites keep their parallax.
+        let fov_y = 2.0 * ((self.fov_y / 2.0).tan() / self.zoom).atan();
+        let mx_perspective =
+            glam::Mat4::perspective_lh(fov_y, size.x / size.y, self.near, self.far);
 
-        let mx_view = look_to_lh(self.eye, self.look_dir, Vec3::unit_y());
+        let mx_view = look_to_lh(self.eye + self.offset, self.look_dir, Vec3::unit_y());
 
         mx_perspective * mx_view
     }
+
+    /// The point at world depth `z` that the perspective projection of a view showing `size`
+    /// pixels places at `ndc`. Sprite centres are projected in perspective, so an entity at that
+    /// depth under the cursor has its centre near this point.
+    pub fn unproject(&self, ndc: Vec2, size: Vec2, z: f32) -> Vec3 {
+        let inverse = self.generate_perspective(size).inverse();
+        let near = inverse * Vec4::new(ndc.x, ndc.y, 0.0, 1.0);
+        let far = inverse * Vec4::new(ndc.x, ndc.y, 1.0, 1.0);
+        let near = near.truncate() / near.w;
+        let far = far.truncate() / far.w;
+
+        near + (far - near) * ((z - near.z) / (far.z - near.z))
+    }
+
+    /// Where a sprite centred on `point` is drawn in a view showing `size` pixels, in normalised
+    /// device coordinates with depth.
+    pub fn project(&self, point: Vec3, size: Vec2) -> Vec3 {
+        self.project_offset(point, Vec3::zero(), size)
+    }
+
+    /// Where the point `offset` metres from the centre of a sprite at `centre` is drawn. Sprite
+    /// centres are placed in perspective but the quad around them is orthographic, so this is
+    /// not the same as projecting `centre + offset`.
+    pub fn project_offset(&self, centre: Vec3, offset: Vec3, size: Vec2) -> Vec3 {
+        project_vertex(
+            &self.generate_matrix(size),
+            &Mat4::from_translation(centre),
+            offset,
+        )
+    }
+
+    /// The offset from the centre of a sprite at `centre` that is drawn at `ndc`, the inverse of
+    /// `project_offset` for offsets in the sprite's plane. Useful for picking sprites under the
+    /// cursor.
+    pub fn unproject_offset(&self, centre: Vec3, ndc: Vec2, size: Vec2) -> Vec3 {
+        let ortho = self.generate_ortho(size);
+        let persp = self.generate_perspective(size);
+        let centre_ortho = project_point(&ortho, centre);
+        let centre_persp = project_point(&persp, centre);
+        let shifted = ndc - (centre_persp - centre_ortho).truncate();
+
+        project_point(&ortho.inverse(), shifted.extend(centre_ortho.z)) - centre
+    }
+}
+
+/// Transforms `point` by `matrix` and divides by w.
+fn project_point(matrix: &Mat4, point: Vec3) -> Vec3 {
+    let projected = *matrix * point.extend(1.0);
+    projected.truncate() / projected.w
+}
+
+/// Where the vertex shader places `local`, a point in the model space of an instance drawn with
+/// `model`, in normalised device coordinates with depth. This is `shader.vert`: the instance
+/// centre is projected in perspective, and the point is projected orthographically and then
+/// moved by the difference between the two projections of the centre.
+pub(crate) fn project_vertex(uniform: &CameraUniform, model: &Mat4, local: Vec3) -> Vec3 {
+    let ortho = Mat4::from_cols_array(&uniform.ortho);
+    let persp = Mat4::from_cols_array(&uniform.persp);
+    let centre = Vec4::new(0.0, 0.0, 0.0, 1.0);
+
+    let p_c = persp * *model * centre;
+    let o_c = ortho * *model * centre;
+    let o_pos = ortho * *model * local.extend(1.0);
+
+    let d = p_c / p_c.w - o_c / o_c.w;
+    (o_pos / o_pos.w + d).truncate()
 }
 
 impl Camera for ParallaxCamera {
-    fn generate_matrix(&self) -> CameraUniform {
-        let ortho = *self.generate_ortho().as_ref();
-        let persp = *self.generate_perspective().as_ref();
+    fn generate_matrix(&self, size: Vec2) -> CameraUniform {
+        let ortho = *self.generate_ortho(size).as_ref();
+        let persp = *self.generate_perspective(size).as_ref();
         CameraUniform { ortho, persp }
     }
 }
 
+/// How the reference resolution is fitted to the window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScalingPolicy {
+    /// Scales by the largest whole number that fits and letterboxes the rest, so every world
+    /// pixel is a square block of screen pixels.
+    PixelPerfect,
+    /// Shows the reference height, and more or less of the world to the sides as the window
+    /// gets wider or narrower.
+    FitHeight,
+    /// Shows the reference width, and more or less above and below.
+    FitWidth,
+    /// Shows exactly the reference resolution, squashed or stretched to the window.
+    Stretch,
+}
+
+/// How many world pixels the window shows, independent of its size. Cameras drawing into the
+/// window show `reference` pixels fitted by `policy`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ScreenScaling {
+    pub reference: Vec2,
+    pub policy: ScalingPolicy,
+}
+
+impl ScreenScaling {
+    pub fn new(reference: Vec2, policy: ScalingPolicy) -> Self {
+        ScreenScaling { reference, policy }
+    }
+
+    /// Where the cameras draw in a window of the given size and how much they show.
+    pub fn fit(&self, window: &WindowSize) -> ScreenFit {
+        let size = Vec2::new(window.width as f32, window.height as f32);
+        let scale = size / self.reference;
+        let (area, visible) = match self.policy {
+            ScalingPolicy::PixelPerfect => {
+                let scale = scale.x.min(scale.y).floor().max(1.0);
+                let area = (self.reference * scale).min(size);
+                (area, area / scale)
+            }
+            ScalingPolicy::FitHeight => (size, size / scale.y),
+            ScalingPolicy::FitWidth => (size, size / scale.x),
+            ScalingPolicy::Stretch => (size, self.reference),
+        };
+        let origin = ((size - area) / 2.0).floor();
+
+        ScreenFit {
+            area: [
+                origin.x as u32,
+                origin.y as u32,
+                area.x as u32,
+                area.y as u32,
+            ],
+            visible,
+        }
+    }
+}
+
+impl Default for ScreenScaling {
+    fn default() -> Self {
+        ScreenScaling::new(
+            Vec2::new(WINDOW_SIZE.width as f32, WINDOW_SIZE.height as f32),
+            ScalingPolicy::FitHeight,
+        )
+    }
+}
+
+/// The result of fitting [`ScreenScaling`] to the window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScreenFit {
+    /// x, y, width and height in window pixels that cameras draw into, the rest is letterbox.
+    pub area: [u32; 4],
+    /// World pixels shown across the area.
+    pub visible: Vec2,
+}
+
+impl ScreenFit {
+    /// Converts a position in window pixels into normalised device coordinates of the area.
+    pub fn to_ndc(&self, position: Vec2) -> Vec2 {
+        let [x, y, width, height] = self.area;
+        Vec2::new(
+            2.0 * (position.x - x as f32) / width as f32 - 1.0,
+            1.0 - 2.0 * (position.y - y as f32) / height as f32,
+        )
+    }
+
+    /// Converts normalised device coordinates of the area into a position in window pixels.
+    pub fn to_window(&self, ndc: Vec2) -> Vec2 {
+        let [x, y, width, height] = self.area;
+        Vec2::new(
+            x as f32 + (ndc.x + 1.0) / 2.0 * width as f32,
+            y as f32 + (1.0 - ndc.y) / 2.0 * height as f32,
+        )
+    }
+
+    /// Where a sprite centred on `point` is drawn in window pixels, by a camera filling the
+    /// window.
+    pub fn project(&self, camera: &ParallaxCamera, point: Vec3) -> Vec2 {
+        self.to_window(camera.project(point, self.visible).truncate())
+    }
+
+    /// The point at world depth `z` under `position` in window pixels, seen by a camera filling
+    /// the window.
+    pub fn unproject(&self, camera: &ParallaxCamera, position: Vec2, z: f32) -> Vec3 {
+        camera.unproject(self.to_ndc(position), self.visible, z)
+    }
+
+    /// The part of the area a camera with `viewport` draws into and the world pixels it shows,
+    /// so that `project` and `unproject` work for split-screen and inset cameras too.
+    pub fn viewport(&self, viewport: &Viewport) -> ScreenFit {
+        let [x, y, width, height] = self.area;
+        let size = Vec2::new(width as f32, height as f32);
+        let [left, top, width, height] = viewport.pixels(size);
+        ScreenFit {
+            area: [x + left, y + top, width, height],
+            visible: self.visible * Vec2::new(width as f32, height as f32) / size,
+        }
+    }
+
+    /// Whether `position` in window pixels is inside the area.
+    pub fn contains(&self, position: Vec2) -> bool {
+        let [x, y, width, height] = self.area;
+        position.x >= x as f32
+            && position.y >= y as f32
+            && position.x < (x + width) as f32
+            && position.y < (y + height) as f32
+    }
+}
+
+/// What an active camera draws into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CameraTarget {
+    Window,
+    /// The first frame of the sprite, which can then be drawn like any other, eg. as a
+    /// `UiImage` minimap. The sprite's own instances are left out of what the camera draws,
+    /// as its texture can not be read while it is drawn into.
+    Sprite(SpriteId),
+}
+
+/// The part of its target an active camera draws into, as fractions of the target's size from
+/// its top left, so split-screen halves are (0, 0, 0.5, 1) and (0.5, 0, 0.5, 1). In the window
+/// the target is the area [`ScreenScaling`] fits the world into. Cameras are
+/// drawn in increasing `order`, later ones over earlier ones. Cameras without a viewport fill
+/// the window at order 0.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Viewport {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub order: i32,
+    pub target: CameraTarget,
+}
+
+impl Viewport {
+    pub fn new(x: f32, y: f32, width: f32, height: f32) -> Self {
+        Viewport {
+            x,
+            y,
+            width,
+            height,
+            order: 0,
+            target: CameraTarget::Window,
+        }
+    }
+
+    pub fn with_order(mut self, order: i32) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn with_target(mut self, target: CameraTarget) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// The viewport in whole pixels of a target of `size` pixels, as x, y, width and height.
+    pub fn pixels(&self, size: Vec2) -> [u32; 4] {
+        let min = (Vec2::new(self.x, self.y) * size).round();
+        let max = (Vec2::new(self.x + self.width, self.y + self.height) * size)
+            .round()
+            .min(size);
+        let min = min.max(Vec2::zero()).min(max);
+        [
+            min.x as u32,
+            min.y as u32,
+            (max.x - min.x) as u32,
+            (max.y - min.y) as u32,
+        ]
+    }
+}
+
+impl Default for Viewport {
+    fn default() -> Self {
+        Viewport::new(0.0, 0.0, 1.0, 1.0)
+    }
+}
+
+/// Maps window pixels onto the screen with y up, so (0, 0) is the top left corner and
+/// (width, -height) the bottom right. Using the same matrix for both projections removes the
+/// parallax shift from the sprite shader.
+pub(crate) fn screen_space_uniform(window: &WindowSize) -> CameraUniform {
+    let projection = *Mat4::orthographic_lh(
+        0.0,
+        window.width as f32,
+        -(window.height as f32),
+        0.0,
+        0.0,
+        1.0,
+    )
+    .as_ref();
+    CameraUniform {
+        ortho: projection,
+        persp: projection,
+    }
+}
+
 fn look_to_lh(eye: Vec3, dir: Vec3, up: Vec3) -> Mat4 {
     let f = dir.normalize();
     let s = up.cross(f).normalize();
@@ -80,17 +387,750 @@ fn look_to_lh(eye: Vec3, dir: Vec3, up: Vec3) -> Mat4 {
     )
 }
 
-pub fn update_camera_position(
-    player_query: Query<(&PlayerState, &Position)>,
-    mut camera_query: Query<(&ActiveCamera, &mut ParallaxCamera)>,
+/// Moves the camera's eye to keep `target` in view. The target can move within `dead_zone`, a
+/// half width and height in metres, before the camera follows, and the camera leads the target
+/// by `look_ahead` metres in the direction it last moved. `smoothing` is roughly the time in
+/// seconds the camera takes to catch up, with no overshoot, or 0 to stay locked on.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CameraFollow {
+    #[serde(with = "crate::serialization::entity_id")]
+    pub target: Entity,
+    /// Where the eye sits relative to the target.
+    pub offset: Vec2,
+    pub dead_zone: Vec2,
+    pub smoothing: f32,
+    pub look_ahead: f32,
+    /// When false the eye keeps its height.
+    pub vertical: bool,
+    /// Lowest and highest eye position.
+    pub bounds: Option<(Vec2, Vec2)>,
+    focus: Option<Vec2>,
+    velocity: Vec2,
+    direction: f32,
+}
+
+impl CameraFollow {
+    pub fn new(target: Entity) -> Self {
+        CameraFollow {
+            target,
+            offset: Vec2::zero(),
+            dead_zone: Vec2::zero(),
+            smoothing: 0.0,
+            look_ahead: 0.0,
+            vertical: true,
+            bounds: None,
+            focus: None,
+            velocity: Vec2::zero(),
+            direction: 0.0,
+        }
+    }
+
+    pub fn with_offset(mut self, offset: Vec2) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn with_dead_zone(mut self, dead_zone: Vec2) -> Self {
+        self.dead_zone = dead_zone;
+        self
+    }
+
+    pub fn with_smoothing(mut self, smoothing: f32) -> Self {
+        self.smoothing = smoothing;
+        self
+    }
+
+    pub fn with_look_ahead(mut self, look_ahead: f32) -> Self {
+        self.look_ahead = look_ahead;
+        self
+    }
+
+    pub fn with_vertical(mut self, vertical: bool) -> Self {
+        self.vertical = vertical;
+        self
+    }
+
+    pub fn with_bounds(mut self, min: Vec2, max: Vec2) -> Self {
+        self.bounds = Some((min, max));
+        self
+    }
+
+    /// Where the eye should move towards this tick.
+    fn goal(&mut self, target: Vec2, moving: f32, eye: Vec2) -> Vec2 {
+        let target = target + self.offset;
+        let focus = self
+            .focus
+            .unwrap_or(target)
+            .max(target - self.dead_zone)
+            .min(target + self.dead_zone);
+        self.focus = Some(focus);
+
+        if moving != 0.0 {
+            self.direction = moving.signum();
+        }
+
+        let mut goal = focus + Vec2::new(self.look_ahead * self.direction, 0.0);
+        if !self.vertical {
+            goal.y = eye.y;
+        }
+        goal
+    }
+}
+
+impl MapEntities for CameraFollow {
+    fn map_entities(&mut self, entity_map: &EntityMap) -> Result<(), MapEntitiesError> {
+        self.target = entity_map.get(self.target)?;
+        Ok(())
+    }
+}
+
+/// Runs after transforms are propagated, so targets in a hierarchy are followed where they are
+/// drawn this frame. Cameras playing a `CameraPath` are left to it.
+pub fn follow_targets(
+    targets: Query<(&Position, Option<&GlobalTransform>, Option<&Velocity>)>,
+    mut cameras: Query<(&mut ParallaxCamera, &mut CameraFollow, Option<&CameraPath>)>,
+    timer: Res<Timer>,
+) {
+    let dt = timer.elapsed().as_secs_f32();
+
+    for (mut camera, mut follow, path) in cameras.iter_mut() {
+        if path.is_some_and(CameraPath::is_playing) {
+            continue;
+        }
+        let follow = &mut *follow;
+        let (position, global, velocity) = match targets.get(follow.target) {
+            Ok(target) => target,
+            Err(_) => continue,
+        };
+        let target = global
+            .map(|global| global.position)
+            .unwrap_or(position.0)
+            .truncate();
+        let moving = velocity.map(|velocity| velocity.0.x).unwrap_or(0.0);
+        let eye = camera.eye.truncate();
+        let first = follow.focus.is_none();
+        let goal = follow.goal(target, moving, eye);
+
+        // Start on the target rather than sweeping over from wherever the camera was placed.
+        let mut eye = if first || follow.smoothing <= 0.0 {
+            follow.velocity = Vec2::zero();
+            goal
+        } else {
+            smooth_damp(eye, goal, &mut follow.velocity, follow.smoothing, dt)
+        };
+
+        if let Some((min, max)) = follow.bounds {
+            eye = eye.max(min).min(max);
+        }
+
+        camera.eye.x = eye.x;
+        camera.eye.y = eye.y;
+    }
+}
+
+/// Adds shake to a camera's `offset` that grows with the square of `trauma`, so small knocks
+/// barely move the view and big ones throw it around. Trauma wears off by `decay` every second.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CameraShake {
+    /// From 0 to 1.
+    pub trauma: f32,
+    pub decay: f32,
+    /// Largest offset in metres, at full trauma.
+    pub max_offset: Vec2,
+    /// How many times a second the shake changes direction, roughly.
+    pub frequency: f32,
+    time: f32,
+}
+
+impl CameraShake {
+    pub fn new(max_offset: Vec2) -> Self {
+        CameraShake {
+            trauma: 0.0,
+            decay: 1.0,
+            max_offset,
+            frequency: 8.0,
+            time: 0.0,
+        }
+    }
+
+    pub fn with_decay(mut self, decay: f32) -> Self {
+        self.decay = decay;
+        self
+    }
+
+    pub fn with_frequency(mut self, frequency: f32) -> Self {
+        self.frequency = frequency;
+        self
+    }
+
+    pub fn add_trauma(&mut self, trauma: f32) {
+        self.trauma = (self.trauma + trauma).clamp(0.0, 1.0);
+    }
+}
+
+pub fn shake_cameras(
+    mut cameras: Query<(&mut ParallaxCamera, &mut CameraShake)>,
+    timer: Res<Timer>,
 ) {
-    let mut x = 0.0;
+    let dt = timer.elapsed().as_secs_f32();
+
+    for (mut camera, mut shake) in cameras.iter_mut() {
+        shake.time += dt;
+        shake.trauma = (shake.trauma - shake.decay * dt).max(0.0);
+
+        let t = shake.time * shake.frequency * std::f32::consts::TAU;
+        let noise = Vec2::new(wobble(t, 0.0), wobble(t, 17.3));
+        let offset = shake.max_offset * noise * shake.trauma * shake.trauma;
+        camera.offset = offset.extend(0.0);
+    }
+}
 
-    if let Some((_, pos)) = player_query.iter().next() {
-        x = pos.0.x;
+/// Smooth noise from -1 to 1, a few sines of unrelated frequencies summed so it never visibly
+/// repeats. `seed` picks a different curve.
+fn wobble(t: f32, seed: f32) -> f32 {
+    (t + seed).sin() * 0.5
+        + (t * 2.31 + seed * 1.7).sin() * 0.3
+        + (t * 4.67 + seed * 2.9).sin() * 0.2
+}
+
+/// Eases a camera's `zoom` towards `target`, taking roughly `smoothing` seconds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CameraZoom {
+    pub target: f32,
+    pub smoothing: f32,
+    velocity: f32,
+}
+
+impl CameraZoom {
+    pub fn new(target: f32, smoothing: f32) -> Self {
+        CameraZoom {
+            target,
+            smoothing,
+            velocity: 0.0,
+        }
+    }
+}
+
+/// Cameras playing a `CameraPath` are left to it.
+pub fn zoom_cameras(
+    mut cameras: Query<(&mut ParallaxCamera, &mut CameraZoom, Option<&CameraPath>)>,
+    timer: Res<Timer>,
+) {
+    let dt = timer.elapsed().as_secs_f32();
+
+    for (mut camera, mut zoom, path) in cameras.iter_mut() {
+        if path.is_some_and(CameraPath::is_playing) {
+            continue;
+        }
+        let zoom = &mut *zoom;
+        camera.zoom = if zoom.smoothing <= 0.0 {
+            zoom.velocity = 0.0;
+            zoom.target
+        } else {
+            smooth_damp(
+                camera.zoom,
+                zoom.target,
+                &mut zoom.velocity,
+                zoom.smoothing,
+                dt,
+            )
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Ease {
+    Linear,
+    /// Starts and ends slowly.
+    #[default]
+    InOut,
+}
+
+/// Where the camera is `time` seconds into a path. `ease` shapes the move from the previous
+/// keyframe.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CameraKeyframe {
+    pub time: f32,
+    pub eye: Vec3,
+    #[serde(default = "default_zoom")]
+    pub zoom: f32,
+    #[serde(default)]
+    pub ease: Ease,
+}
+
+/// Moves a camera's eye and zoom through keyframes, eg. for cutscenes. `CameraFollow` and
+/// `CameraZoom` on the same camera wait until the path has finished and then ease back from
+/// wherever it ended, while `CameraShake` keeps working throughout.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CameraPath {
+    /// Sorted by time.
+    pub keyframes: Vec<CameraKeyframe>,
+    #[serde(default)]
+    pub looping: bool,
+    #[serde(default)]
+    elapsed: f32,
+}
+
+impl CameraPath {
+    pub fn new(mut keyframes: Vec<CameraKeyframe>) -> Self {
+        keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(Ordering::Equal));
+        CameraPath {
+            keyframes,
+            looping: false,
+            elapsed: 0.0,
+        }
+    }
+
+    pub fn read(file: impl AsRef<Path>) -> std::io::Result<Self> {
+        let reader = BufReader::new(File::open(file)?);
+        let path: CameraPath = serde_json::from_reader(reader)?;
+        Ok(CameraPath::new(path.keyframes).with_looping(path.looping))
+    }
+
+    pub fn with_looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |keyframe| keyframe.time)
+    }
+
+    pub fn is_playing(&self) -> bool {
+        !self.keyframes.is_empty() && (self.looping || self.elapsed < self.duration())
+    }
+
+    /// Plays the path again from the start.
+    pub fn restart(&mut self) {
+        self.elapsed = 0.0;
+    }
+
+    /// Eye and zoom at `time` seconds into the path.
+    pub fn sample(&self, time: f32) -> Option<(Vec3, f32)> {
+        let first = self.keyframes.first()?;
+        let next = match self
+            .keyframes
+            .iter()
+            .position(|keyframe| keyframe.time > time)
+        {
+            Some(0) => return Some((first.eye, first.zoom)),
+            Some(next) => next,
+            None => {
+                let last = self.keyframes.last()?;
+                return Some((last.eye, last.zoom));
+            }
+        };
+        let from = &self.keyframes[next - 1];
+        let to = &self.keyframes[next];
+
+        let t = (time - from.time) / (to.time - from.time);
+        let t = match to.ease {
+            Ease::Linear => t,
+            Ease::InOut => t * t * (3.0 - 2.0 * t),
+        };
+        Some((
+            from.eye + (to.eye - from.eye) * t,
+            from.zoom + (to.zoom - from.zoom) * t,
+        ))
+    }
+}
+
+pub fn play_camera_paths(
+    mut cameras: Query<(&mut ParallaxCamera, &mut CameraPath)>,
+    timer: Res<Timer>,
+) {
+    let dt = timer.elapsed().as_secs_f32();
+
+    for (mut camera, mut path) in cameras.iter_mut() {
+        if !path.is_playing() {
+            continue;
+        }
+        path.elapsed += dt;
+        let duration = path.duration();
+        if path.looping && duration > 0.0 {
+            path.elapsed %= duration;
+        }
+        if let Some((eye, zoom)) = path.sample(path.elapsed) {
+            camera.eye = eye;
+            camera.zoom = zoom;
+        }
+    }
+}
+
+/// Moves `current` towards `goal` like a critically damped spring, keeping the spring's
+/// velocity between calls.
+fn smooth_damp<T>(current: T, goal: T, velocity: &mut T, smoothing: f32, dt: f32) -> T
+where
+    T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T>,
+{
+    let omega = 2.0 / smoothing;
+    let x = omega * dt;
+    // Approximates exp(-x) closely enough for any frame time.
+    let decay = 1.0 / (1.0 + x + 0.48 * x * x + 0.235 * x * x * x);
+    let change = current - goal;
+    let temp = (*velocity + change * omega) * dt;
+    *velocity = (*velocity - temp * omega) * decay;
+    goal + (change + temp) * decay
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy_ecs::prelude::{IntoSystem, Stage, SystemStage, World};
+    use glam::Quat;
+    use std::time::Duration;
+
+    const SIZE: Vec2 = glam::const_vec2!([640.0, 360.0]);
+
+    fn camera() -> ParallaxCamera {
+        let mut camera = ParallaxCamera::new(
+            Vec3::new(1.0, -2.0, -5.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            1.0,
+            0.1,
+            100.0,
+        );
+        camera.offset = Vec3::new(0.3, -0.2, 0.0);
+        camera.zoom = 1.5;
+        camera
+    }
+
+    fn assert_near(actual: Vec3, expected: Vec3) {
+        assert!(
+            (actual - expected).abs().max_element() < 1e-4,
+            "{:?} is not {:?}",
+            actual,
+            expected
+        );
+    }
+
+    /// A world whose clock advances with each tick.
+    fn world() -> World {
+        let mut world = World::default();
+        world.insert_resource(Timer::new());
+        world
+    }
+
+    /// Runs `stage` `millis` after the last tick.
+    fn tick(world: &mut World, stage: &mut SystemStage, millis: u64) {
+        world
+            .get_resource_mut::<Timer>()
+            .unwrap()
+            .advance(Duration::from_millis(millis));
+        stage.run(world);
+    }
+
+    fn eye(world: &World, camera: Entity) -> Vec2 {
+        world.get::<ParallaxCamera>(camera).unwrap().eye.truncate()
+    }
+
+    fn move_to(world: &mut World, entity: Entity, x: f32, y: f32) {
+        world.get_mut::<Position>(entity).unwrap().0 = Vec3::new(x, y, 0.0);
+    }
+
+    #[test]
+    fn vertices_are_placed_like_the_vertex_shader() {
+        let camera = camera();
+        let centre = Vec3::new(4.0, 3.0, 20.0);
+        let scale = Vec3::new(2.0, 3.0, 1.0);
+        let model = Mat4::from_scale_rotation_translation(scale, Quat::identity(), centre);
+        let local = Vec3::new(0.5, -0.25, 0.0);
+
+        // The camera looks along z, so the centre is projected in perspective relative to the
+        // shifted eye, and the corner is offset from it by the orthographic scale, which is
+        // PIXELS_PER_METRE pixels a metre magnified by the zoom.
+        let relative = centre - (camera.eye + camera.offset);
+        let tan = (camera.fov_y / 2.0).tan() / camera.zoom;
+        let aspect = SIZE.x / SIZE.y;
+        let metre = 2.0 * PIXELS_PER_METRE as f32 * camera.zoom / SIZE;
+        let corner = local.truncate() * scale.truncate() * metre;
+        let (near, far) = (camera.near, camera.far);
+        let expected = Vec3::new(
+            relative.x / (aspect * tan * relative.z) + corner.x,
+            relative.y / (tan * relative.z) + corner.y,
+            far / (far - near) * (1.0 - near / relative.z),
+        );
+
+        let uniform = camera.generate_matrix(SIZE);
+        assert_near(project_vertex(&uniform, &model, local), expected);
+    }
+
+    #[test]
+    fn unprojected_points_project_back() {
+        let camera = camera();
+        let ndc = Vec2::new(0.3, -0.4);
+
+        for z in [2.0, 10.0, 50.0, 90.0].iter() {
+            let point = camera.unproject(ndc, SIZE, *z);
+            assert!((point.z - z).abs() < 1e-3, "{} is not at {}", point, z);
+            let projected = camera.project(point, SIZE);
+            assert_near(projected.truncate().extend(0.0), ndc.extend(0.0));
+        }
+    }
+
+    #[test]
+    fn unprojected_offsets_match_projected_ones() {
+        let camera = camera();
+
+        for (centre, offset) in [
+            (Vec3::new(1.0, 2.0, 15.0), Vec3::new(0.5, -0.25, 0.0)),
+            (Vec3::new(-3.0, 0.5, 60.0), Vec3::new(-1.5, 2.0, 0.0)),
+        ]
+        .iter()
+        {
+            let ndc = camera.project_offset(*centre, *offset, SIZE);
+            assert_near(
+                camera.unproject_offset(*centre, ndc.truncate(), SIZE),
+                *offset,
+            );
+        }
+    }
+
+    #[test]
+    fn followed_targets_move_freely_inside_the_dead_zone() {
+        let mut world = world();
+        let target = world.spawn().insert(Position(Vec3::zero())).id();
+        let follow = CameraFollow::new(target).with_dead_zone(Vec2::new(2.0, 1.0));
+        let camera = world.spawn().insert_bundle((camera(), follow)).id();
+        let mut stage = SystemStage::single_threaded().with_system(follow_targets.system());
+
+        // The camera starts on the target.
+        tick(&mut world, &mut stage, 16);
+        assert_eq!(eye(&world, camera), Vec2::zero());
+
+        move_to(&mut world, target, 1.5, -0.5);
+        tick(&mut world, &mut stage, 16);
+        assert_eq!(eye(&world, camera), Vec2::zero());
+
+        // Leaving the dead zone drags it along, keeping the target on its edge.
+        move_to(&mut world, target, 3.0, 2.0);
+        tick(&mut world, &mut stage, 16);
+        assert_eq!(eye(&world, camera), Vec2::new(1.0, 1.0));
+
+        move_to(&mut world, target, 2.0, 1.0);
+        tick(&mut world, &mut stage, 16);
+        assert_eq!(eye(&world, camera), Vec2::new(1.0, 1.0));
+        assert_eq!(world.get::<ParallaxCamera>(camera).unwrap().eye.z, -5.0);
+    }
+
+    #[test]
+    fn followed_targets_are_kept_in_bounds() {
+        let mut world = world();
+        let target = world.spawn().insert(Position(Vec3::zero())).id();
+        let follow = CameraFollow::new(target)
+            .with_offset(Vec2::new(0.0, 1.0))
+            .with_bounds(Vec2::new(-1.0, 0.0), Vec2::new(10.0, 5.0));
+        let camera = world.spawn().insert_bundle((camera(), follow)).id();
+        let mut stage = SystemStage::single_threaded().with_system(follow_targets.system());
+
+        move_to(&mut world, target, -4.0, -3.0);
+        tick(&mut world, &mut stage, 16);
+        assert_eq!(eye(&world, camera), Vec2::new(-1.0, 0.0));
+
+        move_to(&mut world, target, 4.0, 2.0);
+        tick(&mut world, &mut stage, 16);
+        assert_eq!(eye(&world, camera), Vec2::new(4.0, 3.0));
+
+        move_to(&mut world, target, 12.0, 8.0);
+        tick(&mut world, &mut stage, 16);
+        assert_eq!(eye(&world, camera), Vec2::new(10.0, 5.0));
+    }
+
+    #[test]
+    fn smoothed_cameras_catch_up_without_overshooting() {
+        let mut world = world();
+        let target = world
+            .spawn()
+            .insert_bundle((Position(Vec3::zero()), Velocity(Vec3::zero())))
+            .id();
+        let follow = CameraFollow::new(target)
+            .with_smoothing(0.25)
+            .with_look_ahead(2.0)
+            .with_vertical(false);
+        let camera = world.spawn().insert_bundle((camera(), follow)).id();
+        let mut stage = SystemStage::single_threaded().with_system(follow_targets.system());
+        tick(&mut world, &mut stage, 16);
+        assert_eq!(eye(&world, camera), Vec2::new(0.0, -2.0));
+
+        // Running right, the camera leads by the look ahead and keeps its height.
+        move_to(&mut world, target, 10.0, 4.0);
+        world.get_mut::<Velocity>(target).unwrap().0.x = 1.0;
+        let mut last = 0.0;
+        for _ in 0..120 {
+            tick(&mut world, &mut stage, 16);
+            let eye = eye(&world, camera);
+            assert!(eye.x >= last && eye.x <= 12.0, "{} overshot", eye);
+            assert_eq!(eye.y, -2.0);
+            last = eye.x;
+        }
+        assert!((last - 12.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn shaken_cameras_come_to_rest() {
+        let mut world = world();
+        let mut shake = CameraShake::new(Vec2::new(0.5, 0.25)).with_decay(2.0);
+        shake.add_trauma(0.75);
+        shake.add_trauma(0.75);
+        assert_eq!(shake.trauma, 1.0);
+        let camera = world.spawn().insert_bundle((camera(), shake)).id();
+        let mut stage = SystemStage::single_threaded().with_system(shake_cameras.system());
+
+        let mut shaken = false;
+        for _ in 0..5 {
+            tick(&mut world, &mut stage, 50);
+            let offset = world.get::<ParallaxCamera>(camera).unwrap().offset;
+            assert!(offset.x.abs() <= 0.5 && offset.y.abs() <= 0.25);
+            shaken |= offset != Vec3::zero();
+        }
+        assert!(shaken);
+
+        tick(&mut world, &mut stage, 300);
+        assert_eq!(world.get::<CameraShake>(camera).unwrap().trauma, 0.0);
+        assert_eq!(
+            world.get::<ParallaxCamera>(camera).unwrap().offset,
+            Vec3::zero()
+        );
+    }
+
+    #[test]
+    fn zoom_eases_to_its_target() {
+        let mut world = world();
+        let camera = world
+            .spawn()
+            .insert_bundle((camera(), CameraZoom::new(3.0, 0.2)))
+            .id();
+        let mut stage = SystemStage::single_threaded().with_system(zoom_cameras.system());
+        let zoom = |world: &World| world.get::<ParallaxCamera>(camera).unwrap().zoom;
+
+        tick(&mut world, &mut stage, 16);
+        assert!(zoom(&world) > 1.5 && zoom(&world) < 3.0);
+        for _ in 0..100 {
+            tick(&mut world, &mut stage, 16);
+            assert!(zoom(&world) <= 3.0);
+        }
+        assert!((zoom(&world) - 3.0).abs() < 1e-3);
+    }
+
+    fn keyframe(time: f32, x: f32, zoom: f32, ease: Ease) -> CameraKeyframe {
+        CameraKeyframe {
+            time,
+            eye: Vec3::new(x, 0.0, -5.0),
+            zoom,
+            ease,
+        }
+    }
+
+    #[test]
+    fn paths_hold_their_ends_and_ease_between_keyframes() {
+        // Keyframes are sorted by time.
+        let path = CameraPath::new(vec![
+            keyframe(3.0, 4.0, 1.0, Ease::InOut),
+            keyframe(1.0, 0.0, 1.0, Ease::Linear),
+            keyframe(2.0, 2.0, 2.0, Ease::Linear),
+        ]);
+        assert_eq!(path.duration(), 3.0);
+        let x = |time| path.sample(time).unwrap().0.x;
+
+        assert_eq!(path.sample(0.0), Some((Vec3::new(0.0, 0.0, -5.0), 1.0)));
+        assert_eq!(x(1.0), 0.0);
+        assert_eq!(path.sample(1.5), Some((Vec3::new(1.0, 0.0, -5.0), 1.5)));
+        assert_eq!(path.sample(2.0), Some((Vec3::new(2.0, 0.0, -5.0), 2.0)));
+        // Easing in and out is slower than linear near the keyframes, and as fast half way.
+        assert!(x(2.25) < 2.5);
+        assert_eq!(x(2.5), 3.0);
+        assert!(x(2.75) > 3.5);
+        assert_eq!(path.sample(3.0), Some((Vec3::new(4.0, 0.0, -5.0), 1.0)));
+        assert_eq!(x(10.0), 4.0);
+
+        assert_eq!(CameraPath::new(vec![]).sample(1.0), None);
+    }
+
+    #[test]
+    fn paths_stop_at_their_last_keyframe_unless_looping() {
+        let mut world = world();
+        let keyframes = vec![
+            keyframe(0.0, 0.0, 1.0, Ease::Linear),
+            keyframe(1.0, 4.0, 2.0, Ease::Linear),
+        ];
+        let once = world
+            .spawn()
+            .insert_bundle((camera(), CameraPath::new(keyframes.clone())))
+            .id();
+        let looping = world
+            .spawn()
+            .insert_bundle((camera(), CameraPath::new(keyframes).with_looping(true)))
+            .id();
+        let mut stage = SystemStage::single_threaded().with_system(play_camera_paths.system());
+
+        tick(&mut world, &mut stage, 250);
+        assert_eq!(eye(&world, once), Vec2::new(1.0, 0.0));
+        tick(&mut world, &mut stage, 1000);
+        assert_eq!(eye(&world, once), Vec2::new(4.0, 0.0));
+        assert_eq!(world.get::<ParallaxCamera>(once).unwrap().zoom, 2.0);
+        assert!(!world.get::<CameraPath>(once).unwrap().is_playing());
+        assert_eq!(eye(&world, looping), Vec2::new(1.0, 0.0));
+
+        world.get_mut::<CameraPath>(once).unwrap().restart();
+        tick(&mut world, &mut stage, 500);
+        assert_eq!(eye(&world, once), Vec2::new(2.0, 0.0));
+    }
+
+    /// A 2:1 window for a 4:3 reference resolution.
+    fn fit(policy: ScalingPolicy) -> ScreenFit {
+        ScreenScaling::new(Vec2::new(320.0, 240.0), policy).fit(&WindowSize {
+            width: 1000,
+            height: 500,
+        })
+    }
+
+    #[test]
+    fn pixel_perfect_scaling_letterboxes_whole_multiples() {
+        let fit = fit(ScalingPolicy::PixelPerfect);
+        assert_eq!(fit.area, [180, 10, 640, 480]);
+        assert_eq!(fit.visible, Vec2::new(320.0, 240.0));
+
+        // Windows smaller than the reference show what fits at 1:1.
+        let small = ScreenScaling::new(Vec2::new(320.0, 240.0), ScalingPolicy::PixelPerfect).fit(
+            &WindowSize {
+                width: 200,
+                height: 300,
+            },
+        );
+        assert_eq!(small.area, [0, 30, 200, 240]);
+        assert_eq!(small.visible, Vec2::new(200.0, 240.0));
+    }
+
+    #[test]
+    fn fitted_scaling_fills_the_window_and_shows_more_on_one_axis() {
+        let height = fit(ScalingPolicy::FitHeight);
+        assert_eq!(height.area, [0, 0, 1000, 500]);
+        assert_near(height.visible.extend(0.0), Vec3::new(480.0, 240.0, 0.0));
+
+        let width = fit(ScalingPolicy::FitWidth);
+        assert_eq!(width.area, [0, 0, 1000, 500]);
+        assert_near(width.visible.extend(0.0), Vec3::new(320.0, 160.0, 0.0));
+    }
+
+    #[test]
+    fn stretched_scaling_shows_the_reference_over_the_whole_window() {
+        let fit = fit(ScalingPolicy::Stretch);
+        assert_eq!(fit.area, [0, 0, 1000, 500]);
+        assert_eq!(fit.visible, Vec2::new(320.0, 240.0));
+        assert_eq!(fit.to_ndc(Vec2::new(500.0, 250.0)), Vec2::zero());
+        assert_eq!(
+            fit.to_window(Vec2::new(1.0, -1.0)),
+            Vec2::new(1000.0, 500.0)
+        );
     }
 
-    if let Some((_, mut camera)) = camera_query.iter_mut().next() {
-        camera.eye.x = x;
+    #[test]
+    fn positions_in_the_letterbox_are_outside_the_area() {
+        let fit = fit(ScalingPolicy::PixelPerfect);
+        assert_eq!(fit.to_ndc(Vec2::new(180.0, 10.0)), Vec2::new(-1.0, 1.0));
+        assert!(fit.contains(Vec2::new(180.0, 10.0)));
+        assert!(!fit.contains(Vec2::new(100.0, 250.0)));
+        assert!(!fit.contains(Vec2::new(820.0, 250.0)));
     }
 }
@@ -0,0 +1,244 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct GamepadId(pub usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum GamepadButton {
+    South,
+    East,
+    North,
+    West,
+    LeftTrigger,
+    LeftTrigger2,
+    RightTrigger,
+    RightTrigger2,
+    Select,
+    Start,
+    Mode,
+    LeftThumb,
+    RightThumb,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum GamepadAxis {
+    LeftStickX,
+    LeftStickY,
+    LeftZ,
+    RightStickX,
+    RightStickY,
+    RightZ,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GamepadEvent {
+    Connected(GamepadId),
+    Disconnected(GamepadId),
+    ButtonPressed(GamepadId, GamepadButton),
+    ButtonReleased(GamepadId, GamepadButton),
+    /// Axis values range from -1 to 1, with up and right positive.
+    AxisChanged(GamepadId, GamepadAxis, f32),
+}
+
+/// Where the game reads gamepad events from at the start of every tick.
+pub trait GamepadSource {
+    fn next_event(&mut self) -> Option<GamepadEvent>;
+}
+
+/// Hands out queued events, for driving gamepad input without a device.
+#[derive(Default)]
+pub struct MockGamepads {
+    events: VecDeque<GamepadEvent>,
+}
+
+impl MockGamepads {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn push(&mut self, event: GamepadEvent) {
+        self.events.push_back(event);
+    }
+}
+
+impl GamepadSource for MockGamepads {
+    fn next_event(&mut self) -> Option<GamepadEvent> {
+        self.events.pop_front()
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Gamepad {
+    held: BTreeSet<GamepadButton>,
+    just_pressed: Vec<GamepadButton>,
+    just_released: Vec<GamepadButton>,
+    axes: BTreeMap<GamepadAxis, f32>,
+}
+
+impl Gamepad {
+    pub fn held(&self, button: GamepadButton) -> bool {
+        self.held.contains(&button)
+    }
+
+    pub fn just_pressed(&self, button: GamepadButton) -> bool {
+        self.just_pressed.contains(&button)
+    }
+
+    pub fn just_released(&self, button: GamepadButton) -> bool {
+        self.just_released.contains(&button)
+    }
+
+    /// Raw axis value, before any dead-zone is applied.
+    pub fn axis(&self, axis: GamepadAxis) -> f32 {
+        self.axes.get(&axis).copied().unwrap_or(0.0)
+    }
+}
+
+/// Buttons and axes of every connected gamepad.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GamepadState {
+    gamepads: BTreeMap<GamepadId, Gamepad>,
+}
+
+impl GamepadState {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn update(&mut self, event: GamepadEvent) {
+        match event {
+            GamepadEvent::Connected(id) => {
+                self.gamepads.entry(id).or_default();
+            }
+            GamepadEvent::Disconnected(id) => {
+                self.gamepads.remove(&id);
+            }
+            GamepadEvent::ButtonPressed(id, button) => {
+                let gamepad = self.gamepads.entry(id).or_default();
+                if gamepad.held.insert(button) {
+                    gamepad.just_pressed.push(button);
+                }
+            }
+            GamepadEvent::ButtonReleased(id, button) => {
+                if let Some(gamepad) = self.gamepads.get_mut(&id) {
+                    if gamepad.held.remove(&button) {
+                        gamepad.just_released.push(button);
+                    }
+                }
+            }
+            GamepadEvent::AxisChanged(id, axis, value) => {
+                self.gamepads
+                    .entry(id)
+                    .or_default()
+                    .axes
+                    .insert(axis, value);
+            }
+        }
+    }
+
+    pub fn get(&self, id: GamepadId) -> Option<&Gamepad> {
+        self.gamepads.get(&id)
+    }
+
+    pub fn connected(&self) -> impl Iterator<Item = GamepadId> + '_ {
+        self.gamepads.keys().copied()
+    }
+
+    pub(crate) fn clear_frame(&mut self) {
+        for gamepad in self.gamepads.values_mut() {
+            gamepad.just_pressed.clear();
+            gamepad.just_released.clear();
+        }
+    }
+}
+
+/// Reads connected gamepads through gilrs. `App::run` uses this unless another source has been
+/// set on the game.
+#[cfg(feature = "gamepad")]
+pub struct GilrsSource(gilrs::Gilrs);
+
+#[cfg(feature = "gamepad")]
+impl GilrsSource {
+    pub fn new() -> Result<Self, Box<gilrs::Error>> {
+        Ok(GilrsSource(gilrs::Gilrs::new()?))
+    }
+}
+
+#[cfg(feature = "gamepad")]
+impl GamepadSource for GilrsSource {
+    fn next_event(&mut self) -> Option<GamepadEvent> {
+        use gilrs::EventType;
+
+        // Skip events that have no equivalent until one that does comes along.
+        while let Some(gilrs::Event { id, event, .. }) = self.0.next_event() {
+            let id = GamepadId(id.into());
+            let event = match event {
+                EventType::Connected => Some(GamepadEvent::Connected(id)),
+                EventType::Disconnected => Some(GamepadEvent::Disconnected(id)),
+                EventType::ButtonPressed(button, _) => {
+                    from_gilrs_button(button).map(|b| GamepadEvent::ButtonPressed(id, b))
+                }
+                EventType::ButtonReleased(button, _) => {
+                    from_gilrs_button(button).map(|b| GamepadEvent::ButtonReleased(id, b))
+                }
+                EventType::AxisChanged(axis, value, _) => {
+                    from_gilrs_axis(axis).map(|a| GamepadEvent::AxisChanged(id, a, value))
+                }
+                _ => None,
+            };
+            if event.is_some() {
+                return event;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(feature = "gamepad")]
+fn from_gilrs_button(button: gilrs::Button) -> Option<GamepadButton> {
+    use gilrs::Button;
+
+    let button = match button {
+        Button::South => GamepadButton::South,
+        Button::East => GamepadButton::East,
+        Button::North => GamepadButton::North,
+        Button::West => GamepadButton::West,
+        Button::LeftTrigger => GamepadButton::LeftTrigger,
+        Button::LeftTrigger2 => GamepadButton::LeftTrigger2,
+        Button::RightTrigger => GamepadButton::RightTrigger,
+        Button::RightTrigger2 => GamepadButton::RightTrigger2,
+        Button::Select => GamepadButton::Select,
+        Button::Start => GamepadButton::Start,
+        Button::Mode => GamepadButton::Mode,
+        Button::LeftThumb => GamepadButton::LeftThumb,
+        Button::RightThumb => GamepadButton::RightThumb,
+        Button::DPadUp => GamepadButton::DPadUp,
+        Button::DPadDown => GamepadButton::DPadDown,
+        Button::DPadLeft => GamepadButton::DPadLeft,
+        Button::DPadRight => GamepadButton::DPadRight,
+        Button::C | Button::Z | Button::Unknown => return None,
+    };
+    Some(button)
+}
+
+/// D-pad axes are left out because gilrs also reports the d-pad as buttons.
+#[cfg(feature = "gamepad")]
+fn from_gilrs_axis(axis: gilrs::Axis) -> Option<GamepadAxis> {
+    use gilrs::Axis;
+
+    let axis = match axis {
+        Axis::LeftStickX => GamepadAxis::LeftStickX,
+        Axis::LeftStickY => GamepadAxis::LeftStickY,
+        Axis::LeftZ => GamepadAxis::LeftZ,
+        Axis::RightStickX => GamepadAxis::RightStickX,
+        Axis::RightStickY => GamepadAxis::RightStickY,
+        Axis::RightZ => GamepadAxis::RightZ,
+        Axis::DPadX | Axis::DPadY | Axis::Unknown => return None,
+    };
+    Some(axis)
+}
@@ -1,13 +1,26 @@
+use crate::gamepad::{Gamepad, GamepadAxis, GamepadButton, GamepadId, GamepadState};
+use crate::time::Timer;
+use bevy_ecs::prelude::{Query, Res};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::time::{Duration, Instant};
 use winit::event::{ElementState, KeyboardInput, VirtualKeyCode};
 
-#[derive(Debug, Default, Copy, Clone)]
+/// A key going down or up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyEvent {
+    pub key: VirtualKeyCode,
+    pub state: ElementState,
+}
+
+/// Keys held down and every key transition since the last tick, in the order they happened.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct KeyState {
-    pub left: bool,
-    pub right: bool,
-    pub up: bool,
-    pub down: bool,
-    pub a: bool,
-    pub pressed_this_frame: Option<VirtualKeyCode>,
+    held: BTreeSet<VirtualKeyCode>,
+    events: Vec<KeyEvent>,
 }
 
 impl KeyState {
@@ -16,48 +29,653 @@ impl KeyState {
     }
 
     pub fn update(&mut self, input: KeyboardInput) {
-        self.pressed_this_frame = None;
-        match input {
-            KeyboardInput {
-                state,
-                virtual_keycode: Some(VirtualKeyCode::Left),
-                ..
-            } => match state {
-                ElementState::Pressed => {
-                    if !self.left {
-                        self.pressed_this_frame = input.virtual_keycode;
-                    }
-                    self.left = true;
+        if let Some(key) = input.virtual_keycode {
+            let changed = match input.state {
+                ElementState::Pressed => self.held.insert(key),
+                ElementState::Released => self.held.remove(&key),
+            };
+            // Held keys repeat their pressed events, which are not transitions.
+            if changed {
+                self.events.push(KeyEvent {
+                    key,
+                    state: input.state,
+                });
+            }
+        }
+    }
+
+    pub fn held(&self, key: VirtualKeyCode) -> bool {
+        self.held.contains(&key)
+    }
+
+    /// Whether the key went down since the last tick, even if it has been released again.
+    pub fn just_pressed(&self, key: VirtualKeyCode) -> bool {
+        self.events
+            .iter()
+            .any(|event| event.key == key && event.state == ElementState::Pressed)
+    }
+
+    /// Whether the key went up since the last tick, even if it has been pressed again.
+    pub fn just_released(&self, key: VirtualKeyCode) -> bool {
+        self.events
+            .iter()
+            .any(|event| event.key == key && event.state == ElementState::Released)
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+
+    pub(crate) fn clear_frame(&mut self) {
+        self.events.clear();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    MoveLeft,
+    MoveRight,
+    Attack,
+    Jump,
+}
+
+/// Pushing a stick past the dead-zone in `direction` triggers the bound action.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AxisBinding {
+    pub axis: GamepadAxis,
+    pub direction: AxisDirection,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AxisDirection {
+    Positive,
+    Negative,
+}
+
+/// The keys, gamepad buttons and stick directions bound to each action. Saved with the action
+/// names as keys, e.g. `{ "keys": { "move_left": ["Left", "H"] } }`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ActionMap {
+    #[serde(default)]
+    keys: BTreeMap<Action, Vec<VirtualKeyCode>>,
+    #[serde(default)]
+    buttons: BTreeMap<Action, Vec<GamepadButton>>,
+    #[serde(default)]
+    axes: BTreeMap<Action, Vec<AxisBinding>>,
+    /// Stick deflections smaller than this are ignored.
+    #[serde(default = "default_dead_zone")]
+    dead_zone: f32,
+}
+
+fn default_dead_zone() -> f32 {
+    0.25
+}
+
+impl ActionMap {
+    /// A map without any bindings.
+    pub fn empty() -> Self {
+        ActionMap {
+            keys: BTreeMap::new(),
+            buttons: BTreeMap::new(),
+            axes: BTreeMap::new(),
+            dead_zone: default_dead_zone(),
+        }
+    }
+
+    pub fn read(file: impl AsRef<Path>) -> std::io::Result<Self> {
+        let reader = BufReader::new(File::open(file)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    pub fn write(&self, file: impl AsRef<Path>) -> std::io::Result<()> {
+        let writer = BufWriter::new(File::create(file)?);
+        Ok(serde_json::to_writer_pretty(writer, self)?)
+    }
+
+    /// Adds `key` to the keys that trigger `action`.
+    pub fn bind(&mut self, action: Action, key: VirtualKeyCode) {
+        bind(&mut self.keys, action, key);
+    }
+
+    pub fn unbind(&mut self, action: Action, key: VirtualKeyCode) {
+        if let Some(keys) = self.keys.get_mut(&action) {
+            keys.retain(|bound| *bound != key);
+        }
+    }
+
+    /// Replaces every key bound to `action`.
+    pub fn rebind(&mut self, action: Action, keys: Vec<VirtualKeyCode>) {
+        self.keys.insert(action, keys);
+    }
+
+    pub fn keys(&self, action: Action) -> &[VirtualKeyCode] {
+        self.keys.get(&action).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    pub fn bind_button(&mut self, action: Action, button: GamepadButton) {
+        bind(&mut self.buttons, action, button);
+    }
+
+    pub fn unbind_button(&mut self, action: Action, button: GamepadButton) {
+        if let Some(buttons) = self.buttons.get_mut(&action) {
+            buttons.retain(|bound| *bound != button);
+        }
+    }
+
+    /// Replaces every gamepad button bound to `action`.
+    pub fn rebind_buttons(&mut self, action: Action, buttons: Vec<GamepadButton>) {
+        self.buttons.insert(action, buttons);
+    }
+
+    pub fn buttons(&self, action: Action) -> &[GamepadButton] {
+        self.buttons.get(&action).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    pub fn bind_axis(&mut self, action: Action, axis: GamepadAxis, direction: AxisDirection) {
+        bind(&mut self.axes, action, AxisBinding { axis, direction });
+    }
+
+    pub fn axes(&self, action: Action) -> &[AxisBinding] {
+        self.axes.get(&action).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    pub fn dead_zone(&self) -> f32 {
+        self.dead_zone
+    }
+
+    pub fn set_dead_zone(&mut self, dead_zone: f32) {
+        self.dead_zone = dead_zone;
+    }
+
+    /// Every action with at least one binding.
+    pub fn actions(&self) -> impl Iterator<Item = Action> {
+        let actions: BTreeSet<Action> = self
+            .keys
+            .keys()
+            .chain(self.buttons.keys())
+            .chain(self.axes.keys())
+            .copied()
+            .collect();
+        actions.into_iter()
+    }
+
+    fn gamepad_held(&self, gamepad: &Gamepad, action: Action) -> bool {
+        self.buttons(action)
+            .iter()
+            .any(|button| gamepad.held(*button))
+            || self.axes(action).iter().any(|binding| {
+                let value = gamepad.axis(binding.axis);
+                match binding.direction {
+                    AxisDirection::Positive => value > self.dead_zone,
+                    AxisDirection::Negative => value < -self.dead_zone,
                 }
-                ElementState::Released => self.left = false,
-            },
-            KeyboardInput {
-                state,
-                virtual_keycode: Some(VirtualKeyCode::Right),
-                ..
-            } => match state {
-                ElementState::Pressed => {
-                    if !self.right {
-                        self.pressed_this_frame = input.virtual_keycode;
+            })
+    }
+
+    fn gamepad_just_pressed(&self, gamepad: &Gamepad, action: Action) -> bool {
+        self.buttons(action)
+            .iter()
+            .any(|button| gamepad.just_pressed(*button))
+    }
+
+    fn gamepad_just_released(&self, gamepad: &Gamepad, action: Action) -> bool {
+        self.buttons(action)
+            .iter()
+            .any(|button| gamepad.just_released(*button))
+    }
+}
+
+fn bind<T: PartialEq>(bindings: &mut BTreeMap<Action, Vec<T>>, action: Action, input: T) {
+    let bound = bindings.entry(action).or_default();
+    if !bound.contains(&input) {
+        bound.push(input);
+    }
+}
+
+impl Default for ActionMap {
+    fn default() -> Self {
+        let mut map = ActionMap::empty();
+        map.bind(Action::MoveLeft, VirtualKeyCode::Left);
+        map.bind(Action::MoveRight, VirtualKeyCode::Right);
+        map.bind(Action::Attack, VirtualKeyCode::A);
+        map.bind(Action::Jump, VirtualKeyCode::Space);
+        map.bind_button(Action::MoveLeft, GamepadButton::DPadLeft);
+        map.bind_button(Action::MoveRight, GamepadButton::DPadRight);
+        map.bind_button(Action::Attack, GamepadButton::West);
+        map.bind_button(Action::Jump, GamepadButton::South);
+        map.bind_axis(
+            Action::MoveLeft,
+            GamepadAxis::LeftStickX,
+            AxisDirection::Negative,
+        );
+        map.bind_axis(
+            Action::MoveRight,
+            GamepadAxis::LeftStickX,
+            AxisDirection::Positive,
+        );
+        map
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputDevice {
+    Keyboard,
+    Gamepad(GamepadId),
+}
+
+/// The devices that drive an entity's `ActionState`, so that each player can be given their
+/// own. Entities without one are driven by the keyboard and every gamepad.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InputDevices(pub Vec<InputDevice>);
+
+/// Actions held down and the actions pressed or released since the last tick, derived from the
+/// input devices through the `ActionMap` before the gameplay systems run.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ActionState {
+    held: BTreeSet<Action>,
+    just_pressed: BTreeSet<Action>,
+    just_released: BTreeSet<Action>,
+    /// Scenes save these relative to the `Timer`, as `Instant`s can not be saved.
+    #[serde(skip)]
+    last_pressed: BTreeMap<Action, Instant>,
+}
+
+impl ActionState {
+    pub fn held(&self, action: Action) -> bool {
+        self.held.contains(&action)
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.just_pressed.contains(&action)
+    }
+
+    pub fn just_released(&self, action: Action) -> bool {
+        self.just_released.contains(&action)
+    }
+
+    /// Whether the action was pressed no more than `window` before `now` and has not been
+    /// consumed since. Buffering presses like this keeps inputs made slightly too early, such
+    /// as attacking just before the previous attack ends, from being dropped.
+    pub fn pressed_within(&self, action: Action, window: Duration, now: Instant) -> bool {
+        self.last_pressed
+            .get(&action)
+            .map(|pressed| now.saturating_duration_since(*pressed) <= window)
+            .unwrap_or(false)
+    }
+
+    /// Forgets a buffered press so that it only triggers once.
+    pub fn consume(&mut self, action: Action) {
+        self.last_pressed.remove(&action);
+    }
+
+    pub(crate) fn last_pressed(&self) -> &BTreeMap<Action, Instant> {
+        &self.last_pressed
+    }
+
+    pub(crate) fn set_last_pressed(&mut self, action: Action, pressed: Instant) {
+        self.last_pressed.insert(action, pressed);
+    }
+}
+
+pub fn update_action_state(
+    keys: Res<KeyState>,
+    gamepads: Res<GamepadState>,
+    map: Res<ActionMap>,
+    timer: Res<Timer>,
+    mut query: Query<(&mut ActionState, Option<&InputDevices>)>,
+) {
+    let every_device: Vec<InputDevice> = std::iter::once(InputDevice::Keyboard)
+        .chain(gamepads.connected().map(InputDevice::Gamepad))
+        .collect();
+
+    for (mut actions, devices) in query.iter_mut() {
+        let devices = devices.map(|devices| &devices.0).unwrap_or(&every_device);
+        let was_held = std::mem::take(&mut actions.held);
+        actions.just_pressed.clear();
+        actions.just_released.clear();
+
+        for action in map.actions() {
+            let mut held = false;
+            let mut pressed = false;
+            let mut released = false;
+
+            for device in devices {
+                match device {
+                    InputDevice::Keyboard => {
+                        let bound = map.keys(action);
+                        held |= bound.iter().any(|key| keys.held(*key));
+                        pressed |= bound.iter().any(|key| keys.just_pressed(*key));
+                        released |= bound.iter().any(|key| keys.just_released(*key));
                     }
-                    self.right = true;
-                }
-                ElementState::Released => self.right = false,
-            },
-            KeyboardInput {
-                state,
-                virtual_keycode: Some(VirtualKeyCode::A),
-                ..
-            } => match state {
-                ElementState::Pressed => {
-                    if !self.a {
-                        self.pressed_this_frame = input.virtual_keycode;
+                    InputDevice::Gamepad(id) => {
+                        if let Some(gamepad) = gamepads.get(*id) {
+                            held |= map.gamepad_held(gamepad, action);
+                            pressed |= map.gamepad_just_pressed(gamepad, action);
+                            released |= map.gamepad_just_released(gamepad, action);
+                        }
                     }
-                    self.a = true;
                 }
-                ElementState::Released => self.a = false,
-            },
-            _ => (),
+            }
+
+            // Sticks have no press or release events, so crossing the dead-zone counts as one.
+            let was_held = was_held.contains(&action);
+            pressed |= held && !was_held;
+            released |= was_held && !held;
+
+            if held {
+                actions.held.insert(action);
+            }
+            if pressed {
+                actions.just_pressed.insert(action);
+                actions.last_pressed.insert(action, timer.now());
+            }
+            if released {
+                actions.just_released.insert(action);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gamepad::{GamepadEvent, GamepadSource, MockGamepads};
+    use bevy_ecs::prelude::{Entity, IntoSystem, Stage, SystemStage, World};
+
+    const PAD_0: GamepadId = GamepadId(0);
+    const PAD_1: GamepadId = GamepadId(1);
+
+    struct Input {
+        world: World,
+        stage: SystemStage,
+        gamepads: MockGamepads,
+    }
+
+    impl Input {
+        fn new() -> Self {
+            let mut world = World::default();
+            world.insert_resource(KeyState::new());
+            world.insert_resource(GamepadState::new());
+            world.insert_resource(ActionMap::default());
+            world.insert_resource(Timer::new());
+            let mut stage = SystemStage::single_threaded();
+            stage.add_system(update_action_state.system());
+
+            let mut gamepads = MockGamepads::new();
+            gamepads.push(GamepadEvent::Connected(PAD_0));
+            gamepads.push(GamepadEvent::Connected(PAD_1));
+            Input {
+                world,
+                stage,
+                gamepads,
+            }
+        }
+
+        /// Runs one tick with the queued gamepad events, like `Game::update`.
+        fn tick(&mut self) {
+            let mut state = self.world.get_resource_mut::<GamepadState>().unwrap();
+            while let Some(event) = self.gamepads.next_event() {
+                state.update(event);
+            }
+            self.stage.run(&mut self.world);
+            self.world
+                .get_resource_mut::<GamepadState>()
+                .unwrap()
+                .clear_frame();
+            self.world
+                .get_resource_mut::<KeyState>()
+                .unwrap()
+                .clear_frame();
+        }
+
+        fn player(&mut self, devices: Option<Vec<InputDevice>>) -> Entity {
+            let mut player = self.world.spawn();
+            player.insert(ActionState::default());
+            if let Some(devices) = devices {
+                player.insert(InputDevices(devices));
+            }
+            player.id()
+        }
+
+        fn actions(&self, player: Entity) -> &ActionState {
+            self.world.get::<ActionState>(player).unwrap()
         }
+
+        #[allow(deprecated)]
+        fn key(&mut self, key: VirtualKeyCode, state: ElementState) {
+            self.world
+                .get_resource_mut::<KeyState>()
+                .unwrap()
+                .update(KeyboardInput {
+                    scancode: 0,
+                    state,
+                    virtual_keycode: Some(key),
+                    modifiers: Default::default(),
+                });
+        }
+    }
+
+    #[test]
+    fn sticks_count_only_past_the_dead_zone() {
+        let mut input = Input::new();
+        let player = input.player(None);
+        let stick = |value| GamepadEvent::AxisChanged(PAD_0, GamepadAxis::LeftStickX, value);
+
+        input.gamepads.push(stick(0.2));
+        input.tick();
+        assert!(!input.actions(player).held(Action::MoveRight));
+
+        input.gamepads.push(stick(0.6));
+        input.tick();
+        assert!(input.actions(player).held(Action::MoveRight));
+        assert!(input.actions(player).just_pressed(Action::MoveRight));
+
+        input.tick();
+        assert!(input.actions(player).held(Action::MoveRight));
+        assert!(!input.actions(player).just_pressed(Action::MoveRight));
+
+        input.gamepads.push(stick(-0.1));
+        input.tick();
+        assert!(!input.actions(player).held(Action::MoveLeft));
+        assert!(input.actions(player).just_released(Action::MoveRight));
+
+        let mut map = ActionMap::default();
+        map.set_dead_zone(0.05);
+        input.world.insert_resource(map);
+        input.tick();
+        assert!(input.actions(player).held(Action::MoveLeft));
+    }
+
+    #[test]
+    fn buttons_map_to_their_actions() {
+        let mut input = Input::new();
+        let player = input.player(None);
+
+        input
+            .gamepads
+            .push(GamepadEvent::ButtonPressed(PAD_0, GamepadButton::West));
+        input
+            .gamepads
+            .push(GamepadEvent::ButtonPressed(PAD_1, GamepadButton::DPadLeft));
+        input.tick();
+        let actions = input.actions(player);
+        assert!(actions.just_pressed(Action::Attack));
+        assert!(actions.held(Action::MoveLeft));
+        assert!(!actions.held(Action::Jump));
+
+        input
+            .gamepads
+            .push(GamepadEvent::ButtonReleased(PAD_0, GamepadButton::West));
+        input.tick();
+        let actions = input.actions(player);
+        assert!(actions.just_released(Action::Attack));
+        assert!(!actions.held(Action::Attack));
+        assert!(actions.held(Action::MoveLeft));
+    }
+
+    #[test]
+    fn players_only_read_their_own_devices() {
+        let mut input = Input::new();
+        let first = input.player(Some(vec![InputDevice::Gamepad(PAD_0)]));
+        let second = input.player(Some(vec![
+            InputDevice::Gamepad(PAD_1),
+            InputDevice::Keyboard,
+        ]));
+        let everyone = input.player(None);
+
+        input
+            .gamepads
+            .push(GamepadEvent::ButtonPressed(PAD_0, GamepadButton::South));
+        input.tick();
+        assert!(input.actions(first).held(Action::Jump));
+        assert!(!input.actions(second).held(Action::Jump));
+        assert!(input.actions(everyone).held(Action::Jump));
+
+        input.key(VirtualKeyCode::Right, ElementState::Pressed);
+        input.gamepads.push(GamepadEvent::AxisChanged(
+            PAD_1,
+            GamepadAxis::LeftStickX,
+            -1.0,
+        ));
+        input.tick();
+        assert!(!input.actions(first).held(Action::MoveRight));
+        assert!(input.actions(second).held(Action::MoveRight));
+        assert!(input.actions(second).held(Action::MoveLeft));
+        assert!(!input.actions(first).held(Action::MoveLeft));
+
+        input.gamepads.push(GamepadEvent::Disconnected(PAD_0));
+        input.tick();
+        assert!(!input.actions(first).held(Action::Jump));
+        assert!(!input.actions(everyone).held(Action::Jump));
+    }
+
+    #[test]
+    fn taps_within_one_tick_are_pressed_and_released() {
+        let mut input = Input::new();
+        let player = input.player(None);
+
+        input.key(VirtualKeyCode::A, ElementState::Pressed);
+        input.key(VirtualKeyCode::A, ElementState::Released);
+        input.tick();
+        let actions = input.actions(player);
+        assert!(actions.just_pressed(Action::Attack));
+        assert!(actions.just_released(Action::Attack));
+        assert!(!actions.held(Action::Attack));
+
+        input.tick();
+        assert!(!input.actions(player).just_pressed(Action::Attack));
+        assert!(!input.actions(player).just_released(Action::Attack));
+    }
+
+    #[test]
+    fn keys_changing_in_the_same_tick_are_all_seen() {
+        let mut input = Input::new();
+        let player = input.player(None);
+        input.key(VirtualKeyCode::Left, ElementState::Pressed);
+        input.tick();
+
+        input.key(VirtualKeyCode::Left, ElementState::Released);
+        input.key(VirtualKeyCode::Right, ElementState::Pressed);
+        input.key(VirtualKeyCode::Space, ElementState::Pressed);
+        // Repeats of a held key are not presses.
+        input.key(VirtualKeyCode::Right, ElementState::Pressed);
+        let events = input
+            .world
+            .get_resource::<KeyState>()
+            .unwrap()
+            .events()
+            .len();
+        assert_eq!(events, 3);
+        input.tick();
+
+        let actions = input.actions(player);
+        assert!(actions.just_released(Action::MoveLeft) && !actions.held(Action::MoveLeft));
+        assert!(actions.just_pressed(Action::MoveRight) && actions.held(Action::MoveRight));
+        assert!(actions.just_pressed(Action::Jump) && actions.held(Action::Jump));
+    }
+
+    #[test]
+    fn presses_are_buffered_until_consumed() {
+        let mut input = Input::new();
+        let player = input.player(None);
+        let window = Duration::from_millis(150);
+        input.key(VirtualKeyCode::A, ElementState::Pressed);
+        input.key(VirtualKeyCode::A, ElementState::Released);
+        input.tick();
+        let pressed = input.world.get_resource::<Timer>().unwrap().now();
+
+        let actions = input.actions(player);
+        assert!(actions.pressed_within(Action::Attack, window, pressed + window));
+        assert!(!actions.pressed_within(
+            Action::Attack,
+            window,
+            pressed + window + Duration::from_millis(1)
+        ));
+        assert!(!actions.pressed_within(Action::Jump, window, pressed));
+
+        input
+            .world
+            .get_mut::<ActionState>(player)
+            .unwrap()
+            .consume(Action::Attack);
+        assert!(!input
+            .actions(player)
+            .pressed_within(Action::Attack, window, pressed));
+    }
+
+    #[test]
+    fn bindings_round_trip_through_the_bindings_file() {
+        let map =
+            ActionMap::read(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/bindings.json")).unwrap();
+        assert_eq!(map, ActionMap::default());
+        assert_eq!(
+            map.axes(Action::MoveLeft),
+            &[AxisBinding {
+                axis: GamepadAxis::LeftStickX,
+                direction: AxisDirection::Negative,
+            }]
+        );
+
+        let mut rebound = map;
+        rebound.rebind(Action::Jump, vec![VirtualKeyCode::Up, VirtualKeyCode::W]);
+        rebound.rebind_buttons(Action::Attack, vec![GamepadButton::East]);
+        rebound.set_dead_zone(0.1);
+        let file = std::env::temp_dir().join("erlking_bindings.json");
+        rebound.write(&file).unwrap();
+
+        let read = ActionMap::read(&file).unwrap();
+        assert_eq!(read, rebound);
+        assert_eq!(
+            read.keys(Action::Jump),
+            &[VirtualKeyCode::Up, VirtualKeyCode::W]
+        );
+    }
+
+    #[test]
+    fn rebound_actions_ignore_their_old_inputs() {
+        let mut input = Input::new();
+        let player = input.player(None);
+        let mut map = ActionMap::default();
+        map.rebind(Action::MoveRight, vec![VirtualKeyCode::D]);
+        map.rebind_buttons(Action::Jump, vec![GamepadButton::North]);
+        input.world.insert_resource(map);
+
+        input.key(VirtualKeyCode::Right, ElementState::Pressed);
+        input
+            .gamepads
+            .push(GamepadEvent::ButtonPressed(PAD_0, GamepadButton::South));
+        input.tick();
+        assert!(!input.actions(player).held(Action::MoveRight));
+        assert!(!input.actions(player).held(Action::Jump));
+
+        input.key(VirtualKeyCode::D, ElementState::Pressed);
+        input
+            .gamepads
+            .push(GamepadEvent::ButtonPressed(PAD_0, GamepadButton::North));
+        input.tick();
+        assert!(input.actions(player).just_pressed(Action::MoveRight));
+        assert!(input.actions(player).just_pressed(Action::Jump));
     }
 }
@@ -1,68 +1,290 @@
-use crate::asset::SpriteId;
-use crate::input::KeyState;
+use crate::asset::{SpriteCatalog, SpriteId};
+use crate::gamepad::{GamepadSource, GamepadState};
+use crate::input::{update_action_state, ActionMap, KeyState};
+use crate::pointer::{Mouse, Touches};
 use crate::sprite::Sprite;
+use crate::text::Text;
 use crate::{
-    camera::{ActiveCamera, Camera, ParallaxCamera},
+    camera::{
+        follow_targets, play_camera_paths, screen_space_uniform, shake_cameras, zoom_cameras,
+        ActiveCamera, Camera, CameraTarget, ParallaxCamera, ScreenFit, ScreenScaling, Viewport,
+    },
     time::Timer,
 };
-pub use app::App;
+pub use app::{App, Headless};
+use app::{Screenshots, WindowMode, WindowSize};
 use bevy_ecs::bundle::Bundle;
+use bevy_ecs::component::Component;
 use bevy_ecs::entity::Entity;
-use bevy_ecs::prelude::{Schedule, Stage, SystemStage, World};
+use bevy_ecs::prelude::{
+    IntoSystem, ParallelSystemDescriptorCoercion, Schedule, Stage, SystemStage, World,
+};
 use bevy_ecs::schedule::SystemDescriptor;
 use bevy_ecs::world::SpawnBatchIter;
-use glam::{Quat, Vec3};
+use glam::{Quat, Vec2, Vec3};
 use renderer::gpu_primitives::{Instance, InstanceRaw};
-use renderer::scene::Scene;
+pub use renderer::reference::{differing_pixels, ReferenceRenderer};
+use renderer::scene::{Scene, SceneView};
+use renderer::sprite::PIXELS_PER_METRE;
 pub use renderer::TEXTURE_ARRAY_SIZE;
+use replay::{InputRecording, RecordedTick};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serialization::{ComponentRegistry, Migration, SceneError, SceneFile, Snapshot};
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tilemap::{update_tilemaps, Tilemap};
+use transform::{propagate_transforms, Children, GlobalTransform, Parent};
 use winit::event::WindowEvent;
 
 pub mod app;
 pub mod asset;
 pub mod camera;
+pub mod gamepad;
 pub mod input;
 pub mod player;
+pub mod pointer;
 mod renderer;
+pub mod replay;
+pub mod serialization;
 pub mod sprite;
+pub mod text;
+pub mod tiled;
+pub mod tilemap;
 mod time;
+pub mod transform;
+pub mod ui;
 
+#[derive(Serialize, Deserialize)]
 pub struct Position(pub Vec3);
-#[derive(PartialOrd, PartialEq)]
+#[derive(PartialOrd, PartialEq, Serialize, Deserialize)]
 pub struct Velocity(pub Vec3);
+#[derive(Serialize, Deserialize)]
 pub struct Rotation(pub Quat);
-pub struct Scale(pub u8);
+/// Per axis scale, negative values mirror.
+#[derive(Serialize, Deserialize)]
+pub struct Scale(pub Vec3);
 pub struct Collider(pub parry2d::shape::Cuboid);
-#[derive(Clone, Copy)]
+
+impl Collider {
+    /// The collider with its half extents multiplied by the entity's scale.
+    pub fn scaled(&self, scale: Option<&Scale>) -> parry2d::shape::Cuboid {
+        match scale {
+            Some(scale) => parry2d::shape::Cuboid::new(parry2d::na::Vector2::new(
+                self.0.half_extents.x * scale.0.x.abs(),
+                self.0.half_extents.y * scale.0.y.abs(),
+            )),
+            None => self.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct MoveSpeed(pub f32);
+#[derive(Serialize, Deserialize)]
 pub struct Terrain;
 
+/// How many world instances the last scene drew and how many were skipped for being outside
+/// every camera's view. Glyphs of world text and tilemap tiles count one each.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RenderStats {
+    pub visible: usize,
+    pub culled: usize,
+}
+
 pub struct Game {
     world: World,
     schedule: Schedule,
+    components: ComponentRegistry,
+    snapshot_version: u32,
+    snapshot_migration: Option<Migration>,
+    recording: Option<(PathBuf, InputRecording)>,
+    replay: Option<std::vec::IntoIter<RecordedTick>>,
+    gamepads: Option<Box<dyn GamepadSource>>,
 }
 
 impl Game {
     pub fn new() -> Game {
         let mut schedule = Schedule::default();
-        schedule.add_stage("gameplay", SystemStage::parallel());
+        schedule.add_stage("input", SystemStage::parallel());
+        schedule.add_stage_after("input", "gameplay", SystemStage::parallel());
+        schedule.add_system_to_stage("input", update_action_state.system());
+        schedule.add_stage_after("gameplay", "transform", SystemStage::parallel());
+        schedule.add_system_to_stage(
+            "transform",
+            propagate_transforms.system().label("propagate"),
+        );
+        schedule.add_system_to_stage("transform", update_tilemaps.system().after("propagate"));
+        // Paths take over from following and zooming, and shake is added on top of wherever
+        // the others left the camera, so the order is fixed to keep replays deterministic.
+        schedule.add_stage_after("transform", "camera", SystemStage::parallel());
+        schedule.add_system_to_stage("camera", play_camera_paths.system().label("paths"));
+        schedule.add_system_to_stage(
+            "camera",
+            follow_targets.system().label("follow").after("paths"),
+        );
+        schedule.add_system_to_stage(
+            "camera",
+            zoom_cameras.system().label("zoom").after("follow"),
+        );
+        schedule.add_system_to_stage("camera", shake_cameras.system().after("zoom"));
 
         let mut world = World::default();
         world.insert_resource(Timer::new());
         world.insert_resource(KeyState::new());
+        world.insert_resource(ActionMap::default());
+        world.insert_resource(GamepadState::new());
+        world.insert_resource(Mouse::new());
+        world.insert_resource(Touches::new());
+        world.insert_resource(WindowSize::default());
+        world.insert_resource(WindowMode::default());
+        world.insert_resource(ScreenScaling::default());
+        world.insert_resource(RenderStats::default());
+        world.insert_resource(Screenshots::default());
 
-        Game { world, schedule }
+        Game {
+            world,
+            schedule,
+            components: ComponentRegistry::with_builtin(),
+            snapshot_version: 1,
+            snapshot_migration: None,
+            recording: None,
+            replay: None,
+            gamepads: None,
+        }
     }
 
     fn run(&mut self) -> Scene {
-        self.world.get_resource_mut::<Timer>().unwrap().tick();
-        self.schedule.run(&mut self.world);
+        self.update();
         self.build_scene()
     }
 
+    /// Advances the game by one tick without drawing it.
+    pub fn update(&mut self) {
+        let replayed = self.replay.as_mut().and_then(|ticks| ticks.next());
+        match replayed {
+            Some(tick) => {
+                *self.world.get_resource_mut::<KeyState>().unwrap() = tick.keys;
+                *self.world.get_resource_mut::<GamepadState>().unwrap() = tick.gamepads;
+                *self.world.get_resource_mut::<Mouse>().unwrap() = tick.mouse;
+                *self.world.get_resource_mut::<Touches>().unwrap() = tick.touches;
+                self.world
+                    .get_resource_mut::<Timer>()
+                    .unwrap()
+                    .advance(tick.elapsed);
+            }
+            None => {
+                self.replay = None;
+                self.world.get_resource_mut::<Timer>().unwrap().tick();
+                self.poll_gamepads();
+            }
+        }
+
+        if let Some((_, recording)) = &mut self.recording {
+            recording.ticks.push(RecordedTick {
+                elapsed: self.world.get_resource::<Timer>().unwrap().elapsed(),
+                keys: self.world.get_resource::<KeyState>().unwrap().clone(),
+                gamepads: self.world.get_resource::<GamepadState>().unwrap().clone(),
+                mouse: self.world.get_resource::<Mouse>().unwrap().clone(),
+                touches: self.world.get_resource::<Touches>().unwrap().clone(),
+            });
+        }
+
+        self.schedule.run(&mut self.world);
+        self.clear_pressed_with_frame();
+    }
+
+    /// Culling counts from the most recently drawn frame.
+    pub fn render_stats(&self) -> RenderStats {
+        *self.world.get_resource::<RenderStats>().unwrap()
+    }
+
+    /// Ticks the game until the current replay has finished, for running replays without a
+    /// window.
+    pub fn run_headless(&mut self) {
+        while self.is_replaying() {
+            self.update();
+        }
+    }
+
+    /// Makes every tick take exactly `step` instead of following the wall clock.
+    pub fn set_fixed_timestep(&mut self, step: Option<Duration>) {
+        self.world
+            .get_resource_mut::<Timer>()
+            .unwrap()
+            .set_fixed_step(step);
+    }
+
+    /// Records the input of every tick until [`Game::stop_recording`] writes it to `file`.
+    pub fn record_input(&mut self, file: impl AsRef<Path>) {
+        self.recording = Some((file.as_ref().to_path_buf(), InputRecording::default()));
+    }
+
+    /// Writes the input recorded so far, if any, and stops recording. `App::run` calls this
+    /// when the window closes.
+    pub fn stop_recording(&mut self) -> std::io::Result<()> {
+        match self.recording.take() {
+            Some((file, recording)) => recording.write(file),
+            None => Ok(()),
+        }
+    }
+
+    /// Feeds the recorded input and tick lengths into the following ticks in place of live
+    /// input. The game has to be set up the same way as when the recording was made.
+    pub fn replay(&mut self, recording: InputRecording) {
+        *self.world.get_resource_mut::<KeyState>().unwrap() = KeyState::new();
+        *self.world.get_resource_mut::<GamepadState>().unwrap() = GamepadState::new();
+        *self.world.get_resource_mut::<Mouse>().unwrap() = Mouse::new();
+        *self.world.get_resource_mut::<Touches>().unwrap() = Touches::new();
+        self.replay = Some(recording.ticks.into_iter());
+    }
+
+    pub fn replay_input(&mut self, file: impl AsRef<Path>) -> std::io::Result<()> {
+        self.replay(InputRecording::read(file)?);
+        Ok(())
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.replay
+            .as_ref()
+            .map(|ticks| ticks.len() > 0)
+            .unwrap_or(false)
+    }
+
     pub fn spawn(&mut self, components: impl Bundle) -> Entity {
         self.world.spawn().insert_bundle(components).id()
     }
 
+    pub fn insert(&mut self, entity: Entity, component: impl Component) {
+        self.world.entity_mut(entity).insert(component);
+    }
+
+    /// Spawns an entity whose `Position`, `Rotation` and `Scale` are relative to `parent`.
+    pub fn spawn_child(&mut self, parent: Entity, components: impl Bundle) -> Entity {
+        let child = self.spawn(components);
+        self.add_child(parent, child);
+        child
+    }
+
+    pub fn add_child(&mut self, parent: Entity, child: Entity) {
+        let mut child_entity = self.world.entity_mut(child);
+        child_entity.insert(Parent(parent));
+        if !child_entity.contains::<GlobalTransform>() {
+            child_entity.insert(GlobalTransform::identity());
+        }
+
+        let mut parent_entity = self.world.entity_mut(parent);
+        if let Some(mut children) = parent_entity.get_mut::<Children>() {
+            children.0.push(child);
+        } else {
+            parent_entity.insert(Children(vec![child]));
+        }
+        if !parent_entity.contains::<GlobalTransform>() {
+            parent_entity.insert(GlobalTransform::identity());
+        }
+    }
+
     pub fn spawn_batch<I>(&mut self, iter: I) -> SpawnBatchIter<'_, I::IntoIter>
     where
         I: IntoIterator,
@@ -75,67 +297,432 @@ impl Game {
         self.schedule.add_system_to_stage("gameplay", system);
     }
 
+    /// Reads gamepad events from `source` at the start of every tick. `App::run` sets up gilrs
+    /// when the `gamepad` feature is enabled and no source has been set.
+    pub fn set_gamepad_source(&mut self, source: impl GamepadSource + 'static) {
+        self.gamepads = Some(Box::new(source));
+    }
+
+    fn poll_gamepads(&mut self) {
+        if let Some(source) = &mut self.gamepads {
+            let mut state = self.world.get_resource_mut::<GamepadState>().unwrap();
+            while let Some(event) = source.next_event() {
+                state.update(event);
+            }
+        }
+    }
+
     fn capture_input_event(&mut self, event: winit::event::WindowEvent) {
-        if let WindowEvent::KeyboardInput { input, .. } = event {
-            self.world
+        if self.replay.is_some() {
+            return;
+        }
+        match event {
+            WindowEvent::KeyboardInput { input, .. } => self
+                .world
                 .get_resource_mut::<KeyState>()
                 .unwrap()
-                .update(input)
+                .update(input),
+            WindowEvent::CursorMoved { position, .. } => {
+                let position = Vec2::new(position.x as f32, position.y as f32);
+                self.world
+                    .get_resource_mut::<Mouse>()
+                    .unwrap()
+                    .moved(Some(position));
+            }
+            WindowEvent::CursorLeft { .. } => {
+                self.world.get_resource_mut::<Mouse>().unwrap().moved(None);
+            }
+            WindowEvent::MouseInput { state, button, .. } => {
+                self.world
+                    .get_resource_mut::<Mouse>()
+                    .unwrap()
+                    .button(button, state);
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                self.world
+                    .get_resource_mut::<Mouse>()
+                    .unwrap()
+                    .scroll(delta);
+            }
+            WindowEvent::Touch(touch) => {
+                self.world
+                    .get_resource_mut::<Touches>()
+                    .unwrap()
+                    .update(touch);
+            }
+            _ => (),
         }
     }
 
+    /// Replaces the key bindings with the ones in `file`. Systems can rebind keys at runtime
+    /// through the `ActionMap` resource.
+    pub fn load_key_bindings(&mut self, file: impl AsRef<Path>) -> std::io::Result<()> {
+        self.world.insert_resource(ActionMap::read(file)?);
+        Ok(())
+    }
+
+    pub fn save_key_bindings(&self, file: impl AsRef<Path>) -> std::io::Result<()> {
+        self.world.get_resource::<ActionMap>().unwrap().write(file)
+    }
+
+    pub fn set_action_map(&mut self, map: ActionMap) {
+        self.world.insert_resource(map);
+    }
+
+    /// Switches between windowed, borderless and fullscreen once the next frame is drawn.
+    /// `App::run` also toggles borderless with F11.
+    pub fn set_window_mode(&mut self, mode: WindowMode) {
+        self.world.insert_resource(mode);
+    }
+
+    /// Saves the next frame drawn to the window as an image at `file`, in a format picked from
+    /// its extension. Pressing F12 saves one to a timestamped PNG.
+    pub fn save_screenshot(&mut self, file: impl Into<PathBuf>) {
+        self.world
+            .get_resource_mut::<Screenshots>()
+            .unwrap()
+            .0
+            .push(file.into());
+    }
+
+    /// Sets how many world pixels the window shows, see [`ScreenScaling`].
+    pub fn set_screen_scaling(&mut self, scaling: ScreenScaling) {
+        self.world.insert_resource(scaling);
+    }
+
+    /// Sprite names are needed to save and load scenes. `App::run` sets this, so it only needs
+    /// to be called to load scenes before the game starts.
+    pub fn set_sprite_catalog(&mut self, catalog: SpriteCatalog) {
+        self.world.insert_resource(catalog);
+    }
+
+    /// Allows the component to be saved in and loaded from scenes under `name`.
+    pub fn register_component<T>(&mut self, name: &str)
+    where
+        T: Component + Serialize + DeserializeOwned,
+    {
+        self.components.register::<T>(name);
+    }
+
+    pub fn register_component_with<T, S>(
+        &mut self,
+        name: &str,
+        save: fn(&T, &World) -> Result<S, SceneError>,
+        load: fn(S, &World) -> Result<T, SceneError>,
+    ) where
+        T: Component,
+        S: Serialize + DeserializeOwned + 'static,
+    {
+        self.components.register_with(name, save, load);
+    }
+
+    /// Spawns the entities of a scene file and returns them in file order.
+    pub fn load_scene(&mut self, file: impl AsRef<Path>) -> Result<Vec<Entity>, SceneError> {
+        let scene = SceneFile::read(file)?;
+        self.components.load(&mut self.world, scene)
+    }
+
+    /// Writes the registered components of every entity to a scene file.
+    pub fn save_scene(&mut self, file: impl AsRef<Path>) -> Result<(), SceneError> {
+        self.components.save(&mut self.world)?.write(file)
+    }
+
+    /// Allows the resource to be saved in and restored from snapshots under `name`.
+    pub fn register_resource<T>(&mut self, name: &str)
+    where
+        T: Component + Serialize + DeserializeOwned,
+    {
+        self.components.register_resource::<T>(name);
+    }
+
+    /// Sets the version written to new snapshots. Older snapshots are passed through `migrate`
+    /// when they are loaded.
+    pub fn set_snapshot_version(&mut self, version: u32, migrate: Migration) {
+        self.snapshot_version = version;
+        self.snapshot_migration = Some(migrate);
+    }
+
+    /// Writes the registered resources and the registered components of every entity.
+    pub fn save_snapshot(&mut self, file: impl AsRef<Path>) -> Result<(), SceneError> {
+        self.components
+            .snapshot(&mut self.world, self.snapshot_version)?
+            .write(file)
+    }
+
+    /// Replaces the world with the contents of a snapshot.
+    pub fn load_snapshot(&mut self, file: impl AsRef<Path>) -> Result<(), SceneError> {
+        let snapshot = Snapshot::read(file, self.snapshot_version, self.snapshot_migration)?;
+        self.components.restore(&mut self.world, snapshot)
+    }
+
     fn clear_pressed_with_frame(&mut self) {
         self.world
             .get_resource_mut::<KeyState>()
             .unwrap()
-            .pressed_this_frame = None;
+            .clear_frame();
+        self.world
+            .get_resource_mut::<GamepadState>()
+            .unwrap()
+            .clear_frame();
+        self.world
+            .get_resource_mut::<Mouse>()
+            .unwrap()
+            .clear_frame();
+        self.world
+            .get_resource_mut::<Touches>()
+            .unwrap()
+            .clear_frame();
     }
 
     fn build_scene(&mut self) -> Scene {
         let mut sprites: Vec<(SpriteId, InstanceRaw)> = vec![];
+        let mut translucent: Vec<(f32, SpriteId, InstanceRaw)> = vec![];
+
+        let mut query = self.world.query::<(
+            &Position,
+            &Rotation,
+            &Scale,
+            &Sprite,
+            Option<&GlobalTransform>,
+        )>();
+
+        let mut text_query = self.world.query::<(
+            &Position,
+            Option<&Rotation>,
+            Option<&Scale>,
+            &Text,
+            Option<&GlobalTransform>,
+        )>();
+
+        let mut tilemap_query = self.world.query::<&Tilemap>();
+
+        let mut collider_query = self.world.query::<(
+            &Position,
+            &Rotation,
+            &Collider,
+            Option<&Scale>,
+            Option<&GlobalTransform>,
+        )>();
 
-        let mut query = self
+        let mut tile_collider_query = self.world.query::<(
+            &Position,
+            Option<&Scale>,
+            Option<&GlobalTransform>,
+            &Tilemap,
+        )>();
+
+        let mut camera_query = self
+            .world
+            .query::<(&ActiveCamera, &ParallaxCamera, Option<&Viewport>)>();
+
+        let overlay = ui::build_overlay(&mut self.world);
+
+        let catalog = self.world.get_resource::<SpriteCatalog>();
+        let window = self.world.get_resource::<WindowSize>().unwrap();
+        let screen = self
             .world
-            .query::<(&Position, &Rotation, &Scale, &Sprite)>();
+            .get_resource::<ScreenScaling>()
+            .unwrap()
+            .fit(window);
+
+        let mut views: Vec<(Viewport, SceneView)> = camera_query
+            .iter(&self.world)
+            .filter_map(|(_, camera, viewport)| {
+                let viewport = viewport.copied().unwrap_or_default();
+                // Window viewports are laid out in the area the scaling fits the world into,
+                // sprites show their own pixels.
+                let target = match viewport.target {
+                    CameraTarget::Window => screen,
+                    CameraTarget::Sprite(id) => {
+                        let info = catalog?.get(id)?;
+                        ScreenFit {
+                            area: [0, 0, info.width, info.height],
+                            visible: Vec2::new(info.width as f32, info.height as f32),
+                        }
+                    }
+                };
+                let fit = target.viewport(&viewport);
+                let [_, _, width, height] = fit.area;
+                if width == 0 || height == 0 {
+                    return None;
+                }
+                let view = SceneView {
+                    camera_uniform: camera.generate_matrix(fit.visible),
+                    target: viewport.target,
+                    viewport: fit.area,
+                };
+                Some((viewport, view))
+            })
+            .collect();
+
+        // Cameras drawing into sprites go first so the window shows what they drew this frame.
+        views
+            .sort_by_key(|(viewport, _)| (viewport.target == CameraTarget::Window, viewport.order));
+        let views: Vec<SceneView> = views.into_iter().map(|(_, view)| view).collect();
+
+        // World instances are only kept if some view can see them. Without sprite sizes there
+        // is nothing to test, so everything is kept.
+        let mut stats = RenderStats::default();
+        let keep = |stats: &mut RenderStats, id: SpriteId, instance: &InstanceRaw| {
+            let shown = match catalog.and_then(|catalog| catalog.get(id)) {
+                Some(info) => {
+                    let size =
+                        Vec2::new(info.width as f32, info.height as f32) / PIXELS_PER_METRE as f32;
+                    views.iter().any(|view| view.shows(instance, size))
+                }
+                None => true,
+            };
+            if shown {
+                stats.visible += 1;
+            } else {
+                stats.culled += 1;
+            }
+            shown
+        };
+
+        for (pos, rot, scale, sprite, global) in query.iter(&self.world) {
+            let global = global
+                .copied()
+                .unwrap_or_else(|| GlobalTransform::from_local(pos, Some(rot), Some(scale)));
+
+            let mut pivot_offset = catalog
+                .and_then(|catalog| catalog.get(sprite.id()))
+                .map(|info| info.pivot_offset(sprite.anim_frame_index))
+                .unwrap_or_else(Vec2::zero);
+            // Flipping mirrors the frame about the quad centre, so the pivot has to follow.
+            if sprite.flip_x {
+                pivot_offset.x = -pivot_offset.x;
+            }
+            if sprite.flip_y {
+                pivot_offset.y = -pivot_offset.y;
+            }
 
-        for (pos, rot, scale, sprite) in query.iter_mut(&mut self.world) {
             let instance_raw = InstanceRaw::from(Instance {
-                position: pos.0,
-                rotation: rot.0,
-                scale: Vec3::splat(scale.0 as f32),
+                position: global.position,
+                rotation: global.rotation,
+                scale: global.scale,
                 frame_id: sprite.anim_frame_index,
+                flip_x: sprite.flip_x,
+                flip_y: sprite.flip_y,
+                pivot_offset,
             });
-            sprites.push((sprite.id(), instance_raw))
+            if !keep(&mut stats, sprite.id(), &instance_raw) {
+                continue;
+            }
+            if sprite.translucent {
+                translucent.push((global.position.z, sprite.id(), instance_raw))
+            } else {
+                sprites.push((sprite.id(), instance_raw))
+            }
         }
 
-        let mut colliders: Vec<InstanceRaw> = vec![];
+        if let Some(catalog) = catalog {
+            let font = |id: SpriteId| catalog.get(id).and_then(|info| info.font.as_ref());
+
+            for (pos, rot, scale, text, global) in text_query.iter(&self.world) {
+                let metrics = match font(text.font) {
+                    Some(metrics) => metrics,
+                    None => continue,
+                };
+                let global = global
+                    .copied()
+                    .unwrap_or_else(|| GlobalTransform::from_local(pos, rot, scale));
 
-        let mut query = self.world.query::<(&Position, &Rotation, &Collider)>();
+                // Every glyph shares the anchor and is offset like a pivot, so the text is
+                // projected as one quad would be rather than spreading out with parallax.
+                for (frame, offset) in metrics.layout(&text.value, text.align) {
+                    let instance_raw = InstanceRaw::from(Instance {
+                        position: global.position,
+                        rotation: global.rotation,
+                        scale: global.scale,
+                        frame_id: frame,
+                        flip_x: false,
+                        flip_y: false,
+                        pivot_offset: offset / PIXELS_PER_METRE as f32,
+                    });
+                    if keep(&mut stats, text.font, &instance_raw) {
+                        translucent.push((global.position.z, text.font, instance_raw));
+                    }
+                }
+            }
+        }
+
+        for tilemap in tilemap_query.iter(&self.world) {
+            let id = tilemap.sprite();
+            for (bounds, instances) in tilemap.chunks() {
+                // Chunks no view can see are skipped without testing each of their tiles.
+                if !views.iter().any(|view| view.shows(bounds, Vec2::one())) {
+                    stats.culled += instances.len();
+                    continue;
+                }
+                sprites.extend(
+                    instances
+                        .iter()
+                        .filter(|instance| keep(&mut stats, id, instance))
+                        .map(|instance| (id, *instance)),
+                );
+            }
+        }
+
+        // The camera looks down +z so the furthest sprites have the largest z and must be
+        // blended first.
+        translucent.sort_by(|(a, ..), (b, ..)| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+
+        let mut colliders: Vec<InstanceRaw> = vec![];
 
-        for (pos, rot, collider) in query.iter(&self.world) {
+        for (pos, rot, collider, scale, global) in collider_query.iter(&self.world) {
+            let global = global
+                .copied()
+                .unwrap_or_else(|| GlobalTransform::from_local(pos, Some(rot), scale));
+            let cuboid = collider.scaled(Some(&Scale(global.scale)));
             let instance_raw = InstanceRaw::from(Instance {
-                position: pos.0,
-                rotation: rot.0,
+                position: global.position,
+                rotation: global.rotation,
                 scale: Vec3::new(
-                    2.0 * collider.0.half_extents.x,
-                    2.0 * collider.0.half_extents.y,
+                    2.0 * cuboid.half_extents.x,
+                    2.0 * cuboid.half_extents.y,
                     1.0,
                 ),
                 frame_id: 0,
+                flip_x: false,
+                flip_y: false,
+                pivot_offset: Vec2::zero(),
             });
 
             colliders.push(instance_raw);
         }
 
-        let mut query = self.world.query::<(&ActiveCamera, &ParallaxCamera)>();
-
-        let (_, cam) = query.iter(&self.world).next().expect("No camera defined");
+        for (pos, scale, global, tilemap) in tile_collider_query.iter(&self.world) {
+            let global = global
+                .copied()
+                .unwrap_or_else(|| GlobalTransform::from_local(pos, None, scale));
+            for collider in tilemap.colliders() {
+                let (centre, shape) = collider.placed(&global);
+                colliders.push(InstanceRaw::from(Instance {
+                    position: centre,
+                    rotation: Quat::identity(),
+                    scale: Vec3::new(2.0 * shape.half_extents.x, 2.0 * shape.half_extents.y, 1.0),
+                    frame_id: 0,
+                    flip_x: false,
+                    flip_y: false,
+                    pivot_offset: Vec2::zero(),
+                }));
+            }
+        }
 
-        Scene {
+        let scene = Scene {
             sprite_instances: sprites,
-            camera_uniform: cam.generate_matrix(),
+            translucent_instances: translucent
+                .into_iter()
+                .map(|(_, id, instance)| (id, instance))
+                .collect(),
+            views,
             hitbox_instances: colliders,
-        }
+            overlay_instances: overlay,
+            overlay_camera_uniform: screen_space_uniform(window),
+        };
+        self.world.insert_resource(stats);
+        scene
     }
 }
 
@@ -144,3 +731,464 @@ impl Default for Game {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::SpriteInfo;
+    use crate::camera::{ActiveCamera, CameraFollow, CameraShake, CameraZoom, ParallaxCamera};
+    use crate::input::ActionState;
+    use crate::player::{
+        flip_sprite, get_input_from_actions, move_players, update_player_state_machine,
+        PlayerInput, PlayerState,
+    };
+    use crate::tilemap::Tile;
+    use bevy_ecs::prelude::With;
+    use glam::Mat4;
+    use parry2d::na::Vector2;
+    use parry2d::shape::Cuboid;
+    use std::time::Instant;
+    use winit::event::{ElementState, KeyboardInput, VirtualKeyCode};
+
+    fn tick(keys: &KeyState) -> RecordedTick {
+        RecordedTick {
+            elapsed: Duration::from_secs_f32(1.0 / 64.0),
+            keys: keys.clone(),
+            gamepads: GamepadState::new(),
+            mouse: Mouse::new(),
+            touches: Touches::new(),
+        }
+    }
+
+    #[allow(deprecated)]
+    fn key(keys: &mut KeyState, state: ElementState) {
+        keys.update(KeyboardInput {
+            scancode: 0,
+            state,
+            virtual_keycode: Some(VirtualKeyCode::Right),
+            modifiers: Default::default(),
+        });
+    }
+
+    /// Holds right for half a second, then lets go for a quarter of a second.
+    fn recording() -> InputRecording {
+        let mut keys = KeyState::new();
+        let mut ticks = vec![];
+        key(&mut keys, ElementState::Pressed);
+        ticks.push(tick(&keys));
+        keys.clear_frame();
+        ticks.extend((1..32).map(|_| tick(&keys)));
+        key(&mut keys, ElementState::Released);
+        ticks.push(tick(&keys));
+        keys.clear_frame();
+        ticks.extend((1..16).map(|_| tick(&keys)));
+        InputRecording { ticks }
+    }
+
+    /// Replays the recording into a new game and returns the player's position and camera.
+    fn replay() -> (Vec3, ParallaxCamera) {
+        let mut game = Game::new();
+        game.add_system(get_input_from_actions.system().label("input"));
+        game.add_system(
+            update_player_state_machine
+                .system()
+                .label("state")
+                .after("input"),
+        );
+        game.add_system(move_players.system().after("state"));
+
+        let player = game.spawn((
+            Position(Vec3::zero()),
+            Velocity(Vec3::zero()),
+            MoveSpeed(4.0),
+            Collider(Cuboid::new(Vector2::new(0.4, 0.6))),
+            PlayerInput::None,
+            ActionState::default(),
+            PlayerState::Standing(Instant::now()),
+        ));
+        let mut shake = CameraShake::new(Vec2::new(0.3, 0.2)).with_decay(0.2);
+        shake.add_trauma(0.5);
+        let camera = game.spawn((
+            ParallaxCamera::new(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0), 1.0, 0.1, 100.0),
+            ActiveCamera,
+            CameraFollow::new(player),
+            CameraZoom::new(2.0, 0.1),
+            shake,
+        ));
+
+        game.replay(recording());
+        game.run_headless();
+
+        let position = game.world.get::<Position>(player).unwrap().0;
+        let camera = *game.world.get::<ParallaxCamera>(camera).unwrap();
+        (position, camera)
+    }
+
+    #[test]
+    fn replays_reproduce_the_recorded_session() {
+        let (position, camera) = replay();
+
+        // 32 ticks of 1/64 seconds at 4 metres a second.
+        assert!((position.x - 2.0).abs() < 1e-4, "{}", position.x);
+        assert_eq!(camera.eye.truncate(), position.truncate());
+        assert!((camera.zoom - 2.0).abs() < 0.01, "{}", camera.zoom);
+        assert_ne!(camera.offset, Vec3::zero());
+
+        let (again, camera_again) = replay();
+        assert_eq!(again, position);
+        assert_eq!(camera_again.eye, camera.eye);
+        assert_eq!(camera_again.offset, camera.offset);
+        assert_eq!(camera_again.zoom, camera.zoom);
+    }
+
+    fn sprite_at(position: Vec3, sprite: Sprite) -> (Position, Rotation, Scale, Sprite) {
+        (
+            Position(position),
+            Rotation(Quat::identity()),
+            Scale(Vec3::one()),
+            sprite,
+        )
+    }
+
+    fn positions(instances: &[(SpriteId, InstanceRaw)]) -> Vec<Vec3> {
+        instances
+            .iter()
+            .map(|(_, instance)| instance.model().transform_point3(Vec3::zero()))
+            .collect()
+    }
+
+    #[test]
+    fn translucent_sprites_are_drawn_back_to_front_after_opaque_ones() {
+        let mut game = Game::new();
+        for z in [10.0, 30.0, 20.0].iter() {
+            game.spawn(sprite_at(Vec3::new(0.0, 0.0, *z), Sprite::translucent(0)));
+        }
+        game.spawn(sprite_at(Vec3::new(1.0, 0.0, 40.0), Sprite::new(0)));
+
+        let scene = game.build_scene();
+        let depths: Vec<f32> = positions(&scene.translucent_instances)
+            .iter()
+            .map(|position| position.z)
+            .collect();
+        assert_eq!(depths, vec![30.0, 20.0, 10.0]);
+        assert_eq!(
+            positions(&scene.sprite_instances),
+            vec![Vec3::new(1.0, 0.0, 40.0)]
+        );
+    }
+
+    #[test]
+    fn flipped_sprites_are_mirrored_by_flags_rather_than_rotated() {
+        let mut game = Game::new();
+        let position = Vec3::new(1.0, 2.0, 10.0);
+        let mut sprite = Sprite::new(0);
+        sprite.flip_x = true;
+        game.spawn(sprite_at(position, sprite));
+        sprite.flip_x = false;
+        sprite.flip_y = true;
+        game.spawn(sprite_at(position, sprite));
+
+        let scene = game.build_scene();
+        let flips: Vec<(bool, bool)> = scene
+            .sprite_instances
+            .iter()
+            .map(|(_, instance)| instance.flip())
+            .collect();
+        assert_eq!(flips, vec![(true, false), (false, true)]);
+        for (_, instance) in scene.sprite_instances.iter() {
+            assert_eq!(instance.model(), Mat4::from_translation(position));
+        }
+    }
+
+    #[test]
+    fn sprites_face_the_way_they_move() {
+        let mut game = Game::new();
+        game.add_system(flip_sprite.system());
+        let left = game.spawn((Velocity(Vec3::new(-1.0, 0.0, 0.0)), Sprite::new(0)));
+        let right = game.spawn((Velocity(Vec3::new(1.0, 0.0, 0.0)), Sprite::new(0)));
+
+        game.update();
+        assert!(game.world.get::<Sprite>(left).unwrap().flip_x);
+        assert!(!game.world.get::<Sprite>(right).unwrap().flip_x);
+
+        // Stopping keeps the sprite facing the way it last moved.
+        game.world.get_mut::<Velocity>(left).unwrap().0 = Vec3::zero();
+        game.update();
+        assert!(game.world.get::<Sprite>(left).unwrap().flip_x);
+    }
+
+    fn camera() -> (ParallaxCamera, ActiveCamera) {
+        (
+            ParallaxCamera::new(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0), 1.0, 0.1, 100.0),
+            ActiveCamera,
+        )
+    }
+
+    #[test]
+    fn pivots_follow_flipped_frames() {
+        let mut game = Game::new();
+        // 1 x 2 metres, pivoted on the top left corner.
+        game.set_sprite_catalog(SpriteCatalog(vec![SpriteInfo {
+            id: "sprite".to_string(),
+            width: 32,
+            height: 64,
+            pivots: vec![Vec2::zero()],
+            font: None,
+        }]));
+        game.spawn(camera());
+        let mut sprite = Sprite::new(0);
+        game.spawn(sprite_at(Vec3::new(0.0, 0.0, 10.0), sprite));
+        sprite.flip_x = true;
+        game.spawn(sprite_at(Vec3::new(0.0, 0.0, 10.0), sprite));
+        sprite.flip_y = true;
+        game.spawn(sprite_at(Vec3::new(0.0, 0.0, 10.0), sprite));
+
+        let pivots: Vec<Vec2> = game
+            .build_scene()
+            .sprite_instances
+            .iter()
+            .map(|(_, instance)| instance.pivot_offset())
+            .collect();
+        assert_eq!(
+            pivots,
+            vec![
+                Vec2::new(0.5, -1.0),
+                Vec2::new(-0.5, -1.0),
+                Vec2::new(-0.5, 1.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn colliders_scale_per_axis() {
+        let collider = Collider(Cuboid::new(Vector2::new(0.5, 0.5)));
+        let half_extents = |scale: Option<Scale>| collider.scaled(scale.as_ref()).half_extents;
+
+        assert_eq!(half_extents(None), Vector2::new(0.5, 0.5));
+        assert_eq!(
+            half_extents(Some(Scale(Vec3::new(2.0, 0.5, 1.0)))),
+            Vector2::new(1.0, 0.25)
+        );
+        // Mirroring does not turn the collider inside out.
+        assert_eq!(
+            half_extents(Some(Scale(Vec3::new(-3.0, 1.0, 1.0)))),
+            Vector2::new(1.5, 0.5)
+        );
+    }
+
+    #[test]
+    fn scaled_colliders_are_drawn_and_collided_with_at_their_scaled_size() {
+        let mut game = Game::new();
+        game.set_fixed_timestep(Some(Duration::from_secs(1)));
+        game.add_system(move_players.system());
+        let player = game.spawn((
+            Position(Vec3::zero()),
+            Velocity(Vec3::new(10.0, 0.0, 0.0)),
+            Collider(Cuboid::new(Vector2::new(0.5, 0.5))),
+        ));
+        // 4 metres wide, so its left edge is at 3.
+        game.spawn((
+            Position(Vec3::new(5.0, 0.0, 0.0)),
+            Rotation(Quat::identity()),
+            Scale(Vec3::new(4.0, 0.5, 1.0)),
+            Collider(Cuboid::new(Vector2::new(0.5, 0.5))),
+            Terrain,
+        ));
+
+        game.update();
+        let x = game.world.get::<Position>(player).unwrap().0.x;
+        assert!((x - 2.5).abs() < 1e-3, "{}", x);
+
+        let hitbox = game.build_scene().hitbox_instances[0].model();
+        assert_eq!(
+            hitbox,
+            Mat4::from_scale_rotation_translation(
+                Vec3::new(4.0, 0.5, 1.0),
+                Quat::identity(),
+                Vec3::new(5.0, 0.0, 0.0)
+            )
+        );
+    }
+
+    #[test]
+    fn parented_and_scaled_tilemaps_collide_where_they_are_drawn() {
+        let mut game = Game::new();
+        game.set_fixed_timestep(Some(Duration::from_secs(1)));
+        game.add_system(move_players.system());
+        let player = game.spawn((
+            Position(Vec3::new(0.0, -1.0, 0.0)),
+            Velocity(Vec3::zero()),
+            Collider(Cuboid::new(Vector2::new(0.5, 0.5))),
+        ));
+        let tile = Tile {
+            frame: 0,
+            solid: true,
+            flip_x: false,
+            flip_y: false,
+        };
+        let parent = game.spawn((Position(Vec3::new(10.0, 0.0, 0.0)),));
+        // A 2 x 2 metre block whose left edge is at 10.
+        game.spawn_child(
+            parent,
+            (
+                Position(Vec3::zero()),
+                Scale(Vec3::new(2.0, 2.0, 1.0)),
+                Tilemap::from_tiles(0, 1, vec![Some(tile)], Vec2::one()),
+            ),
+        );
+
+        // The tilemap is placed in the transform stage, after the player has moved.
+        game.update();
+        game.world.get_mut::<Velocity>(player).unwrap().0 = Vec3::new(20.0, 0.0, 0.0);
+        game.update();
+        let x = game.world.get::<Position>(player).unwrap().0.x;
+        assert!((x - 9.5).abs() < 1e-3, "{}", x);
+
+        let hitbox = game.build_scene().hitbox_instances.last().unwrap().model();
+        assert_eq!(
+            hitbox,
+            Mat4::from_scale_rotation_translation(
+                Vec3::new(2.0, 2.0, 1.0),
+                Quat::identity(),
+                Vec3::new(11.0, -1.0, 0.0)
+            )
+        );
+    }
+
+    /// A game whose only sprite is 1 metre square, with a camera at `x` on each half of the
+    /// window.
+    fn split_screen(left: f32, right: f32) -> Game {
+        let mut game = Game::new();
+        game.set_sprite_catalog(SpriteCatalog(vec![SpriteInfo {
+            id: "sprite".to_string(),
+            width: 32,
+            height: 32,
+            pivots: vec![],
+            font: None,
+        }]));
+        for (x, viewport) in [
+            (left, Viewport::new(0.0, 0.0, 0.5, 1.0)),
+            (right, Viewport::new(0.5, 0.0, 0.5, 1.0)),
+        ]
+        .iter()
+        {
+            let (mut camera, active) = camera();
+            camera.eye.x = *x;
+            game.spawn((camera, active, *viewport));
+        }
+        game
+    }
+
+    #[test]
+    fn sprites_outside_every_view_are_culled() {
+        let mut game = split_screen(0.0, 100.0);
+        for position in [
+            // In front of the left camera, and half off its side.
+            Vec3::new(0.0, 0.0, 10.0),
+            Vec3::new(4.9, 0.0, 10.0),
+            // Between the cameras, behind them and past the far plane.
+            Vec3::new(50.0, 0.0, 10.0),
+            Vec3::new(0.0, 0.0, -5.0),
+            Vec3::new(0.0, 0.0, 150.0),
+        ]
+        .iter()
+        {
+            game.spawn(sprite_at(*position, Sprite::new(0)));
+        }
+        // Only the right camera sees these.
+        game.spawn(sprite_at(Vec3::new(100.0, 0.0, 10.0), Sprite::new(0)));
+        game.spawn(sprite_at(
+            Vec3::new(101.0, 1.0, 20.0),
+            Sprite::translucent(0),
+        ));
+
+        let scene = game.build_scene();
+        assert_eq!(
+            positions(&scene.sprite_instances),
+            vec![
+                Vec3::new(0.0, 0.0, 10.0),
+                Vec3::new(4.9, 0.0, 10.0),
+                Vec3::new(100.0, 0.0, 10.0),
+            ]
+        );
+        assert_eq!(
+            positions(&scene.translucent_instances),
+            vec![Vec3::new(101.0, 1.0, 20.0)]
+        );
+        assert_eq!(
+            game.render_stats(),
+            RenderStats {
+                visible: 4,
+                culled: 3
+            }
+        );
+
+        // Without the right camera, what only it could see is culled.
+        let right = game
+            .world
+            .query::<(Entity, &ParallaxCamera)>()
+            .iter(&game.world)
+            .find(|(_, camera)| camera.eye.x == 100.0)
+            .map(|(entity, _)| entity)
+            .unwrap();
+        game.world.despawn(right);
+        let scene = game.build_scene();
+        assert_eq!(scene.sprite_instances.len(), 2);
+        assert!(scene.translucent_instances.is_empty());
+        assert_eq!(
+            game.render_stats(),
+            RenderStats {
+                visible: 2,
+                culled: 5
+            }
+        );
+    }
+
+    #[test]
+    fn tiles_are_culled_by_chunk_and_counted_one_each() {
+        let mut game = split_screen(0.0, 100.0);
+        let tile = Some(Tile {
+            frame: 0,
+            solid: false,
+            flip_x: false,
+            flip_y: false,
+        });
+        let tiles = |width: usize| Tilemap::from_tiles(0, width, vec![tile; width], Vec2::one());
+        game.spawn((Position(Vec3::new(-1.0, 0.0, 10.0)), tiles(3)));
+        game.spawn((Position(Vec3::new(50.0, 0.0, 10.0)), tiles(4)));
+        game.update();
+
+        let scene = game.build_scene();
+        assert_eq!(scene.sprite_instances.len(), 3);
+        assert_eq!(
+            game.render_stats(),
+            RenderStats {
+                visible: 3,
+                culled: 4
+            }
+        );
+    }
+
+    #[test]
+    fn nothing_is_drawn_without_a_camera() {
+        let mut game = split_screen(0.0, 100.0);
+        game.spawn(sprite_at(Vec3::new(0.0, 0.0, 10.0), Sprite::new(0)));
+        let cameras: Vec<Entity> = game
+            .world
+            .query_filtered::<Entity, With<ActiveCamera>>()
+            .iter(&game.world)
+            .collect();
+        for camera in cameras {
+            game.world.despawn(camera);
+        }
+
+        assert!(game.build_scene().sprite_instances.is_empty());
+        assert_eq!(
+            game.render_stats(),
+            RenderStats {
+                visible: 0,
+                culled: 1
+            }
+        );
+    }
+}
@@ -1,15 +1,22 @@
-use crate::input::KeyState;
+use crate::input::{Action, ActionState};
 use crate::sprite::{AnimTimeline, Sprite};
+use crate::tilemap::Tilemap;
 use crate::time::Timer;
-use crate::{Collider, MoveSpeed, Position, Rotation, Terrain, Velocity};
+use crate::transform::GlobalTransform;
+use crate::{Collider, MoveSpeed, Position, Scale, Terrain, Velocity};
 use bevy_ecs::prelude::{Changed, Query, Res, Without};
-use glam::{Quat, Vec3};
+use glam::{Vec2, Vec3};
 use parry2d::math::Isometry;
 use parry2d::na::Vector2;
 use parry2d::query::TOIStatus;
+use parry2d::shape::Cuboid;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
-use std::time::Instant;
-use winit::event::VirtualKeyCode;
+use std::time::{Duration, Instant};
+
+/// Attack presses are remembered for this long, so pressing attack just before the current
+/// attack finishes starts the next one.
+const ATTACK_BUFFER: Duration = Duration::from_millis(150);
 
 #[derive(Clone, Copy)]
 pub enum PlayerState {
@@ -18,6 +25,7 @@ pub enum PlayerState {
     Attacking(Instant),
 }
 
+#[derive(Serialize, Deserialize)]
 pub enum PlayerInput {
     Left,
     Right,
@@ -68,7 +76,7 @@ impl PlayerState {
             }
             (Self::Attacking(start), PlayerInput::Attack) => {
                 if now.duration_since(start).as_secs_f32() >= attack_duration {
-                    (PlayerState::Standing(now), Vec3::zero())
+                    (PlayerState::Attacking(now), Vec3::zero())
                 } else {
                     (PlayerState::Attacking(start), Vec3::zero())
                 }
@@ -95,30 +103,83 @@ impl PlayerState {
 }
 
 pub fn update_player_state_machine(
-    mut query: Query<(&mut PlayerState, &PlayerInput, &mut Velocity, &MoveSpeed)>,
+    mut query: Query<(
+        &mut PlayerState,
+        &PlayerInput,
+        &mut Velocity,
+        &MoveSpeed,
+        Option<&mut ActionState>,
+    )>,
     timer: Res<Timer>,
 ) {
-    for (mut state, input, mut vel, speed) in query.iter_mut() {
+    for (mut state, input, mut vel, speed, actions) in query.iter_mut() {
+        let now = timer.now();
         let (new_state, new_vel) =
-            state.handle_player_input(Vec3::new(speed.0, 0.0, 0.0), &input, timer.now());
+            state.handle_player_input(Vec3::new(speed.0, 0.0, 0.0), &input, now);
+
+        // A buffered attack press only starts one attack.
+        if let (PlayerState::Attacking(start), Some(mut actions)) = (new_state, actions) {
+            if start == now {
+                actions.consume(Action::Attack);
+            }
+        }
 
         vel.0 = new_vel;
         *state = new_state;
     }
 }
 
+#[allow(clippy::type_complexity)]
 pub fn move_players(
-    terrain: Query<(&Collider, &Position, &Terrain)>,
-    mut players: Query<(&Collider, &mut Position, &Velocity, Without<Terrain>)>,
+    terrain: Query<(
+        &Collider,
+        &Position,
+        Option<&Scale>,
+        Option<&GlobalTransform>,
+        &Terrain,
+    )>,
+    tilemaps: Query<(
+        &Position,
+        Option<&Scale>,
+        Option<&GlobalTransform>,
+        &Tilemap,
+    )>,
+    mut players: Query<
+        (&Collider, &mut Position, Option<&Scale>, &Velocity),
+        (Without<Terrain>, Without<Tilemap>),
+    >,
     timer: Res<Timer>,
 ) {
     let max_toi = timer.elapsed().as_secs_f32();
     let threshold = 0.00001;
 
-    for (player_collider, mut pos, vel, _) in players.iter_mut() {
-        let collision = terrain
+    // Obstacles that are part of a hierarchy are placed by their world space transform.
+    let place = |pos: &Position, scale: Option<&Scale>, global: Option<&GlobalTransform>| {
+        global
+            .copied()
+            .unwrap_or_else(|| GlobalTransform::from_local(pos, None, scale))
+    };
+    let obstacles: Vec<(Vec2, Cuboid)> = terrain
+        .iter()
+        .map(|(collider, pos, scale, global, _)| {
+            let global = place(pos, scale, global);
+            let scale = Scale(global.scale);
+            (global.position.truncate(), collider.scaled(Some(&scale)))
+        })
+        .chain(tilemaps.iter().flat_map(|(pos, scale, global, tilemap)| {
+            let global = place(pos, scale, global);
+            tilemap.colliders().iter().map(move |collider| {
+                let (centre, shape) = collider.placed(&global);
+                (centre.truncate(), shape)
+            })
+        }))
+        .collect();
+
+    for (player_collider, mut pos, player_scale, vel) in players.iter_mut() {
+        let player_collider = player_collider.scaled(player_scale);
+        let collision = obstacles
             .iter()
-            .filter_map(|(terrain_collider, terrain_pos, _)| {
+            .filter_map(|(terrain_pos, terrain_collider)| {
                 // If we query against the actually location of the terrain, the player collider
                 // will penetrate the terrain collider.
                 // Once the player has penetrated the terrain collider it will forever be stuck as
@@ -126,16 +187,16 @@ pub fn move_players(
                 // This can be resolved by querying against a virtual terrain collider that has been
                 // shifted closer to the player rather than the actual terrain collider.
                 let virtual_pos1 = Isometry::translation(
-                    terrain_pos.0.x - threshold * vel.0.x,
-                    terrain_pos.0.y - threshold * vel.0.y,
+                    terrain_pos.x - threshold * vel.0.x,
+                    terrain_pos.y - threshold * vel.0.y,
                 );
                 parry2d::query::time_of_impact(
                     &virtual_pos1,
                     &Vector2::new(0.0, 0.0),
-                    &terrain_collider.0,
+                    terrain_collider,
                     &Isometry::translation(pos.0.x, pos.0.y),
                     &Vector2::new(vel.0.x, vel.0.y),
-                    &player_collider.0,
+                    &player_collider,
                     max_toi,
                 )
                 .unwrap()
@@ -168,29 +229,19 @@ pub fn move_players(
     }
 }
 
-pub fn get_input_from_keystate(mut query: Query<&mut PlayerInput>, key_state: Res<KeyState>) {
-    for mut command in query.iter_mut() {
-        let next = match *key_state {
-            KeyState {
-                pressed_this_frame: Some(VirtualKeyCode::A),
-                ..
-            } => PlayerInput::Attack,
-            KeyState {
-                left: true,
-                right: false,
-                ..
-            } => PlayerInput::Left,
-            KeyState {
-                left: false,
-                right: true,
-                ..
-            } => PlayerInput::Right,
-            KeyState {
-                left: true,
-                right: true,
-                ..
-            } => PlayerInput::Right,
-            _ => PlayerInput::None,
+pub fn get_input_from_actions(
+    mut query: Query<(&ActionState, &mut PlayerInput)>,
+    timer: Res<Timer>,
+) {
+    for (actions, mut command) in query.iter_mut() {
+        let next = if actions.pressed_within(Action::Attack, ATTACK_BUFFER, timer.now()) {
+            PlayerInput::Attack
+        } else if actions.held(Action::MoveRight) {
+            PlayerInput::Right
+        } else if actions.held(Action::MoveLeft) {
+            PlayerInput::Left
+        } else {
+            PlayerInput::None
         };
         *command = next;
     }
@@ -205,14 +256,134 @@ pub fn update_animation_state(
     }
 }
 
-pub fn flip_sprite(mut query: Query<(Changed<Velocity>, &Velocity, &mut Rotation, &Sprite)>) {
-    for (vel_changed, vel, mut rot, _) in query.iter_mut() {
+pub fn flip_sprite(mut query: Query<(Changed<Velocity>, &Velocity, &mut Sprite)>) {
+    for (vel_changed, vel, mut sprite) in query.iter_mut() {
         if vel_changed {
             if vel.0.x > 0.0 {
-                rot.0 = Quat::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), 0.0);
+                sprite.flip_x = false;
             } else if vel.0.x < 0.0 {
-                rot.0 = Quat::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), 180.0_f32.to_radians());
+                sprite.flip_x = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gamepad::GamepadState;
+    use crate::input::{update_action_state, ActionMap, KeyState};
+    use bevy_ecs::prelude::{
+        Entity, IntoSystem, ParallelSystemDescriptorCoercion, Stage, SystemStage, World,
+    };
+    use winit::event::{ElementState, KeyboardInput, VirtualKeyCode};
+
+    struct Player {
+        world: World,
+        stage: SystemStage,
+        player: Entity,
+    }
+
+    impl Player {
+        /// A player that started attacking on this tick.
+        fn attacking() -> Self {
+            let mut world = World::default();
+            let timer = Timer::new();
+            let now = timer.now();
+            world.insert_resource(timer);
+            world.insert_resource(KeyState::new());
+            world.insert_resource(GamepadState::new());
+            world.insert_resource(ActionMap::default());
+            let player = world
+                .spawn()
+                .insert_bundle((
+                    PlayerState::Attacking(now),
+                    PlayerInput::None,
+                    Velocity(Vec3::zero()),
+                    MoveSpeed(1.0),
+                    ActionState::default(),
+                ))
+                .id();
+            let mut stage = SystemStage::single_threaded();
+            stage.add_system(update_action_state.system().label("actions"));
+            stage.add_system(
+                get_input_from_actions
+                    .system()
+                    .label("input")
+                    .after("actions"),
+            );
+            stage.add_system(update_player_state_machine.system().after("input"));
+            Player {
+                world,
+                stage,
+                player,
             }
         }
+
+        #[allow(deprecated)]
+        fn tap_attack(&mut self) {
+            let mut keys = self.world.get_resource_mut::<KeyState>().unwrap();
+            for state in [ElementState::Pressed, ElementState::Released].iter() {
+                keys.update(KeyboardInput {
+                    scancode: 0,
+                    state: *state,
+                    virtual_keycode: Some(VirtualKeyCode::A),
+                    modifiers: Default::default(),
+                });
+            }
+        }
+
+        /// Advances the clock by `millis` and runs the systems.
+        fn tick(&mut self, millis: u64) {
+            self.world
+                .get_resource_mut::<Timer>()
+                .unwrap()
+                .advance(Duration::from_millis(millis));
+            self.stage.run(&mut self.world);
+            self.world
+                .get_resource_mut::<KeyState>()
+                .unwrap()
+                .clear_frame();
+        }
+
+        /// Seconds since the current state started.
+        fn state(&self) -> (&'static str, f32) {
+            let now = self.world.get_resource::<Timer>().unwrap().now();
+            let since = |start: &Instant| (now - *start).as_secs_f32();
+            match self.world.get::<PlayerState>(self.player).unwrap() {
+                PlayerState::Standing(start) => ("standing", since(start)),
+                PlayerState::Running(start) => ("running", since(start)),
+                PlayerState::Attacking(start) => ("attacking", since(start)),
+            }
+        }
+    }
+
+    #[test]
+    fn attacks_pressed_just_before_the_last_one_ends_follow_it() {
+        let mut player = Player::attacking();
+        player.tap_attack();
+        player.tick(350);
+        assert_eq!(player.state().0, "attacking");
+        assert!(player.state().1 > 0.3);
+
+        // The press was 100 ms ago when the attack ends, so a new one starts.
+        player.tick(100);
+        assert_eq!(player.state(), ("attacking", 0.0));
+
+        // And the press is used up by it.
+        player.tick(450);
+        assert_eq!(player.state(), ("standing", 0.0));
+    }
+
+    #[test]
+    fn attacks_pressed_too_early_are_dropped() {
+        let mut player = Player::attacking();
+        player.tick(200);
+        player.tap_attack();
+        player.tick(50);
+
+        // The press was 200 ms ago when the attack ends.
+        player.tick(200);
+        assert_eq!(player.state(), ("standing", 0.0));
     }
 }
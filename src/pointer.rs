@@ -0,0 +1,314 @@
+use crate::camera::{ParallaxCamera, ScreenFit};
+use glam::{Vec2, Vec3};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use winit::event::{ElementState, MouseButton, MouseScrollDelta, TouchPhase};
+
+/// Pixel scroll deltas, from touchpads, are converted to lines of this many pixels.
+const PIXELS_PER_LINE: f32 = 20.0;
+
+/// Cursor position, buttons and scrolling since the last tick. Positions are in physical pixels
+/// from the top left of the window.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Mouse {
+    position: Option<Vec2>,
+    held: Vec<MouseButton>,
+    just_pressed: Vec<MouseButton>,
+    just_released: Vec<MouseButton>,
+    wheel: Vec2,
+}
+
+impl Mouse {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// `None` while the cursor is outside the window.
+    pub fn position(&self) -> Option<Vec2> {
+        self.position
+    }
+
+    /// The point at world depth `z` under the cursor. For cameras with a [`Viewport`], pass the
+    /// fit of their viewport from [`ScreenFit::viewport`], and check that it
+    /// [contains](ScreenFit::contains) the cursor to find the camera it is over.
+    ///
+    /// [`Viewport`]: crate::camera::Viewport
+    pub fn world_position(
+        &self,
+        camera: &ParallaxCamera,
+        screen: &ScreenFit,
+        z: f32,
+    ) -> Option<Vec3> {
+        self.position
+            .map(|position| screen.unproject(camera, position, z))
+    }
+
+    pub fn held(&self, button: MouseButton) -> bool {
+        self.held.contains(&button)
+    }
+
+    pub fn just_pressed(&self, button: MouseButton) -> bool {
+        self.just_pressed.contains(&button)
+    }
+
+    pub fn just_released(&self, button: MouseButton) -> bool {
+        self.just_released.contains(&button)
+    }
+
+    /// Lines scrolled since the last tick, positive up and to the right.
+    pub fn wheel(&self) -> Vec2 {
+        self.wheel
+    }
+
+    pub(crate) fn moved(&mut self, position: Option<Vec2>) {
+        self.position = position;
+    }
+
+    pub(crate) fn button(&mut self, button: MouseButton, state: ElementState) {
+        match state {
+            ElementState::Pressed => {
+                if !self.held.contains(&button) {
+                    self.held.push(button);
+                    self.just_pressed.push(button);
+                }
+            }
+            ElementState::Released => {
+                if self.held.contains(&button) {
+                    self.held.retain(|held| *held != button);
+                    self.just_released.push(button);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn scroll(&mut self, delta: MouseScrollDelta) {
+        self.wheel += match delta {
+            MouseScrollDelta::LineDelta(x, y) => Vec2::new(x, y),
+            MouseScrollDelta::PixelDelta(delta) => {
+                Vec2::new(delta.x as f32, delta.y as f32) / PIXELS_PER_LINE
+            }
+        };
+    }
+
+    pub(crate) fn clear_frame(&mut self) {
+        self.just_pressed.clear();
+        self.just_released.clear();
+        self.wheel = Vec2::zero();
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Touch {
+    pub id: u64,
+    /// Physical pixels from the top left of the window.
+    pub position: Vec2,
+    pub start_position: Vec2,
+}
+
+impl Touch {
+    /// The point at world depth `z` under the touch, see [`Mouse::world_position`].
+    pub fn world_position(&self, camera: &ParallaxCamera, screen: &ScreenFit, z: f32) -> Vec3 {
+        screen.unproject(camera, self.position, z)
+    }
+}
+
+/// Fingers on the screen, and the touches that started or ended since the last tick.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Touches {
+    active: BTreeMap<u64, Touch>,
+    started: Vec<u64>,
+    ended: Vec<Touch>,
+}
+
+impl Touches {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Touch> {
+        self.active.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Touch> {
+        self.active.values()
+    }
+
+    /// Touches that started since the last tick, including taps that have already ended.
+    pub fn just_started(&self) -> impl Iterator<Item = &Touch> {
+        self.started.iter().filter_map(move |id| {
+            self.active
+                .get(id)
+                .or_else(|| self.ended.iter().find(|touch| touch.id == *id))
+        })
+    }
+
+    /// Touches that were lifted or cancelled, with their last position.
+    pub fn just_ended(&self) -> &[Touch] {
+        &self.ended
+    }
+
+    pub(crate) fn update(&mut self, touch: winit::event::Touch) {
+        let position = Vec2::new(touch.location.x as f32, touch.location.y as f32);
+        match touch.phase {
+            TouchPhase::Started => {
+                self.active.insert(
+                    touch.id,
+                    Touch {
+                        id: touch.id,
+                        position,
+                        start_position: position,
+                    },
+                );
+                self.started.push(touch.id);
+            }
+            TouchPhase::Moved => {
+                if let Some(active) = self.active.get_mut(&touch.id) {
+                    active.position = position;
+                }
+            }
+            TouchPhase::Ended | TouchPhase::Cancelled => {
+                if let Some(mut active) = self.active.remove(&touch.id) {
+                    active.position = position;
+                    self.ended.push(active);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn clear_frame(&mut self) {
+        self.started.clear();
+        self.ended.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Viewport;
+    use winit::dpi::PhysicalPosition;
+    use winit::event::DeviceId;
+
+    fn touch(id: u64, phase: TouchPhase, x: f64, y: f64) -> winit::event::Touch {
+        winit::event::Touch {
+            // Never passed to winit.
+            device_id: unsafe { DeviceId::dummy() },
+            phase,
+            location: PhysicalPosition::new(x, y),
+            force: None,
+            id,
+        }
+    }
+
+    fn ids<'a>(touches: impl Iterator<Item = &'a Touch>) -> Vec<u64> {
+        touches.map(|touch| touch.id).collect()
+    }
+
+    #[test]
+    fn touches_start_and_end_for_one_tick() {
+        let mut touches = Touches::new();
+        touches.update(touch(1, TouchPhase::Started, 10.0, 20.0));
+        assert_eq!(ids(touches.just_started()), vec![1]);
+
+        touches.clear_frame();
+        touches.update(touch(1, TouchPhase::Moved, 30.0, 40.0));
+        assert_eq!(ids(touches.just_started()), Vec::<u64>::new());
+        let moved = touches.get(1).unwrap();
+        assert_eq!(moved.position, Vec2::new(30.0, 40.0));
+        assert_eq!(moved.start_position, Vec2::new(10.0, 20.0));
+
+        touches.clear_frame();
+        touches.update(touch(1, TouchPhase::Cancelled, 35.0, 40.0));
+        assert!(touches.get(1).is_none());
+        assert_eq!(touches.just_ended()[0].position, Vec2::new(35.0, 40.0));
+
+        touches.clear_frame();
+        assert!(touches.just_ended().is_empty());
+        assert_eq!(touches.iter().count(), 0);
+    }
+
+    #[test]
+    fn taps_within_one_tick_start_and_end() {
+        let mut touches = Touches::new();
+        touches.update(touch(1, TouchPhase::Started, 10.0, 20.0));
+        touches.update(touch(2, TouchPhase::Started, 50.0, 20.0));
+        touches.update(touch(1, TouchPhase::Ended, 12.0, 20.0));
+
+        assert_eq!(ids(touches.just_started()), vec![1, 2]);
+        assert_eq!(ids(touches.just_ended().iter()), vec![1]);
+        assert_eq!(ids(touches.iter()), vec![2]);
+        // Fingers that ended without starting, eg. before the window had focus, are ignored.
+        touches.update(touch(3, TouchPhase::Ended, 0.0, 0.0));
+        assert_eq!(touches.just_ended().len(), 1);
+    }
+
+    #[test]
+    fn buttons_are_pressed_and_released_once() {
+        let mut mouse = Mouse::new();
+        mouse.button(MouseButton::Left, ElementState::Pressed);
+        // Repeated presses, eg. from a second device, do not press again.
+        mouse.button(MouseButton::Left, ElementState::Pressed);
+        mouse.button(MouseButton::Right, ElementState::Released);
+        assert!(mouse.held(MouseButton::Left) && mouse.just_pressed(MouseButton::Left));
+        assert_eq!(mouse.just_pressed, vec![MouseButton::Left]);
+        assert!(!mouse.just_released(MouseButton::Right));
+
+        mouse.clear_frame();
+        assert!(mouse.held(MouseButton::Left) && !mouse.just_pressed(MouseButton::Left));
+
+        mouse.button(MouseButton::Left, ElementState::Released);
+        mouse.button(MouseButton::Middle, ElementState::Pressed);
+        mouse.button(MouseButton::Middle, ElementState::Released);
+        assert!(!mouse.held(MouseButton::Left) && mouse.just_released(MouseButton::Left));
+        // Clicks within one tick are both pressed and released.
+        assert!(mouse.just_pressed(MouseButton::Middle));
+        assert!(mouse.just_released(MouseButton::Middle));
+        assert!(!mouse.held(MouseButton::Middle));
+
+        mouse.clear_frame();
+        assert!(!mouse.just_released(MouseButton::Left));
+    }
+
+    #[test]
+    fn scrolling_adds_up_until_the_next_tick() {
+        let mut mouse = Mouse::new();
+        mouse.scroll(MouseScrollDelta::LineDelta(0.0, 1.0));
+        mouse.scroll(MouseScrollDelta::PixelDelta(PhysicalPosition::new(
+            f64::from(PIXELS_PER_LINE),
+            0.0,
+        )));
+        assert_eq!(mouse.wheel(), Vec2::new(1.0, 1.0));
+        mouse.clear_frame();
+        assert_eq!(mouse.wheel(), Vec2::zero());
+    }
+
+    #[test]
+    fn cursors_over_a_split_screen_view_are_placed_by_its_camera() {
+        let screen = ScreenFit {
+            area: [0, 0, 640, 360],
+            visible: Vec2::new(640.0, 360.0),
+        };
+        let right = screen.viewport(&Viewport::new(0.5, 0.0, 0.5, 1.0));
+        assert_eq!(right.area, [320, 0, 320, 360]);
+        assert_eq!(right.visible, Vec2::new(320.0, 360.0));
+
+        let camera = ParallaxCamera::new(
+            Vec3::new(2.0, 1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            1.0,
+            0.1,
+            100.0,
+        );
+        let mut mouse = Mouse::new();
+        mouse.moved(Some(Vec2::new(480.0, 180.0)));
+        assert!(right.contains(mouse.position().unwrap()));
+        let left = screen.viewport(&Viewport::new(0.0, 0.0, 0.5, 1.0));
+        assert!(!left.contains(mouse.position().unwrap()));
+
+        // The centre of the right half is under its camera, and the view does not stretch.
+        let centre = mouse.world_position(&camera, &right, 10.0).unwrap();
+        assert!((centre - Vec3::new(2.0, 1.0, 10.0)).abs().max_element() < 1e-4);
+        mouse.moved(Some(Vec2::new(640.0, 0.0)));
+        let corner = mouse.world_position(&camera, &right, 10.0).unwrap() - centre;
+        assert!((corner.x / corner.y - 160.0 / 180.0).abs() < 1e-4);
+    }
+}
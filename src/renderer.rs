@@ -1,31 +1,58 @@
-use std::{mem, num::NonZeroU32};
+use std::{collections::HashMap, mem, num::NonZeroU32, ops::Range};
 
-use wgpu::{util::DeviceExt, BlendFactor, BlendOperation};
+use image::RgbaImage;
 
-use gpu_primitives::{CameraUniform, InstanceRaw, Vertex};
+use wgpu::util::DeviceExt;
+
+use gpu_primitives::{CameraUniform, InstanceRaw};
+use pipeline::Pipelines;
 use scene::Scene;
 use sprite::{DrawSprite, Sprite};
-use texture::DepthTexture;
+use texture::{ArrayTexture, CaptureTexture, DepthTexture};
 
-use crate::asset::SpriteRegistry;
+use crate::asset::{SpriteId, SpriteRegistry};
+use crate::camera::CameraTarget;
 use crate::renderer::hitbox::{DrawHitbox, Hitbox};
 
 pub mod gpu_primitives;
 mod hitbox;
+mod pipeline;
+pub mod reference;
 pub mod scene;
 pub mod sprite;
 pub mod texture;
 
 pub const TEXTURE_ARRAY_SIZE: usize = 128;
 
+const CLEAR_COLOUR: wgpu::Color = wgpu::Color {
+    r: 0.0,
+    g: 0.0,
+    b: 0.0,
+    a: 0.0,
+};
+
+/// Sprites that cameras draw into start opaque. The opaque pipeline keeps the smaller alpha, so
+/// clearing to `CLEAR_COLOUR` would leave the whole sprite transparent and discarded when drawn.
+const SPRITE_TARGET_CLEAR_COLOUR: wgpu::Color = wgpu::Color {
+    a: 1.0,
+    ..CLEAR_COLOUR
+};
+
 pub struct Renderer {
     sprites: Vec<Sprite>,
     hitbox: Hitbox,
-    uniform_buffer: wgpu::Buffer,
-    sprite_pipeline: wgpu::RenderPipeline,
-    hitbox_pipeline: wgpu::RenderPipeline,
+    window_pipelines: Pipelines,
+    /// For cameras that draw into a sprite.
+    sprite_pipelines: Pipelines,
     depth_texture: DepthTexture,
-    uniform_bind_group: wgpu::BindGroup,
+    /// Depth textures for sprites that cameras draw into, made the first time one does.
+    sprite_depth_textures: HashMap<SpriteId, DepthTexture>,
+    /// Format of the window, which captures of it are drawn in too.
+    format: wgpu::TextureFormat,
+    uniform_bind_group_layout: wgpu::BindGroupLayout,
+    /// A camera uniform for each view of the scene, grown as more views are drawn.
+    view_uniforms: Vec<(wgpu::Buffer, wgpu::BindGroup)>,
+    overlay_uniform: (wgpu::Buffer, wgpu::BindGroup),
 }
 
 impl Renderer {
@@ -35,12 +62,6 @@ impl Renderer {
         queue: &wgpu::Queue,
         sprite_registry: SpriteRegistry,
     ) -> Self {
-        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Uniform Buffer"),
-            contents: &[0u8; mem::size_of::<CameraUniform>()],
-            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
-        });
-
         let uniform_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: None,
@@ -58,14 +79,7 @@ impl Renderer {
                 }],
             });
 
-        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: None,
-            layout: &uniform_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: uniform_buffer.as_entire_binding(),
-            }],
-        });
+        let overlay_uniform = create_uniform(device, &uniform_bind_group_layout);
 
         let sprite_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -113,107 +127,27 @@ impl Renderer {
             push_constant_ranges: &[],
         });
 
-        let vs_module =
-            device.create_shader_module(&wgpu::include_spirv!("../shaders/shader.vert.spv"));
-        let fs_module =
-            device.create_shader_module(&wgpu::include_spirv!("../shaders/shader.frag.spv"));
-        let wire_module =
-            device.create_shader_module(&wgpu::include_spirv!("../shaders/wire.frag.spv"));
-
         let depth_texture = DepthTexture::new(&device, &sc_desc);
 
-        let sprite_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: None,
-            layout: Some(&pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &vs_module,
-                entry_point: "main",
-                buffers: &[Vertex::desc(), InstanceRaw::desc()],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &fs_module,
-                entry_point: "main",
-                targets: &[wgpu::ColorTargetState {
-                    format: sc_desc.format,
-                    color_blend: wgpu::BlendState {
-                        src_factor: BlendFactor::SrcAlpha,
-                        dst_factor: BlendFactor::OneMinusSrcAlpha,
-                        operation: BlendOperation::Add,
-                    },
-                    alpha_blend: wgpu::BlendState {
-                        src_factor: BlendFactor::One,
-                        dst_factor: BlendFactor::One,
-                        operation: BlendOperation::Min,
-                    },
-                    write_mask: wgpu::ColorWrite::ALL,
-                }],
-            }),
-            primitive: wgpu::PrimitiveState {
-                cull_mode: wgpu::CullMode::None,
-                ..Default::default()
-            },
-            depth_stencil: Some(wgpu::DepthStencilState {
-                format: DepthTexture::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
-                bias: Default::default(),
-                clamp_depth: false,
-            }),
-            multisample: wgpu::MultisampleState::default(),
-        });
-
-        let hitbox_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: None,
-            layout: Some(&pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &vs_module,
-                entry_point: "main",
-                buffers: &[Vertex::desc(), InstanceRaw::desc()],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &wire_module,
-                entry_point: "main",
-                targets: &[wgpu::ColorTargetState {
-                    format: sc_desc.format,
-                    color_blend: wgpu::BlendState {
-                        operation: wgpu::BlendOperation::Add,
-                        src_factor: wgpu::BlendFactor::SrcAlpha,
-                        dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
-                    },
-                    alpha_blend: wgpu::BlendState::REPLACE,
-                    write_mask: wgpu::ColorWrite::ALL,
-                }],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::LineStrip,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: wgpu::CullMode::Back,
-                polygon_mode: wgpu::PolygonMode::Line,
-                ..Default::default()
-            },
-            depth_stencil: Some(wgpu::DepthStencilState {
-                format: DepthTexture::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
-                bias: Default::default(),
-                clamp_depth: false,
-            }),
-            multisample: wgpu::MultisampleState::default(),
-        });
-
         Renderer {
-            uniform_buffer,
-            sprite_pipeline,
-            hitbox_pipeline,
+            window_pipelines: Pipelines::new(device, &pipeline_layout, sc_desc.format),
+            sprite_pipelines: Pipelines::new(device, &pipeline_layout, ArrayTexture::FORMAT),
             sprites,
             depth_texture,
-            uniform_bind_group,
+            sprite_depth_textures: HashMap::new(),
+            format: sc_desc.format,
+            uniform_bind_group_layout,
+            view_uniforms: vec![],
+            overlay_uniform,
             hitbox: Hitbox::new(device),
         }
     }
 
+    /// Remakes everything sized to the window after the swap chain changes size.
+    pub fn resize(&mut self, device: &wgpu::Device, sc_desc: &wgpu::SwapChainDescriptor) {
+        self.depth_texture = DepthTexture::new(device, sc_desc);
+    }
+
     pub fn render(
         &mut self,
         frame: &wgpu::SwapChainTexture,
@@ -222,85 +156,262 @@ impl Renderer {
         _sc_desc: &wgpu::SwapChainDescriptor,
         scene: Scene,
     ) {
+        let batches = self.prepare(device, queue, &scene);
+
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        self.draw(
+            &mut encoder,
+            &frame.view,
+            &self.depth_texture,
+            &scene,
+            &batches,
+        );
+        queue.submit(Some(encoder.finish()));
+    }
+
+    /// Draws `scene` into a texture of `width` x `height` instead of the window and reads it
+    /// back. The scene's viewports should be laid out for that size, as they are when it is the
+    /// size of the window.
+    pub fn capture(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        scene: &Scene,
+        width: u32,
+        height: u32,
+    ) -> RgbaImage {
+        let batches = self.prepare(device, queue, scene);
+        let target = CaptureTexture::new(device, width, height, self.format);
+        let depth = DepthTexture::with_size(device, width, height);
+
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        self.draw(&mut encoder, &target.view, &depth, scene, &batches);
+        target.copy_to_buffer(&mut encoder);
+        queue.submit(Some(encoder.finish()));
+
+        target.read(device)
+    }
+
+    /// Writes the uniforms and instance buffers for `scene` and works out how its instances are
+    /// split between the pipelines.
+    fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, scene: &Scene) -> Batches {
+        while self.view_uniforms.len() < scene.views.len() {
+            let uniform = create_uniform(device, &self.uniform_bind_group_layout);
+            self.view_uniforms.push(uniform);
+        }
+
+        for (view, (buffer, _)) in scene.views.iter().zip(self.view_uniforms.iter()) {
+            queue.write_buffer(buffer, 0, bytemuck::bytes_of(&view.camera_uniform));
+        }
         queue.write_buffer(
-            &self.uniform_buffer,
+            &self.overlay_uniform.0,
             0,
-            bytemuck::bytes_of(&scene.camera_uniform),
+            bytemuck::bytes_of(&scene.overlay_camera_uniform),
         );
 
+        for view in scene.views.iter() {
+            if let CameraTarget::Sprite(id) = view.target {
+                let sprite = &self.sprites[id];
+                self.sprite_depth_textures.entry(id).or_insert_with(|| {
+                    DepthTexture::with_size(device, sprite.width, sprite.height)
+                });
+            }
+        }
+
         self.hitbox
-            .update_instance_buffer(scene.hitbox_instances.clone(), queue);
-
-        for (id, sprite) in self.sprites.iter_mut().enumerate() {
-            let instances = scene
-                .sprite_instances
-                .iter()
-                .filter(|(i, _)| *i == id)
-                .map(|(_, instance)| *instance)
-                .collect();
-            sprite.update_instance_buffer(instances, queue);
+            .update_instance_buffer(scene.hitbox_instances.clone(), device, queue);
+
+        // Each sprite's instance buffer holds its opaque instances followed by its translucent
+        // ones, then its overlay ones. Translucent and overlay instances are drawn in runs of
+        // the same sprite so their order is kept across different sprites.
+        let mut instances: Vec<Vec<InstanceRaw>> = vec![vec![]; self.sprites.len()];
+
+        for (id, instance) in scene.sprite_instances.iter() {
+            instances[*id].push(*instance);
         }
 
-        let mut encoder =
-            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-        {
-            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
-                color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                    attachment: &frame.view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.0,
-                            g: 0.0,
-                            b: 0.0,
-                            a: 0.0,
-                        }),
-                        store: true,
-                    },
-                }],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
-                    attachment: &self.depth_texture.view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: true,
-                    }),
-                    stencil_ops: None,
-                }),
-            });
+        let opaque_counts: Vec<u32> = instances.iter().map(|i| i.len() as u32).collect();
+
+        let mut translucent_runs: Vec<(SpriteId, Range<u32>)> = vec![];
+
+        for (id, instance) in scene.translucent_instances.iter() {
+            let index = instances[*id].len() as u32;
+            instances[*id].push(*instance);
+            match translucent_runs.last_mut() {
+                Some((last, run)) if last == id => run.end = index + 1,
+                _ => translucent_runs.push((*id, index..index + 1)),
+            }
+        }
+
+        #[cfg(feature = "sprite-debug")]
+        let instance_counts: Vec<u32> = instances.iter().map(|i| i.len() as u32).collect();
+
+        let mut overlay_runs: Vec<(SpriteId, Range<u32>)> = vec![];
+
+        for (id, instance) in scene.overlay_instances.iter() {
+            let index = instances[*id].len() as u32;
+            instances[*id].push(*instance);
+            match overlay_runs.last_mut() {
+                Some((last, run)) if last == id => run.end = index + 1,
+                _ => overlay_runs.push((*id, index..index + 1)),
+            }
+        }
+
+        for (sprite, instances) in self.sprites.iter_mut().zip(instances) {
+            sprite.update_instance_buffer(instances, device, queue);
+        }
+
+        Batches {
+            opaque_counts,
+            translucent_runs,
+            #[cfg(feature = "sprite-debug")]
+            instance_counts,
+            overlay_runs,
+        }
+    }
+
+    /// Records the passes for every view of `scene`, then the overlay, with the window's views
+    /// and the overlay drawn into `target`.
+    fn draw(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::TextureView,
+        target_depth: &DepthTexture,
+        scene: &Scene,
+        batches: &Batches,
+    ) {
+        // A target is cleared by the first view that draws into it, later views draw over it.
+        let mut cleared: Vec<CameraTarget> = vec![];
+
+        for (view, (_, bind_group)) in scene.views.iter().zip(self.view_uniforms.iter()) {
+            let (attachment, depth, pipelines) = match view.target {
+                CameraTarget::Window => (target, target_depth, &self.window_pipelines),
+                CameraTarget::Sprite(id) => (
+                    &self.sprites[id].frames[0].view,
+                    &self.sprite_depth_textures[&id],
+                    &self.sprite_pipelines,
+                ),
+            };
+            let load = if cleared.contains(&view.target) {
+                wgpu::LoadOp::Load
+            } else {
+                cleared.push(view.target);
+                wgpu::LoadOp::Clear(match view.target {
+                    CameraTarget::Window => CLEAR_COLOUR,
+                    CameraTarget::Sprite(_) => SPRITE_TARGET_CLEAR_COLOUR,
+                })
+            };
+
+            let mut rpass = begin_pass(encoder, attachment, load, depth);
 
-            rpass.set_pipeline(&self.sprite_pipeline);
+            let [x, y, width, height] = view.viewport;
+            rpass.set_viewport(x as f32, y as f32, width as f32, height as f32, 0.0, 1.0);
+            rpass.set_scissor_rect(x, y, width, height);
 
-            for (id, sprite) in self.sprites.iter().enumerate() {
-                let instance_count = scene
-                    .sprite_instances
-                    .iter()
-                    .filter(|(i, _)| *i == id)
-                    .count();
+            // A sprite's texture can not be sampled while it is drawn into, so a sprite's own
+            // instances are left out of the views that draw into it.
+            let drawn = |id: &SpriteId| view.target != CameraTarget::Sprite(*id);
 
-                rpass.draw_sprite(sprite, 0..instance_count as u32, &self.uniform_bind_group);
+            rpass.set_pipeline(&pipelines.sprite);
+
+            for (id, instance_count) in batches.opaque_counts.iter().enumerate() {
+                if drawn(&id) {
+                    rpass.draw_sprite(&self.sprites[id], 0..*instance_count, bind_group);
+                }
+            }
+
+            rpass.set_pipeline(&pipelines.translucent);
+
+            for (id, run) in batches.translucent_runs.iter().filter(|(id, _)| drawn(id)) {
+                rpass.draw_sprite(&self.sprites[*id], run.clone(), bind_group);
             }
 
-            rpass.set_pipeline(&self.hitbox_pipeline);
+            rpass.set_pipeline(&pipelines.hitbox);
 
             #[cfg(feature = "sprite-debug")]
-            for (id, sprite) in self.sprites.iter().enumerate() {
-                let instance_count = scene
-                    .sprite_instances
-                    .iter()
-                    .filter(|(i, _)| *i == id)
-                    .count();
-
-                rpass.draw_sprite(sprite, 0..instance_count as u32, &self.uniform_bind_group);
+            for (id, instance_count) in batches.instance_counts.iter().enumerate() {
+                if drawn(&id) {
+                    rpass.draw_sprite(&self.sprites[id], 0..*instance_count, bind_group);
+                }
             }
 
             rpass.draw_hitbox(
                 &self.hitbox,
                 0..scene.hitbox_instances.len() as u32,
-                &self.uniform_bind_group,
+                bind_group,
             );
         }
 
-        queue.submit(Some(encoder.finish()));
+        let load = if cleared.contains(&CameraTarget::Window) {
+            wgpu::LoadOp::Load
+        } else {
+            wgpu::LoadOp::Clear(CLEAR_COLOUR)
+        };
+        let mut rpass = begin_pass(encoder, target, load, target_depth);
+
+        rpass.set_pipeline(&self.window_pipelines.overlay);
+
+        for (id, run) in batches.overlay_runs.iter() {
+            rpass.draw_sprite(&self.sprites[*id], run.clone(), &self.overlay_uniform.1);
+        }
     }
 }
+
+/// How the instances written by `Renderer::prepare` are drawn.
+struct Batches {
+    opaque_counts: Vec<u32>,
+    translucent_runs: Vec<(SpriteId, Range<u32>)>,
+    /// Every instance of each sprite, outlined when debugging sprites.
+    #[cfg(feature = "sprite-debug")]
+    instance_counts: Vec<u32>,
+    overlay_runs: Vec<(SpriteId, Range<u32>)>,
+}
+
+fn create_uniform(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+) -> (wgpu::Buffer, wgpu::BindGroup) {
+    let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Uniform Buffer"),
+        contents: &[0u8; mem::size_of::<CameraUniform>()],
+        usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+    });
+
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: None,
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: buffer.as_entire_binding(),
+        }],
+    });
+
+    (buffer, bind_group)
+}
+
+/// Starts a pass drawing into `attachment` that clears the depth texture.
+fn begin_pass<'a>(
+    encoder: &'a mut wgpu::CommandEncoder,
+    attachment: &'a wgpu::TextureView,
+    load: wgpu::LoadOp<wgpu::Color>,
+    depth: &'a DepthTexture,
+) -> wgpu::RenderPass<'a> {
+    encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: None,
+        color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
+            attachment,
+            resolve_target: None,
+            ops: wgpu::Operations { load, store: true },
+        }],
+        depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachmentDescriptor {
+            attachment: &depth.view,
+            depth_ops: Some(wgpu::Operations {
+                load: wgpu::LoadOp::Clear(1.0),
+                store: true,
+            }),
+            stencil_ops: None,
+        }),
+    })
+}
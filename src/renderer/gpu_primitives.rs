@@ -1,4 +1,4 @@
-use glam::{Quat, Vec3};
+use glam::{Quat, Vec2, Vec3};
 
 pub type Index = u16;
 
@@ -38,11 +38,37 @@ pub struct CameraUniform {
     pub persp: [f32; 16],
 }
 
+/// Instance buffers start with room for this many instances.
+pub const INITIAL_INSTANCE_CAPACITY: u64 = 1024;
+
+/// How many instances a buffer with room for `capacity` must grow to so `needed` fit, doubling
+/// so that a slowly growing scene only reallocates a few times.
+pub fn instance_capacity(capacity: u64, needed: u64) -> u64 {
+    let mut capacity = capacity.max(1);
+    while capacity < needed {
+        capacity *= 2;
+    }
+    capacity
+}
+
+pub fn create_instance_buffer(device: &wgpu::Device, capacity: u64) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Instance Buffer"),
+        usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+        size: capacity * std::mem::size_of::<InstanceRaw>() as u64,
+        mapped_at_creation: false,
+    })
+}
+
 pub struct Instance {
     pub position: Vec3,
     pub rotation: Quat,
     pub scale: Vec3,
     pub frame_id: u8,
+    pub flip_x: bool,
+    pub flip_y: bool,
+    /// Offset in metres applied to the quad before it is transformed.
+    pub pivot_offset: Vec2,
 }
 
 #[repr(C)]
@@ -50,6 +76,8 @@ pub struct Instance {
 pub struct InstanceRaw {
     model: [[f32; 4]; 4],
     frame_id: u32,
+    flip: [u32; 2],
+    pivot_offset: [f32; 2],
 }
 
 impl From<Instance> for InstanceRaw {
@@ -60,11 +88,29 @@ impl From<Instance> for InstanceRaw {
                 * glam::Mat4::from_scale(from.scale))
             .to_cols_array_2d(),
             frame_id: from.frame_id as u32,
+            flip: [from.flip_x as u32, from.flip_y as u32],
+            pivot_offset: from.pivot_offset.into(),
         }
     }
 }
 
 impl InstanceRaw {
+    pub(crate) fn model(&self) -> glam::Mat4 {
+        glam::Mat4::from_cols_array_2d(&self.model)
+    }
+
+    pub(crate) fn pivot_offset(&self) -> Vec2 {
+        self.pivot_offset.into()
+    }
+
+    pub(crate) fn frame_id(&self) -> u32 {
+        self.frame_id
+    }
+
+    pub(crate) fn flip(&self) -> (bool, bool) {
+        (self.flip[0] != 0, self.flip[1] != 0)
+    }
+
     pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         use std::mem;
         wgpu::VertexBufferLayout {
@@ -96,7 +142,39 @@ impl InstanceRaw {
                     shader_location: 6,
                     format: wgpu::VertexFormat::Uint,
                 },
+                wgpu::VertexAttribute {
+                    offset: (mem::size_of::<[f32; 16]>() + mem::size_of::<u32>())
+                        as wgpu::BufferAddress,
+                    shader_location: 7,
+                    format: wgpu::VertexFormat::Uint2,
+                },
+                wgpu::VertexAttribute {
+                    offset: (mem::size_of::<[f32; 16]>() + mem::size_of::<[u32; 3]>())
+                        as wgpu::BufferAddress,
+                    shader_location: 8,
+                    format: wgpu::VertexFormat::Float2,
+                },
             ],
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capacity_grows_to_fit_more_instances_than_the_initial_buffer() {
+        // A 40 x 30 tilemap drawn from one tileset.
+        let capacity = instance_capacity(INITIAL_INSTANCE_CAPACITY, 40 * 30);
+        assert_eq!(capacity, 2048);
+        assert_eq!(instance_capacity(capacity, 100_000), 131_072);
+    }
+
+    #[test]
+    fn capacity_is_kept_when_instances_fit() {
+        assert_eq!(instance_capacity(INITIAL_INSTANCE_CAPACITY, 0), 1024);
+        assert_eq!(instance_capacity(INITIAL_INSTANCE_CAPACITY, 1024), 1024);
+        assert_eq!(instance_capacity(4096, 10), 4096);
+    }
+}
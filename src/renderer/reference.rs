@@ -0,0 +1,598 @@
+use std::collections::HashMap;
+
+use glam::{Vec2, Vec3, Vec4};
+use image::RgbaImage;
+
+use crate::app::WindowSize;
+use crate::asset::{SpriteId, SpriteRegistry};
+use crate::camera::{project_vertex, CameraTarget};
+use crate::renderer::gpu_primitives::{CameraUniform, InstanceRaw};
+use crate::renderer::scene::Scene;
+use crate::renderer::sprite::PIXELS_PER_METRE;
+use crate::renderer::{CLEAR_COLOUR, SPRITE_TARGET_CLEAR_COLOUR};
+use crate::Game;
+
+/// Draws games on the CPU the way `Renderer` draws them, for checking frames against golden
+/// images on machines without a GPU. Sprites are placed with the vertex shader's parallax
+/// projection, alpha tested and blended like the sprite, translucent and overlay pipelines, and
+/// cameras draw into sprites before the window, leaving out the sprite they draw into. Textures
+/// are always sampled with the nearest texel and hitboxes are not drawn.
+pub struct ReferenceRenderer {
+    /// Frames of every sprite, the first of which cameras may draw into.
+    sprites: Vec<Vec<RgbaImage>>,
+    size: WindowSize,
+}
+
+/// How a pipeline tests, blends and writes depth.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Pass {
+    Opaque,
+    Translucent,
+    Overlay,
+}
+
+/// A colour target in linear space with its depth buffer.
+struct Target {
+    width: u32,
+    height: u32,
+    colour: Vec<Vec4>,
+    depth: Vec<f32>,
+}
+
+impl ReferenceRenderer {
+    /// Draws frames of `width` x `height`, like `Headless::new`.
+    pub fn new(game: &mut Game, sprites: &SpriteRegistry, width: u32, height: u32) -> Self {
+        let size = WindowSize { width, height };
+        game.set_sprite_catalog(sprites.catalog());
+        game.world.insert_resource(size);
+
+        ReferenceRenderer {
+            sprites: sprites.iter().map(|data| data.frames.clone()).collect(),
+            size,
+        }
+    }
+
+    /// Draws the game as it is now, without advancing it.
+    pub fn capture(&mut self, game: &mut Game) -> RgbaImage {
+        let scene = game.build_scene();
+        self.render(&scene, self.size.width, self.size.height)
+    }
+
+    /// Draws `scene` into an image of `width` x `height`, as `Renderer::capture` would.
+    pub(crate) fn render(&mut self, scene: &Scene, width: u32, height: u32) -> RgbaImage {
+        // Each target is cleared before the first view draws into it, like the GPU's load ops.
+        let mut window = Target::new(width, height, colour(CLEAR_COLOUR));
+        let mut sprite_targets: HashMap<SpriteId, Target> = HashMap::new();
+
+        // Opaque instances are drawn a sprite at a time, as they are from the instance buffers.
+        let mut opaque: Vec<&(SpriteId, InstanceRaw)> = scene.sprite_instances.iter().collect();
+        opaque.sort_by_key(|(id, _)| *id);
+
+        for view in scene.views.iter() {
+            let target = match view.target {
+                CameraTarget::Window => &mut window,
+                CameraTarget::Sprite(id) => {
+                    let (width, height) = self.sprites[id][0].dimensions();
+                    sprite_targets.entry(id).or_insert_with(|| {
+                        Target::new(width, height, colour(SPRITE_TARGET_CLEAR_COLOUR))
+                    })
+                }
+            };
+            target.clear_depth();
+
+            let uniform = &view.camera_uniform;
+            let drawn =
+                |(id, _): &&(SpriteId, InstanceRaw)| view.target != CameraTarget::Sprite(*id);
+            for (id, instance) in opaque.iter().copied().filter(drawn) {
+                self.draw(target, view.viewport, uniform, *id, instance, Pass::Opaque);
+            }
+            for (id, instance) in scene.translucent_instances.iter().filter(drawn) {
+                self.draw(
+                    target,
+                    view.viewport,
+                    uniform,
+                    *id,
+                    instance,
+                    Pass::Translucent,
+                );
+            }
+
+            // Later views sample what was drawn, as they would the sprite's texture.
+            if let CameraTarget::Sprite(id) = view.target {
+                self.sprites[id][0] = target.to_image(false);
+            }
+        }
+
+        window.clear_depth();
+        let viewport = [0, 0, width, height];
+        for (id, instance) in scene.overlay_instances.iter() {
+            let uniform = &scene.overlay_camera_uniform;
+            self.draw(&mut window, viewport, uniform, *id, instance, Pass::Overlay);
+        }
+
+        window.to_image(true)
+    }
+
+    /// Rasterises one instance of sprite `id` into `target`. The vertex shader's projection is
+    /// affine for each instance, so the quad is a parallelogram on screen and every pixel centre
+    /// inside it is mapped back onto the quad to find its texture coordinates and depth.
+    fn draw(
+        &self,
+        target: &mut Target,
+        viewport: [u32; 4],
+        uniform: &CameraUniform,
+        id: SpriteId,
+        instance: &InstanceRaw,
+        pass: Pass,
+    ) {
+        let frames = match self.sprites.get(id) {
+            Some(frames) if !frames.is_empty() => frames,
+            _ => return,
+        };
+        let (width, height) = frames[0].dimensions();
+        let half = Vec2::new(width as f32, height as f32) / PIXELS_PER_METRE as f32 / 2.0;
+        let pivot = instance.pivot_offset();
+        let model = instance.model();
+
+        let [x, y, viewport_width, viewport_height] = viewport;
+        let to_screen = |corner: Vec2| {
+            let ndc = project_vertex(uniform, &model, (corner + pivot).extend(0.0));
+            Vec3::new(
+                x as f32 + (ndc.x + 1.0) / 2.0 * viewport_width as f32,
+                y as f32 + (1.0 - ndc.y) / 2.0 * viewport_height as f32,
+                ndc.z,
+            )
+        };
+        // The corners with texture coordinates (0, 1), (1, 1) and (0, 0).
+        let origin = to_screen(Vec2::new(-half.x, -half.y));
+        let across = to_screen(Vec2::new(half.x, -half.y)) - origin;
+        let up = to_screen(Vec2::new(-half.x, half.y)) - origin;
+
+        let det = across.x * up.y - across.y * up.x;
+        if !det.is_finite() || det.abs() < f32::EPSILON {
+            return;
+        }
+
+        let corners = [origin, origin + across, origin + up, origin + across + up];
+        let min = corners
+            .iter()
+            .fold(Vec3::splat(f32::INFINITY), |a, b| a.min(*b));
+        let max = corners
+            .iter()
+            .fold(Vec3::splat(f32::NEG_INFINITY), |a, b| a.max(*b));
+        // Everything is clipped to the scissor rectangle, which is the viewport.
+        let left = (min.x.floor().max(x as f32) as u32).min(target.width);
+        let top = (min.y.floor().max(y as f32) as u32).min(target.height);
+        let right = (max.x.ceil() as u32)
+            .min(x + viewport_width)
+            .min(target.width);
+        let bottom = (max.y.ceil() as u32)
+            .min(y + viewport_height)
+            .min(target.height);
+
+        let frame = frames
+            .get(instance.frame_id() as usize)
+            .unwrap_or(&frames[0]);
+        let (flip_x, flip_y) = instance.flip();
+
+        for py in top..bottom {
+            for px in left..right {
+                let offset = Vec2::new(px as f32 + 0.5 - origin.x, py as f32 + 0.5 - origin.y);
+                let s = (offset.x * up.y - offset.y * up.x) / det;
+                let t = (across.x * offset.y - across.y * offset.x) / det;
+                if !(0.0..1.0).contains(&s) || !(0.0..1.0).contains(&t) {
+                    continue;
+                }
+
+                let depth = origin.z + s * across.z + t * up.z;
+                if !(0.0..=1.0).contains(&depth) {
+                    continue;
+                }
+                let index = (py * target.width + px) as usize;
+                if pass != Pass::Overlay && depth >= target.depth[index] {
+                    continue;
+                }
+
+                let u = if flip_x { 1.0 - s } else { s };
+                let v = if flip_y { t } else { 1.0 - t };
+                let texel = sample(frame, Vec2::new(u, v));
+
+                let discard = match pass {
+                    Pass::Opaque => texel.w < 0.5,
+                    Pass::Translucent | Pass::Overlay => texel.w == 0.0,
+                };
+                if discard {
+                    continue;
+                }
+
+                let dst = target.colour[index];
+                let rgb = texel.truncate() * texel.w + dst.truncate() * (1.0 - texel.w);
+                let alpha = match pass {
+                    Pass::Opaque => texel.w.min(dst.w),
+                    Pass::Translucent | Pass::Overlay => texel.w + dst.w * (1.0 - texel.w),
+                };
+                target.colour[index] = rgb.extend(alpha);
+
+                if pass == Pass::Opaque {
+                    target.depth[index] = depth;
+                }
+            }
+        }
+    }
+}
+
+impl Target {
+    fn new(width: u32, height: u32, clear: Vec4) -> Self {
+        let pixels = (width * height) as usize;
+        Target {
+            width,
+            height,
+            colour: vec![clear; pixels],
+            depth: vec![1.0; pixels],
+        }
+    }
+
+    fn clear_depth(&mut self) {
+        self.depth.iter_mut().for_each(|depth| *depth = 1.0);
+    }
+
+    /// Encodes the target the way an sRGB texture stores it. Captures of the window are opaque.
+    fn to_image(&self, opaque: bool) -> RgbaImage {
+        let mut image = RgbaImage::new(self.width, self.height);
+        for (pixel, colour) in image.pixels_mut().zip(self.colour.iter()) {
+            let alpha = if opaque { 1.0 } else { colour.w };
+            pixel.0 = [
+                to_u8(linear_to_srgb(colour.x)),
+                to_u8(linear_to_srgb(colour.y)),
+                to_u8(linear_to_srgb(colour.z)),
+                to_u8(alpha),
+            ];
+        }
+        image
+    }
+}
+
+/// The texel at `uv` with the nearest filter and clamped edges, in linear space.
+fn sample(frame: &RgbaImage, uv: Vec2) -> Vec4 {
+    let (width, height) = frame.dimensions();
+    let x = ((uv.x * width as f32) as u32).min(width - 1);
+    let y = ((uv.y * height as f32) as u32).min(height - 1);
+    let [r, g, b, a] = frame.get_pixel(x, y).0;
+    Vec4::new(
+        srgb_to_linear(r as f32 / 255.0),
+        srgb_to_linear(g as f32 / 255.0),
+        srgb_to_linear(b as f32 / 255.0),
+        a as f32 / 255.0,
+    )
+}
+
+fn colour(colour: wgpu::Color) -> Vec4 {
+    Vec4::new(
+        colour.r as f32,
+        colour.g as f32,
+        colour.b as f32,
+        colour.a as f32,
+    )
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn to_u8(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// How many pixels differ between two images by more than `tolerance` in any channel, for
+/// comparing captures against golden images. Images of different sizes differ everywhere.
+pub fn differing_pixels(actual: &RgbaImage, expected: &RgbaImage, tolerance: u8) -> usize {
+    if actual.dimensions() != expected.dimensions() {
+        return (actual.width() * actual.height()).max(expected.width() * expected.height())
+            as usize;
+    }
+    actual
+        .pixels()
+        .zip(expected.pixels())
+        .filter(|(a, b)| {
+            a.0.iter()
+                .zip(b.0.iter())
+                .any(|(a, b)| (*a as i16 - *b as i16).unsigned_abs() > tolerance as u16)
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use glam::Quat;
+    use image::Rgba;
+
+    use super::*;
+    use crate::camera::{screen_space_uniform, Camera, ParallaxCamera};
+    use crate::renderer::gpu_primitives::Instance;
+    use crate::renderer::scene::SceneView;
+
+    const WIDTH: u32 = 96;
+    const HEIGHT: u32 = 64;
+    const BLOCK: SpriteId = 0;
+    const GLASS: SpriteId = 1;
+    const PANEL: SpriteId = 2;
+    const MONITOR: SpriteId = 3;
+    const MONITOR_SIZE: Vec2 = glam::const_vec2!([32.0, 24.0]);
+
+    /// Goldens are compared with a little slack for floating point differences between
+    /// platforms. Run with `UPDATE_GOLDEN=1` to write them again after an intended change.
+    const TOLERANCE: u8 = 2;
+
+    fn renderer() -> ReferenceRenderer {
+        // Quadrants so flips show, one of them too faint to pass the alpha test.
+        let block = RgbaImage::from_fn(16, 16, |x, y| match (x < 8, y < 8) {
+            (true, true) => Rgba([220, 40, 40, 255]),
+            (false, true) => Rgba([40, 200, 60, 255]),
+            (true, false) => Rgba([50, 70, 230, 255]),
+            (false, false) => Rgba([255, 255, 255, 64]),
+        });
+        let checks = RgbaImage::from_fn(16, 16, |x, y| {
+            let alpha = if (x / 4 + y / 4) % 2 == 0 { 255 } else { 0 };
+            Rgba([240, 220, 30, alpha])
+        });
+        let glass = RgbaImage::from_pixel(24, 24, Rgba([60, 220, 240, 128]));
+        let panel = RgbaImage::from_fn(32, 16, |x, y| {
+            if x == 0 || y == 0 || x == 31 || y == 15 {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([30, 30, 40, 200])
+            }
+        });
+
+        // Stripes that would show if the monitor drew itself into itself.
+        let monitor = RgbaImage::from_fn(32, 24, |x, _| {
+            if x % 4 < 2 {
+                Rgba([255, 0, 255, 255])
+            } else {
+                Rgba([0, 255, 0, 255])
+            }
+        });
+
+        ReferenceRenderer {
+            sprites: vec![vec![block, checks], vec![glass], vec![panel], vec![monitor]],
+            size: WindowSize {
+                width: WIDTH,
+                height: HEIGHT,
+            },
+        }
+    }
+
+    fn camera() -> ParallaxCamera {
+        ParallaxCamera::new(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            1.0,
+            0.1,
+            100.0,
+        )
+    }
+
+    fn size() -> Vec2 {
+        Vec2::new(WIDTH as f32, HEIGHT as f32)
+    }
+
+    /// An unrotated, unscaled instance drawn where `ndc` is on screen at depth `z`.
+    fn at(camera: &ParallaxCamera, ndc: Vec2, z: f32) -> Instance {
+        Instance {
+            position: camera.unproject(ndc, size(), z),
+            rotation: Quat::identity(),
+            scale: Vec3::one(),
+            frame_id: 0,
+            flip_x: false,
+            flip_y: false,
+            pivot_offset: Vec2::zero(),
+        }
+    }
+
+    fn scene(
+        camera: &ParallaxCamera,
+        opaque: Vec<(SpriteId, Instance)>,
+        translucent: Vec<(SpriteId, Instance)>,
+        overlay: Vec<(SpriteId, Instance)>,
+    ) -> Scene {
+        let raw = |instances: Vec<(SpriteId, Instance)>| {
+            instances
+                .into_iter()
+                .map(|(id, instance)| (id, InstanceRaw::from(instance)))
+                .collect()
+        };
+        Scene {
+            sprite_instances: raw(opaque),
+            translucent_instances: raw(translucent),
+            views: vec![SceneView {
+                camera_uniform: camera.generate_matrix(size()),
+                target: CameraTarget::Window,
+                viewport: [0, 0, WIDTH, HEIGHT],
+            }],
+            hitbox_instances: Vec::new(),
+            overlay_instances: raw(overlay),
+            overlay_camera_uniform: screen_space_uniform(&WindowSize {
+                width: WIDTH,
+                height: HEIGHT,
+            }),
+        }
+    }
+
+    fn assert_matches_golden(name: &str, scene: &Scene) {
+        let actual = renderer().render(scene, WIDTH, HEIGHT);
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/golden")
+            .join(format!("{}.png", name));
+
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            actual.save(&path).unwrap();
+        }
+
+        let expected = image::open(&path)
+            .unwrap_or_else(|err| {
+                panic!(
+                    "Failed to read {}, run with UPDATE_GOLDEN=1 to write it: {}",
+                    path.display(),
+                    err
+                )
+            })
+            .into_rgba8();
+        let differing = differing_pixels(&actual, &expected, TOLERANCE);
+        assert_eq!(
+            differing,
+            0,
+            "{} pixels differ from {}",
+            differing,
+            path.display()
+        );
+    }
+
+    #[test]
+    fn opaque_sprites_are_alpha_tested_and_depth_sorted() {
+        let camera = camera();
+        let checks = Instance {
+            frame_id: 1,
+            ..at(&camera, Vec2::new(-0.15, 0.1), 10.0)
+        };
+        let opaque = vec![
+            (BLOCK, at(&camera, Vec2::new(-0.5, 0.0), 10.0)),
+            // Further away but drawn first, so the depth test has to hide it.
+            (BLOCK, at(&camera, Vec2::new(-0.3, 0.2), 30.0)),
+            (BLOCK, checks),
+            (BLOCK, at(&camera, Vec2::new(0.5, -0.3), 60.0)),
+        ];
+
+        assert_matches_golden("alpha_test", &scene(&camera, opaque, vec![], vec![]));
+    }
+
+    #[test]
+    fn translucent_sprites_blend_over_opaque_ones() {
+        let camera = camera();
+        let opaque = vec![
+            (BLOCK, at(&camera, Vec2::new(-0.3, 0.0), 20.0)),
+            (BLOCK, at(&camera, Vec2::new(0.3, 0.0), 20.0)),
+        ];
+        // Back to front: the first is hidden behind a block, the others overlap.
+        let translucent = vec![
+            (GLASS, at(&camera, Vec2::new(0.35, 0.1), 40.0)),
+            (GLASS, at(&camera, Vec2::new(-0.2, 0.1), 10.0)),
+            (GLASS, at(&camera, Vec2::new(0.0, -0.2), 5.0)),
+        ];
+
+        assert_matches_golden("translucent", &scene(&camera, opaque, translucent, vec![]));
+    }
+
+    #[test]
+    fn overlays_are_drawn_over_the_world_in_screen_space() {
+        let camera = camera();
+        let opaque = vec![(BLOCK, at(&camera, Vec2::new(-0.6, 0.6), 5.0))];
+        let translucent = vec![(GLASS, at(&camera, Vec2::new(-0.5, 0.4), 2.0))];
+        // Overlay quads are measured in pixels with y up from the top left of the window.
+        let panel = |x: f32, y: f32| Instance {
+            position: Vec3::new(x, -y, 0.0),
+            rotation: Quat::identity(),
+            scale: Vec3::splat(PIXELS_PER_METRE as f32),
+            frame_id: 0,
+            flip_x: false,
+            flip_y: false,
+            pivot_offset: Vec2::zero(),
+        };
+        let overlay = vec![(PANEL, panel(24.0, 16.0)), (PANEL, panel(36.0, 22.0))];
+
+        assert_matches_golden("overlay", &scene(&camera, opaque, translucent, overlay));
+    }
+
+    #[test]
+    fn nearer_sprites_move_further_with_the_camera() {
+        let mut camera = camera();
+        let mut opaque: Vec<(SpriteId, Instance)> = [4.0, 8.0, 20.0, 80.0]
+            .iter()
+            .enumerate()
+            .map(|(i, z)| (BLOCK, at(&camera, Vec2::new(0.0, 0.6 - 0.4 * i as f32), *z)))
+            .collect();
+        opaque.push((BLOCK, at(&camera, Vec2::new(-0.6, -0.6), 2.0)));
+        camera.offset = Vec3::new(-0.5, 0.25, 0.0);
+        camera.zoom = 1.25;
+
+        assert_matches_golden("parallax", &scene(&camera, opaque, vec![], vec![]));
+    }
+
+    #[test]
+    fn sprites_are_flipped_about_their_pivots() {
+        let camera = camera();
+        let flipped = |ndc: Vec2, flip_x: bool, flip_y: bool| Instance {
+            flip_x,
+            flip_y,
+            ..at(&camera, ndc, 10.0)
+        };
+        let opaque = vec![
+            (BLOCK, flipped(Vec2::new(-0.6, 0.4), false, false)),
+            (BLOCK, flipped(Vec2::new(-0.2, 0.4), true, false)),
+            (BLOCK, flipped(Vec2::new(0.2, 0.4), false, true)),
+            (BLOCK, flipped(Vec2::new(0.6, 0.4), true, true)),
+            (
+                BLOCK,
+                Instance {
+                    rotation: Quat::from_rotation_z(std::f32::consts::FRAC_PI_4),
+                    pivot_offset: Vec2::new(0.25, 0.25),
+                    ..flipped(Vec2::new(-0.4, -0.4), true, false)
+                },
+            ),
+            (
+                BLOCK,
+                Instance {
+                    scale: Vec3::new(2.0, 1.0, 1.0),
+                    pivot_offset: Vec2::new(0.0, -0.25),
+                    ..flipped(Vec2::new(0.4, -0.4), false, true)
+                },
+            ),
+        ];
+
+        assert_matches_golden("flip_and_pivot", &scene(&camera, opaque, vec![], vec![]));
+    }
+
+    #[test]
+    fn cameras_draw_into_sprites_without_the_sprite_itself() {
+        let camera = camera();
+        let monitor_view = |ndc: Vec2, z: f32| Instance {
+            position: camera.unproject(ndc, MONITOR_SIZE, z),
+            ..at(&camera, ndc, z)
+        };
+        // The monitor is in front of the block in both views, so it hides the block in the
+        // monitor's view unless it is left out.
+        let opaque = vec![
+            (BLOCK, monitor_view(Vec2::zero(), 10.0)),
+            (MONITOR, monitor_view(Vec2::zero(), 8.0)),
+            (BLOCK, at(&camera, Vec2::new(0.6, -0.4), 20.0)),
+            // Another monitor, out of the monitor's view, to show what it drew.
+            (MONITOR, at(&camera, Vec2::new(-0.55, 0.45), 8.0)),
+        ];
+        let translucent = vec![(GLASS, monitor_view(Vec2::new(0.5, 0.5), 6.0))];
+        let mut scene = scene(&camera, opaque, translucent, vec![]);
+        scene.views.insert(
+            0,
+            SceneView {
+                camera_uniform: camera.generate_matrix(MONITOR_SIZE),
+                target: CameraTarget::Sprite(MONITOR),
+                viewport: [0, 0, 32, 24],
+            },
+        );
+
+        assert_matches_golden("sprite_target", &scene);
+    }
+}
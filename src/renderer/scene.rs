@@ -1,9 +1,58 @@
 use crate::asset::SpriteId;
+use crate::camera::{project_vertex, CameraTarget};
 use crate::renderer::gpu_primitives::{CameraUniform, InstanceRaw};
+use glam::{Vec2, Vec3};
 
 #[derive(Clone)]
 pub struct Scene {
     pub sprite_instances: Vec<(SpriteId, InstanceRaw)>,
-    pub camera_uniform: CameraUniform,
+    /// Sorted back to front.
+    pub translucent_instances: Vec<(SpriteId, InstanceRaw)>,
+    /// Every view draws all of the instances, in order.
+    pub views: Vec<SceneView>,
     pub hitbox_instances: Vec<InstanceRaw>,
+    /// Drawn over the world in order, with `overlay_camera_uniform`.
+    pub overlay_instances: Vec<(SpriteId, InstanceRaw)>,
+    pub overlay_camera_uniform: CameraUniform,
+}
+
+/// What one active camera sees and where it draws it.
+#[derive(Clone, Copy)]
+pub struct SceneView {
+    pub camera_uniform: CameraUniform,
+    pub target: CameraTarget,
+    /// x, y, width and height in pixels from the top left of the target.
+    pub viewport: [u32; 4],
+}
+
+impl SceneView {
+    /// Whether any of a quad of `size` metres drawn with `instance` lands in the view. The quad's
+    /// corners are projected like the vertex shader projects them and their bounds are tested
+    /// against the clip volume, so a quad that may be on screen is never reported hidden.
+    pub(crate) fn shows(&self, instance: &InstanceRaw, size: Vec2) -> bool {
+        let model = instance.model();
+        let half = size / 2.0;
+        let pivot = instance.pivot_offset();
+        let corners = [
+            Vec2::new(-half.x, -half.y),
+            Vec2::new(half.x, -half.y),
+            Vec2::new(half.x, half.y),
+            Vec2::new(-half.x, half.y),
+        ];
+
+        let mut min = Vec3::splat(f32::INFINITY);
+        let mut max = Vec3::splat(f32::NEG_INFINITY);
+        for corner in corners.iter() {
+            let ndc = project_vertex(&self.camera_uniform, &model, (*corner + pivot).extend(0.0));
+            min = min.min(ndc);
+            max = max.max(ndc);
+        }
+
+        max.x >= -1.0
+            && min.x <= 1.0
+            && max.y >= -1.0
+            && min.y <= 1.0
+            && max.z >= 0.0
+            && min.z <= 1.0
+    }
 }
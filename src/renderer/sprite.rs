@@ -3,18 +3,27 @@ use std::{convert::TryInto, ops::Range};
 use image::RgbaImage;
 use wgpu::{util::DeviceExt, TextureView};
 
-use crate::renderer::gpu_primitives::{Index, InstanceRaw, Vertex};
+use crate::renderer::gpu_primitives::{
+    create_instance_buffer, instance_capacity, Index, InstanceRaw, Vertex,
+    INITIAL_INSTANCE_CAPACITY,
+};
 use crate::renderer::texture::ArrayTexture;
 use crate::renderer::TEXTURE_ARRAY_SIZE;
 
-pub const MAX_INSTANCES: u64 = 1024;
 pub const PIXELS_PER_METRE: u32 = 32;
 
 pub struct Sprite {
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
     pub instance_buffer: wgpu::Buffer,
+    /// How many instances `instance_buffer` has room for.
+    instance_capacity: u64,
     pub bind_group: wgpu::BindGroup,
+    /// Kept so cameras can draw into the first frame.
+    pub frames: Vec<ArrayTexture>,
+    /// Size of a frame in pixels.
+    pub width: u32,
+    pub height: u32,
     num_indices: u32,
 }
 
@@ -69,13 +78,7 @@ impl Sprite {
             usage: wgpu::BufferUsage::INDEX,
         });
 
-        let instance_buf_size = MAX_INSTANCES * std::mem::size_of::<InstanceRaw>() as u64;
-        let instance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Instance Buffer"),
-            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
-            size: instance_buf_size,
-            mapped_at_creation: false,
-        });
+        let instance_buffer = create_instance_buffer(device, INITIAL_INSTANCE_CAPACITY);
 
         // Create bind group
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
@@ -97,12 +100,27 @@ impl Sprite {
             vertex_buffer,
             index_buffer,
             instance_buffer,
+            instance_capacity: INITIAL_INSTANCE_CAPACITY,
             bind_group,
+            frames: textures,
+            width: tex_width,
+            height: tex_height,
             num_indices: index_data.len() as u32,
         }
     }
 
-    pub fn update_instance_buffer(&mut self, instances: Vec<InstanceRaw>, queue: &wgpu::Queue) {
+    /// Writes `instances` to the instance buffer, replacing it with a larger one if they don't fit.
+    pub fn update_instance_buffer(
+        &mut self,
+        instances: Vec<InstanceRaw>,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) {
+        let capacity = instance_capacity(self.instance_capacity, instances.len() as u64);
+        if capacity != self.instance_capacity {
+            self.instance_buffer = create_instance_buffer(device, capacity);
+            self.instance_capacity = capacity;
+        }
         queue.write_buffer(
             &self.instance_buffer,
             0,
@@ -0,0 +1,40 @@
+use crate::gamepad::GamepadState;
+use crate::input::KeyState;
+use crate::pointer::{Mouse, Touches};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::time::Duration;
+
+/// The input seen by one tick of the game and how long that tick took.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedTick {
+    pub elapsed: Duration,
+    pub keys: KeyState,
+    #[serde(default)]
+    pub gamepads: GamepadState,
+    #[serde(default)]
+    pub mouse: Mouse,
+    #[serde(default)]
+    pub touches: Touches,
+}
+
+/// Per tick input of a play session. Replaying it into a game set up the same way reproduces
+/// the session exactly, because the clock is driven by the recorded tick lengths.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct InputRecording {
+    pub ticks: Vec<RecordedTick>,
+}
+
+impl InputRecording {
+    pub fn read(file: impl AsRef<Path>) -> std::io::Result<Self> {
+        let reader = BufReader::new(File::open(file)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    pub fn write(&self, file: impl AsRef<Path>) -> std::io::Result<()> {
+        let writer = BufWriter::new(File::create(file)?);
+        Ok(serde_json::to_writer(writer, self)?)
+    }
+}
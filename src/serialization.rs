@@ -0,0 +1,1185 @@
+//! Saving and loading entities to scene files, and the whole game state to snapshots.
+//!
+//! Only components and resources registered with a [`ComponentRegistry`] are written, under the
+//! name they were registered with. Files are RON if they end in `.ron` and JSON otherwise.
+//!
+//! Restoring a snapshot replaces every entity in the world, so any component that is not
+//! registered is lost. Components that refer to other entities, like `Parent` and `Children`,
+//! store them as their index in the file.
+
+use crate::asset::{SpriteCatalog, SpriteId};
+use crate::camera::{
+    ActiveCamera, CameraFollow, CameraPath, CameraShake, CameraTarget, CameraZoom, ParallaxCamera,
+    Viewport,
+};
+use crate::input::{Action, ActionState, InputDevices};
+use crate::player::{PlayerInput, PlayerState};
+use crate::sprite::{AnimTimeline, Sprite};
+use crate::text::{ScreenText, Text, TextAlign};
+use crate::tilemap::{Tile, Tilemap};
+use crate::time::Timer;
+use crate::transform::{Children, GlobalTransform, Parent};
+use crate::ui::{NineSlice, UiImage, UiRect};
+use crate::{Collider, MoveSpeed, Position, Rotation, Scale, Terrain, Velocity};
+use bevy_ecs::component::Component;
+use bevy_ecs::entity::{Entity, EntityMap, MapEntities, MapEntitiesError};
+use bevy_ecs::prelude::World;
+use glam::Vec2;
+use parry2d::na::Vector2;
+use parry2d::shape::Cuboid;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{read_to_string, write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Upgrades a snapshot written with format version `from` to the next version, by editing the
+/// file contents before they are restored. The returned snapshot must have its `version` field
+/// increased.
+pub type Migration = fn(from: u32, snapshot: Value) -> Result<Value, SceneError>;
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Ron(ron::Error),
+    UnknownComponent(String),
+    UnknownResource(String),
+    UnknownSprite(String),
+    /// A component refers to an entity that is not in the scene.
+    MissingEntity(Entity),
+    MissingSpriteCatalog,
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::Io(e) => write!(f, "could not access scene file: {}", e),
+            SceneError::Json(e) => write!(f, "invalid json scene: {}", e),
+            SceneError::Ron(e) => write!(f, "invalid ron scene: {}", e),
+            SceneError::UnknownComponent(name) => write!(f, "unregistered component {}", name),
+            SceneError::UnknownResource(name) => write!(f, "unregistered resource {}", name),
+            SceneError::MissingEntity(entity) => {
+                write!(
+                    f,
+                    "entity {} is referred to but not in the scene",
+                    entity.id()
+                )
+            }
+            SceneError::UnknownSprite(name) => write!(f, "unknown sprite {}", name),
+            SceneError::MissingSpriteCatalog => {
+                write!(
+                    f,
+                    "sprites can not be saved or loaded before the sprite catalog is set"
+                )
+            }
+            SceneError::UnsupportedVersion(version) => {
+                write!(f, "snapshot format version {} can not be restored", version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+impl From<std::io::Error> for SceneError {
+    fn from(e: std::io::Error) -> Self {
+        SceneError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for SceneError {
+    fn from(e: serde_json::Error) -> Self {
+        SceneError::Json(e)
+    }
+}
+
+impl From<ron::Error> for SceneError {
+    fn from(e: ron::Error) -> Self {
+        SceneError::Ron(e)
+    }
+}
+
+impl From<MapEntitiesError> for SceneError {
+    fn from(e: MapEntitiesError) -> Self {
+        match e {
+            MapEntitiesError::EntityNotFound(entity) => SceneError::MissingEntity(entity),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SceneFile {
+    pub entities: Vec<SceneEntity>,
+}
+
+/// Components of one entity keyed by their registered name.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SceneEntity(pub BTreeMap<String, Value>);
+
+impl SceneFile {
+    pub fn read(file: impl AsRef<Path>) -> Result<Self, SceneError> {
+        read_file(file.as_ref())
+    }
+
+    pub fn write(&self, file: impl AsRef<Path>) -> Result<(), SceneError> {
+        write_file(file.as_ref(), self)
+    }
+}
+
+/// The runtime state of a game, see [`ComponentRegistry::snapshot`].
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Snapshot {
+    pub version: u32,
+    pub resources: BTreeMap<String, Value>,
+    pub entities: Vec<SceneEntity>,
+}
+
+impl Snapshot {
+    /// Reads a snapshot, running `migrate` until it reaches `version`.
+    pub fn read(
+        file: impl AsRef<Path>,
+        version: u32,
+        migrate: Option<Migration>,
+    ) -> Result<Self, SceneError> {
+        let mut snapshot: Value = read_file(file.as_ref())?;
+
+        loop {
+            let from = snapshot.get("version").and_then(Value::as_u64).unwrap_or(0) as u32;
+            if from == version {
+                break;
+            }
+            snapshot = match migrate {
+                Some(migrate) if from < version => migrate(from, snapshot)?,
+                _ => return Err(SceneError::UnsupportedVersion(from)),
+            };
+            let to = snapshot.get("version").and_then(Value::as_u64);
+            if to.is_none_or(|to| to as u32 <= from) {
+                return Err(SceneError::UnsupportedVersion(from));
+            }
+        }
+
+        Ok(serde_json::from_value(snapshot)?)
+    }
+
+    /// Snapshots are written as plain maps rather than RON structs so they can be read back
+    /// untyped for migration.
+    pub fn write(&self, file: impl AsRef<Path>) -> Result<(), SceneError> {
+        write_file(file.as_ref(), &serde_json::to_value(self)?)
+    }
+}
+
+fn read_file<T: DeserializeOwned>(path: &Path) -> Result<T, SceneError> {
+    let src = read_to_string(path)?;
+    if is_ron(path) {
+        Ok(ron::from_str(&src)?)
+    } else {
+        Ok(serde_json::from_str(&src)?)
+    }
+}
+
+fn write_file<T: Serialize>(path: &Path, contents: &T) -> Result<(), SceneError> {
+    let src = if is_ron(path) {
+        ron::ser::to_string_pretty(contents, ron::ser::PrettyConfig::default())?
+    } else {
+        serde_json::to_string_pretty(contents)?
+    };
+    write(path, src)?;
+    Ok(())
+}
+
+fn is_ron(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some("ron")
+}
+
+/// A decoded component waiting to be added to an entity. The map takes the entities it refers
+/// to from their index in the file to the spawned entities.
+type Insert = Box<dyn FnOnce(&mut World, Entity, &EntityMap)>;
+type InsertResource = Box<dyn FnOnce(&mut World)>;
+
+type HasFn = Box<dyn Fn(&World, Entity) -> bool + Send + Sync>;
+/// The map takes entities to their index in the file.
+type SaveFn =
+    Box<dyn Fn(&World, Entity, &EntityMap) -> Option<Result<Value, SceneError>> + Send + Sync>;
+/// Decodes a component without changing the world. The map holds every index in the file.
+type LoadFn = Box<dyn Fn(&World, Value, &EntityMap) -> Result<Insert, SceneError> + Send + Sync>;
+
+type SaveResourceFn = Box<dyn Fn(&World) -> Option<Result<Value, SceneError>> + Send + Sync>;
+type LoadResourceFn =
+    Box<dyn Fn(&World, Value) -> Result<InsertResource, SceneError> + Send + Sync>;
+type RemoveResourceFn = Box<dyn Fn(&mut World) + Send + Sync>;
+
+struct Registration {
+    name: String,
+    has: HasFn,
+    save: SaveFn,
+    load: LoadFn,
+}
+
+struct ResourceRegistration {
+    name: String,
+    save: SaveResourceFn,
+    load: LoadResourceFn,
+    remove: RemoveResourceFn,
+}
+
+/// The components and resources that can be written to and read from scene files and
+/// snapshots.
+#[derive(Default)]
+pub struct ComponentRegistry {
+    components: Vec<Registration>,
+    resources: Vec<ResourceRegistration>,
+}
+
+impl ComponentRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the components and resources of this crate.
+    pub fn with_builtin() -> Self {
+        let mut registry = Self::new();
+        registry.register::<Position>("Position");
+        registry.register::<Rotation>("Rotation");
+        registry.register::<Scale>("Scale");
+        registry.register::<Velocity>("Velocity");
+        registry.register_mapped::<Parent>("Parent");
+        registry.register_mapped::<Children>("Children");
+        registry.register::<GlobalTransform>("GlobalTransform");
+        registry.register::<MoveSpeed>("MoveSpeed");
+        registry.register::<Terrain>("Terrain");
+        registry.register::<PlayerInput>("PlayerInput");
+        registry.register_with::<ActionState, ActionStateDef>(
+            "ActionState",
+            ActionStateDef::save,
+            ActionStateDef::load,
+        );
+        registry.register::<InputDevices>("InputDevices");
+        registry.register::<AnimTimeline>("AnimTimeline");
+        registry.register::<ParallaxCamera>("ParallaxCamera");
+        registry.register::<ActiveCamera>("ActiveCamera");
+        registry.register_mapped::<CameraFollow>("CameraFollow");
+        registry.register::<CameraShake>("CameraShake");
+        registry.register::<CameraZoom>("CameraZoom");
+        registry.register::<CameraPath>("CameraPath");
+        registry.register_with::<Viewport, ViewportDef>(
+            "Viewport",
+            ViewportDef::save,
+            ViewportDef::load,
+        );
+        registry.register_with::<Collider, ColliderDef>(
+            "Collider",
+            |collider, _| {
+                Ok(ColliderDef {
+                    half_extents: [collider.0.half_extents.x, collider.0.half_extents.y],
+                })
+            },
+            |def, _| {
+                Ok(Collider(Cuboid::new(Vector2::new(
+                    def.half_extents[0],
+                    def.half_extents[1],
+                ))))
+            },
+        );
+        registry.register_with::<Sprite, SpriteDef>("Sprite", SpriteDef::save, SpriteDef::load);
+        registry.register_with::<Tilemap, TilemapDef>(
+            "Tilemap",
+            TilemapDef::save,
+            TilemapDef::load,
+        );
+        registry.register_with::<Text, TextDef>("Text", TextDef::save, TextDef::load);
+        registry.register_with::<ScreenText, ScreenTextDef>(
+            "ScreenText",
+            ScreenTextDef::save,
+            ScreenTextDef::load,
+        );
+        registry.register::<UiRect>("UiRect");
+        registry.register_with::<UiImage, UiImageDef>(
+            "UiImage",
+            UiImageDef::save,
+            UiImageDef::load,
+        );
+        registry.register_with::<NineSlice, NineSliceDef>(
+            "NineSlice",
+            NineSliceDef::save,
+            NineSliceDef::load,
+        );
+        registry.register_with::<PlayerState, PlayerStateDef>(
+            "PlayerState",
+            PlayerStateDef::save,
+            PlayerStateDef::load,
+        );
+        registry.register_resource_with::<Timer, TimerDef>(
+            "Timer",
+            |timer, _| {
+                Ok(TimerDef {
+                    elapsed: timer.elapsed().as_secs_f32(),
+                    fixed_step: timer.fixed_step().map(|step| step.as_secs_f32()),
+                })
+            },
+            |def, _| {
+                let mut timer = Timer::from_elapsed(Duration::from_secs_f32(def.elapsed));
+                timer.set_fixed_step(def.fixed_step.map(Duration::from_secs_f32));
+                Ok(timer)
+            },
+        );
+        registry
+    }
+
+    pub fn register<T>(&mut self, name: &str)
+    where
+        T: Component + Serialize + DeserializeOwned,
+    {
+        self.push(Registration {
+            name: name.to_string(),
+            has: has::<T>(),
+            save: Box::new(|world, entity, _| {
+                let component = world.get::<T>(entity)?;
+                Some(serde_json::to_value(component).map_err(SceneError::from))
+            }),
+            load: Box::new(|_, value, _| {
+                let component: T = serde_json::from_value(value)?;
+                Ok(insert(component))
+            }),
+        });
+    }
+
+    /// Registers a component that refers to other entities, which must be saved too.
+    pub fn register_mapped<T>(&mut self, name: &str)
+    where
+        T: Component + Clone + MapEntities + Serialize + DeserializeOwned,
+    {
+        self.push(Registration {
+            name: name.to_string(),
+            has: has::<T>(),
+            save: Box::new(|world, entity, entities| {
+                let mut component = world.get::<T>(entity)?.clone();
+                Some(
+                    component
+                        .map_entities(entities)
+                        .map_err(SceneError::from)
+                        .and_then(|_| Ok(serde_json::to_value(component)?)),
+                )
+            }),
+            load: Box::new(|_, value, entities| {
+                let component: T = serde_json::from_value(value)?;
+                component.clone().map_entities(entities)?;
+                Ok(Box::new(move |world, entity, entities| {
+                    let mut component = component;
+                    component
+                        .map_entities(entities)
+                        .expect("entities were checked when the component was loaded");
+                    world.entity_mut(entity).insert(component);
+                }))
+            }),
+        });
+    }
+
+    /// Registers a component that is written as `S`, for components that can not be
+    /// serialized directly or refer to things that only exist at runtime.
+    pub fn register_with<T, S>(
+        &mut self,
+        name: &str,
+        save: fn(&T, &World) -> Result<S, SceneError>,
+        load: fn(S, &World) -> Result<T, SceneError>,
+    ) where
+        T: Component,
+        S: Serialize + DeserializeOwned + 'static,
+    {
+        self.push(Registration {
+            name: name.to_string(),
+            has: has::<T>(),
+            save: Box::new(move |world, entity, _| {
+                let component = world.get::<T>(entity)?;
+                Some(save(component, world).and_then(|s| Ok(serde_json::to_value(s)?)))
+            }),
+            load: Box::new(move |world, value, _| {
+                Ok(insert(load(serde_json::from_value(value)?, world)?))
+            }),
+        });
+    }
+
+    pub fn register_resource<T>(&mut self, name: &str)
+    where
+        T: Component + Serialize + DeserializeOwned,
+    {
+        self.push_resource(ResourceRegistration {
+            name: name.to_string(),
+            save: Box::new(|world| {
+                let resource = world.get_resource::<T>()?;
+                Some(serde_json::to_value(resource).map_err(SceneError::from))
+            }),
+            load: Box::new(|_, value| {
+                let resource: T = serde_json::from_value(value)?;
+                Ok(insert_resource(resource))
+            }),
+            remove: remove_resource::<T>(),
+        });
+    }
+
+    pub fn register_resource_with<T, S>(
+        &mut self,
+        name: &str,
+        save: fn(&T, &World) -> Result<S, SceneError>,
+        load: fn(S, &World) -> Result<T, SceneError>,
+    ) where
+        T: Component,
+        S: Serialize + DeserializeOwned + 'static,
+    {
+        self.push_resource(ResourceRegistration {
+            name: name.to_string(),
+            save: Box::new(move |world| {
+                let resource = world.get_resource::<T>()?;
+                Some(save(resource, world).and_then(|s| Ok(serde_json::to_value(s)?)))
+            }),
+            load: Box::new(move |world, value| {
+                Ok(insert_resource(load(
+                    serde_json::from_value(value)?,
+                    world,
+                )?))
+            }),
+            remove: remove_resource::<T>(),
+        });
+    }
+
+    /// Registering a name again replaces the previous registration.
+    fn push(&mut self, registration: Registration) {
+        self.components
+            .retain(|existing| existing.name != registration.name);
+        self.components.push(registration);
+    }
+
+    fn push_resource(&mut self, registration: ResourceRegistration) {
+        self.resources
+            .retain(|existing| existing.name != registration.name);
+        self.resources.push(registration);
+    }
+
+    /// Entities without any registered components are skipped.
+    pub fn save(&self, world: &mut World) -> Result<SceneFile, SceneError> {
+        Ok(SceneFile {
+            entities: self.save_entities(world)?,
+        })
+    }
+
+    /// Spawns every entity in the scene and returns them in file order. Nothing is spawned if
+    /// any component can not be loaded.
+    pub fn load(&self, world: &mut World, scene: SceneFile) -> Result<Vec<Entity>, SceneError> {
+        let entities = self.stage_entities(world, scene.entities)?;
+        Ok(spawn_entities(world, entities))
+    }
+
+    pub fn snapshot(&self, world: &mut World, version: u32) -> Result<Snapshot, SceneError> {
+        Ok(Snapshot {
+            version,
+            resources: self.save_resources(world)?,
+            entities: self.save_entities(world)?,
+        })
+    }
+
+    /// Despawns every entity and replaces them with the ones in the snapshot. The whole snapshot
+    /// is loaded before the world is changed, so if it can not be restored the world is left as
+    /// it was. Components are loaded after the snapshot's resources have been put in place, so
+    /// they can depend on them.
+    pub fn restore(&self, world: &mut World, snapshot: Snapshot) -> Result<(), SceneError> {
+        let resources = self.stage_resources(world, snapshot.resources)?;
+        let previous = self.save_resources(world)?;
+        resources.into_iter().for_each(|insert| insert(world));
+
+        let entities = match self.stage_entities(world, snapshot.entities) {
+            Ok(entities) => entities,
+            Err(e) => {
+                for registration in self.resources.iter() {
+                    (registration.remove)(world);
+                }
+                self.stage_resources(world, previous)
+                    .expect("resources that were just saved can be loaded")
+                    .into_iter()
+                    .for_each(|insert| insert(world));
+                return Err(e);
+            }
+        };
+
+        let existing: Vec<Entity> = world.query::<Entity>().iter(world).collect();
+        for entity in existing {
+            world.despawn(entity);
+        }
+        spawn_entities(world, entities);
+        Ok(())
+    }
+
+    fn save_resources(&self, world: &World) -> Result<BTreeMap<String, Value>, SceneError> {
+        let mut resources = BTreeMap::new();
+        for registration in self.resources.iter() {
+            if let Some(value) = (registration.save)(world) {
+                resources.insert(registration.name.clone(), value?);
+            }
+        }
+        Ok(resources)
+    }
+
+    fn stage_resources(
+        &self,
+        world: &World,
+        resources: BTreeMap<String, Value>,
+    ) -> Result<Vec<InsertResource>, SceneError> {
+        resources
+            .into_iter()
+            .map(|(name, value)| {
+                let registration = self
+                    .resources
+                    .iter()
+                    .find(|registration| registration.name == name)
+                    .ok_or(SceneError::UnknownResource(name))?;
+                (registration.load)(world, value)
+            })
+            .collect()
+    }
+
+    fn save_entities(&self, world: &mut World) -> Result<Vec<SceneEntity>, SceneError> {
+        let entities: Vec<Entity> = world
+            .query::<Entity>()
+            .iter(world)
+            .filter(|entity| {
+                self.components
+                    .iter()
+                    .any(|registration| (registration.has)(world, *entity))
+            })
+            .collect();
+
+        let mut indices = EntityMap::default();
+        for (index, entity) in entities.iter().enumerate() {
+            indices.insert(*entity, Entity::new(index as u32));
+        }
+
+        let mut saved = vec![];
+        for entity in entities {
+            let mut components = BTreeMap::new();
+            for registration in self.components.iter() {
+                if let Some(value) = (registration.save)(world, entity, &indices) {
+                    components.insert(registration.name.clone(), value?);
+                }
+            }
+            saved.push(SceneEntity(components));
+        }
+
+        Ok(saved)
+    }
+
+    /// Decodes the components of every entity without changing the world.
+    fn stage_entities(
+        &self,
+        world: &World,
+        entities: Vec<SceneEntity>,
+    ) -> Result<Vec<Vec<Insert>>, SceneError> {
+        let mut indices = EntityMap::default();
+        for index in 0..entities.len() as u32 {
+            indices.insert(Entity::new(index), Entity::new(index));
+        }
+
+        entities
+            .into_iter()
+            .map(|SceneEntity(components)| {
+                components
+                    .into_iter()
+                    .map(|(name, value)| {
+                        let registration = self
+                            .components
+                            .iter()
+                            .find(|registration| registration.name == name)
+                            .ok_or(SceneError::UnknownComponent(name))?;
+                        (registration.load)(world, value, &indices)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+fn spawn_entities(world: &mut World, entities: Vec<Vec<Insert>>) -> Vec<Entity> {
+    let spawned: Vec<Entity> = entities.iter().map(|_| world.spawn().id()).collect();
+    let mut indices = EntityMap::default();
+    for (index, entity) in spawned.iter().enumerate() {
+        indices.insert(Entity::new(index as u32), *entity);
+    }
+
+    for (entity, components) in spawned.iter().zip(entities) {
+        for insert in components {
+            insert(world, *entity, &indices);
+        }
+    }
+    spawned
+}
+
+/// Writes entities as their id, for `#[serde(with)]`. Reading `Entity` directly only works in
+/// formats that keep integers as `u32`, which JSON does not.
+pub(crate) mod entity_id {
+    use bevy_ecs::entity::Entity;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(entity: &Entity, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(entity.id())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Entity, D::Error> {
+        u32::deserialize(deserializer).map(Entity::new)
+    }
+}
+
+/// Writes lists of entities as their ids, like [`entity_id`].
+pub(crate) mod entity_ids {
+    use bevy_ecs::entity::Entity;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(entities: &[Entity], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(entities.iter().map(|entity| entity.id()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Entity>, D::Error> {
+        Vec::<u32>::deserialize(deserializer).map(|ids| ids.into_iter().map(Entity::new).collect())
+    }
+}
+
+fn has<T: Component>() -> HasFn {
+    Box::new(|world, entity| world.get::<T>(entity).is_some())
+}
+
+fn insert<T: Component>(component: T) -> Insert {
+    Box::new(move |world, entity, _| {
+        world.entity_mut(entity).insert(component);
+    })
+}
+
+fn insert_resource<T: Component>(resource: T) -> InsertResource {
+    Box::new(move |world| world.insert_resource(resource))
+}
+
+fn remove_resource<T: Component>() -> RemoveResourceFn {
+    Box::new(|world| {
+        world.remove_resource::<T>();
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+struct ColliderDef {
+    half_extents: [f32; 2],
+}
+
+/// Sprites are stored by name since ids depend on the order sprites were registered in.
+#[derive(Serialize, Deserialize)]
+struct SpriteDef {
+    name: String,
+    #[serde(default)]
+    frame: u8,
+    #[serde(default)]
+    translucent: bool,
+    #[serde(default)]
+    flip_x: bool,
+    #[serde(default)]
+    flip_y: bool,
+}
+
+impl SpriteDef {
+    fn save(sprite: &Sprite, world: &World) -> Result<Self, SceneError> {
+        Ok(SpriteDef {
+            name: sprite_name(world, sprite.id())?,
+            frame: sprite.anim_frame_index,
+            translucent: sprite.translucent,
+            flip_x: sprite.flip_x,
+            flip_y: sprite.flip_y,
+        })
+    }
+
+    fn load(self, world: &World) -> Result<Sprite, SceneError> {
+        let mut sprite = Sprite::new(sprite_id(world, self.name)?);
+        sprite.anim_frame_index = self.frame;
+        sprite.translucent = self.translucent;
+        sprite.flip_x = self.flip_x;
+        sprite.flip_y = self.flip_y;
+        Ok(sprite)
+    }
+}
+
+/// The target sprite is stored by name, see [`SpriteDef`]. No target means the window.
+#[derive(Serialize, Deserialize)]
+struct ViewportDef {
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    #[serde(default)]
+    order: i32,
+    #[serde(default)]
+    target: Option<String>,
+}
+
+impl ViewportDef {
+    fn save(viewport: &Viewport, world: &World) -> Result<Self, SceneError> {
+        Ok(ViewportDef {
+            x: viewport.x,
+            y: viewport.y,
+            width: viewport.width,
+            height: viewport.height,
+            order: viewport.order,
+            target: match viewport.target {
+                CameraTarget::Window => None,
+                CameraTarget::Sprite(id) => Some(sprite_name(world, id)?),
+            },
+        })
+    }
+
+    fn load(self, world: &World) -> Result<Viewport, SceneError> {
+        let target = match self.target {
+            Some(name) => CameraTarget::Sprite(sprite_id(world, name)?),
+            None => CameraTarget::Window,
+        };
+        Ok(Viewport::new(self.x, self.y, self.width, self.height)
+            .with_order(self.order)
+            .with_target(target))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct TilemapDef {
+    sprite: String,
+    width: usize,
+    tile_size: Vec2,
+    tiles: Vec<Option<Tile>>,
+}
+
+impl TilemapDef {
+    fn save(tilemap: &Tilemap, world: &World) -> Result<Self, SceneError> {
+        Ok(TilemapDef {
+            sprite: sprite_name(world, tilemap.sprite())?,
+            width: tilemap.width(),
+            tile_size: tilemap.tile_size(),
+            tiles: tilemap.tiles().to_vec(),
+        })
+    }
+
+    fn load(self, world: &World) -> Result<Tilemap, SceneError> {
+        Ok(Tilemap::from_tiles(
+            sprite_id(world, self.sprite)?,
+            self.width,
+            self.tiles,
+            self.tile_size,
+        ))
+    }
+}
+
+/// The game clock, or the wall clock for worlds without a `Timer`.
+fn timer_now(world: &World) -> Instant {
+    world
+        .get_resource::<Timer>()
+        .map(|timer| timer.now())
+        .unwrap_or_else(Instant::now)
+}
+
+/// Buffered presses are stored as seconds before the `Timer`, like [`PlayerStateDef`], so
+/// restored snapshots keep inputs made just before they were taken.
+#[derive(Serialize, Deserialize)]
+struct ActionStateDef {
+    #[serde(flatten)]
+    state: ActionState,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pressed_ago: BTreeMap<Action, f32>,
+}
+
+impl ActionStateDef {
+    fn save(state: &ActionState, world: &World) -> Result<Self, SceneError> {
+        let now = timer_now(world);
+        Ok(ActionStateDef {
+            state: state.clone(),
+            pressed_ago: state
+                .last_pressed()
+                .iter()
+                .map(|(action, pressed)| {
+                    let ago = now.saturating_duration_since(*pressed).as_secs_f32();
+                    (*action, ago)
+                })
+                .collect(),
+        })
+    }
+
+    fn load(self, world: &World) -> Result<ActionState, SceneError> {
+        let now = timer_now(world);
+        let mut state = self.state;
+        for (action, ago) in self.pressed_ago {
+            let pressed = now.checked_sub(Duration::from_secs_f32(ago)).unwrap_or(now);
+            state.set_last_pressed(action, pressed);
+        }
+        Ok(state)
+    }
+}
+
+/// `Instant`s can not be saved, so states store how long they have lasted instead.
+#[derive(Serialize, Deserialize)]
+enum PlayerStateDef {
+    Standing { elapsed: f32 },
+    Running { elapsed: f32 },
+    Attacking { elapsed: f32 },
+}
+
+impl PlayerStateDef {
+    fn save(state: &PlayerState, world: &World) -> Result<Self, SceneError> {
+        let now = timer_now(world);
+        let elapsed = |start: &Instant| now.saturating_duration_since(*start).as_secs_f32();
+        Ok(match state {
+            PlayerState::Standing(start) => PlayerStateDef::Standing {
+                elapsed: elapsed(start),
+            },
+            PlayerState::Running(start) => PlayerStateDef::Running {
+                elapsed: elapsed(start),
+            },
+            PlayerState::Attacking(start) => PlayerStateDef::Attacking {
+                elapsed: elapsed(start),
+            },
+        })
+    }
+
+    fn load(self, world: &World) -> Result<PlayerState, SceneError> {
+        let now = timer_now(world);
+        let start = |elapsed: f32| {
+            now.checked_sub(Duration::from_secs_f32(elapsed))
+                .unwrap_or(now)
+        };
+        Ok(match self {
+            PlayerStateDef::Standing { elapsed } => PlayerState::Standing(start(elapsed)),
+            PlayerStateDef::Running { elapsed } => PlayerState::Running(start(elapsed)),
+            PlayerStateDef::Attacking { elapsed } => PlayerState::Attacking(start(elapsed)),
+        })
+    }
+}
+
+/// Fonts are stored by name, see [`SpriteDef`].
+#[derive(Serialize, Deserialize)]
+struct TextDef {
+    font: String,
+    value: String,
+    #[serde(default)]
+    align: TextAlign,
+}
+
+impl TextDef {
+    fn save(text: &Text, world: &World) -> Result<Self, SceneError> {
+        Ok(TextDef {
+            font: sprite_name(world, text.font)?,
+            value: text.value.clone(),
+            align: text.align,
+        })
+    }
+
+    fn load(self, world: &World) -> Result<Text, SceneError> {
+        let mut text = Text::new(sprite_id(world, self.font)?, self.value);
+        text.align = self.align;
+        Ok(text)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScreenTextDef {
+    font: String,
+    value: String,
+    #[serde(default)]
+    align: TextAlign,
+    position: Vec2,
+    scale: f32,
+}
+
+impl ScreenTextDef {
+    fn save(text: &ScreenText, world: &World) -> Result<Self, SceneError> {
+        Ok(ScreenTextDef {
+            font: sprite_name(world, text.font)?,
+            value: text.value.clone(),
+            align: text.align,
+            position: text.position,
+            scale: text.scale,
+        })
+    }
+
+    fn load(self, world: &World) -> Result<ScreenText, SceneError> {
+        let mut text = ScreenText::new(sprite_id(world, self.font)?, self.value, self.position);
+        text.align = self.align;
+        text.scale = self.scale;
+        Ok(text)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct UiImageDef {
+    sprite: String,
+    #[serde(default)]
+    frame: u8,
+}
+
+impl UiImageDef {
+    fn save(image: &UiImage, world: &World) -> Result<Self, SceneError> {
+        Ok(UiImageDef {
+            sprite: sprite_name(world, image.sprite)?,
+            frame: image.frame,
+        })
+    }
+
+    fn load(self, world: &World) -> Result<UiImage, SceneError> {
+        Ok(UiImage {
+            sprite: sprite_id(world, self.sprite)?,
+            frame: self.frame,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct NineSliceDef {
+    sprite: String,
+}
+
+impl NineSliceDef {
+    fn save(panel: &NineSlice, world: &World) -> Result<Self, SceneError> {
+        Ok(NineSliceDef {
+            sprite: sprite_name(world, panel.sprite)?,
+        })
+    }
+
+    fn load(self, world: &World) -> Result<NineSlice, SceneError> {
+        Ok(NineSlice::new(sprite_id(world, self.sprite)?))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct TimerDef {
+    /// Duration of the last frame in seconds.
+    elapsed: f32,
+    /// Length of every tick in seconds when the clock is fixed.
+    #[serde(default)]
+    fixed_step: Option<f32>,
+}
+
+fn sprite_name(world: &World, id: SpriteId) -> Result<String, SceneError> {
+    let catalog = world
+        .get_resource::<SpriteCatalog>()
+        .ok_or(SceneError::MissingSpriteCatalog)?;
+    catalog
+        .get(id)
+        .map(|info| info.id.clone())
+        .ok_or_else(|| SceneError::UnknownSprite(id.to_string()))
+}
+
+fn sprite_id(world: &World, name: String) -> Result<SpriteId, SceneError> {
+    let catalog = world
+        .get_resource::<SpriteCatalog>()
+        .ok_or(SceneError::MissingSpriteCatalog)?;
+    catalog.find(&name).ok_or(SceneError::UnknownSprite(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::SpriteInfo;
+    use crate::ui::Anchor;
+    use crate::Game;
+    use glam::{Quat, Vec3};
+
+    fn world() -> World {
+        let mut world = World::default();
+        world.insert_resource(SpriteCatalog(vec![SpriteInfo {
+            id: "font".to_string(),
+            width: 8,
+            height: 8,
+            pivots: vec![],
+            font: None,
+        }]));
+        world.insert_resource(Timer::from_elapsed(Duration::from_millis(250)));
+        world
+    }
+
+    fn positions(world: &mut World) -> Vec<Vec3> {
+        world
+            .query::<&Position>()
+            .iter(world)
+            .map(|position| position.0)
+            .collect()
+    }
+
+    #[test]
+    fn snapshots_that_can_not_be_restored_leave_the_world_alone() {
+        let registry = ComponentRegistry::with_builtin();
+        let mut world = world();
+        world.spawn().insert(Position(Vec3::new(1.0, 2.0, 3.0)));
+        let mut snapshot = registry.snapshot(&mut world, 1).unwrap();
+
+        snapshot.resources.insert(
+            "Timer".to_string(),
+            serde_json::json!({ "elapsed": 0.5, "fixed_step": null }),
+        );
+        let mut unknown = SceneEntity::default();
+        unknown
+            .0
+            .insert("Unknown".to_string(), serde_json::json!(null));
+        snapshot.entities.push(unknown);
+
+        assert!(matches!(
+            registry.restore(&mut world, snapshot),
+            Err(SceneError::UnknownComponent(name)) if name == "Unknown"
+        ));
+        assert_eq!(positions(&mut world), vec![Vec3::new(1.0, 2.0, 3.0)]);
+        let timer = world.get_resource::<Timer>().unwrap();
+        assert_eq!(timer.elapsed(), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn unknown_resources_are_reported_as_resources() {
+        let registry = ComponentRegistry::with_builtin();
+        let mut world = world();
+        let mut snapshot = registry.snapshot(&mut world, 1).unwrap();
+        snapshot
+            .resources
+            .insert("Weather".to_string(), serde_json::json!("rain"));
+
+        assert!(matches!(
+            registry.restore(&mut world, snapshot),
+            Err(SceneError::UnknownResource(name)) if name == "Weather"
+        ));
+    }
+
+    #[test]
+    fn followed_entities_are_mapped_to_the_restored_ones() {
+        let registry = ComponentRegistry::with_builtin();
+        let mut world = world();
+        let player = world.spawn().insert(Position(Vec3::one())).id();
+        world
+            .spawn()
+            .insert_bundle((CameraFollow::new(player), CameraZoom::new(2.0, 0.5)));
+        let snapshot = registry.snapshot(&mut world, 1).unwrap();
+
+        registry.restore(&mut world, snapshot).unwrap();
+
+        let mut follows = world.query::<(&CameraFollow, &CameraZoom)>();
+        let (follow, zoom) = follows.iter(&world).next().unwrap();
+        assert_ne!(follow.target, player);
+        assert_eq!(world.get::<Position>(follow.target).unwrap().0, Vec3::one());
+        assert_eq!(zoom.target, 2.0);
+    }
+
+    #[test]
+    fn buffered_presses_are_restored_relative_to_the_timer() {
+        let registry = ComponentRegistry::with_builtin();
+        let mut world = world();
+        let now = world.get_resource::<Timer>().unwrap().now();
+        let mut actions = ActionState::default();
+        actions.set_last_pressed(Action::Attack, now - Duration::from_millis(100));
+        world.spawn().insert(actions);
+        let snapshot = registry.snapshot(&mut world, 1).unwrap();
+
+        registry.restore(&mut world, snapshot).unwrap();
+
+        let now = world.get_resource::<Timer>().unwrap().now();
+        let mut actions = world.query::<&ActionState>();
+        let actions = actions.iter(&world).next().unwrap();
+        assert!(actions.pressed_within(Action::Attack, Duration::from_millis(101), now));
+        assert!(!actions.pressed_within(Action::Attack, Duration::from_millis(99), now));
+        assert!(!actions.pressed_within(Action::Jump, Duration::from_secs(1), now));
+    }
+
+    #[test]
+    fn followed_entities_must_be_saved() {
+        let registry = ComponentRegistry::with_builtin();
+        let mut world = world();
+        let unsaved = world.spawn().id();
+        world.spawn().insert(CameraFollow::new(unsaved));
+
+        assert!(matches!(
+            registry.snapshot(&mut world, 1),
+            Err(SceneError::MissingEntity(entity)) if entity == unsaved
+        ));
+    }
+
+    #[test]
+    fn text_and_widgets_are_saved_with_sprite_names() {
+        let registry = ComponentRegistry::with_builtin();
+        let mut world = world();
+        world.spawn().insert_bundle((
+            UiRect::new(Anchor::TopLeft, Vec2::new(4.0, 4.0), Vec2::new(64.0, 16.0)),
+            NineSlice::new(0),
+            ScreenText::new(0, "Score", Vec2::new(2.0, 2.0)),
+        ));
+        let scene = registry.save(&mut world).unwrap();
+        assert_eq!(
+            scene.entities[0].0["NineSlice"],
+            serde_json::json!({ "sprite": "font" })
+        );
+
+        let loaded = registry.load(&mut world, scene).unwrap();
+        let text = world.get::<ScreenText>(loaded[0]).unwrap();
+        assert_eq!((text.font, text.value.as_str()), (0, "Score"));
+        assert!(world.get::<UiRect>(loaded[0]).is_some());
+    }
+
+    #[test]
+    fn scenes_round_trip_through_ron_files() {
+        let registry = ComponentRegistry::with_builtin();
+        let mut world = world();
+        let mut sprite = Sprite::new(0);
+        sprite.flip_x = true;
+        world.spawn().insert_bundle((
+            Position(Vec3::new(1.0, -2.0, 20.0)),
+            Rotation(Quat::from_rotation_z(0.5)),
+            Scale(Vec3::new(2.0, 0.5, 1.0)),
+            sprite,
+            Collider(Cuboid::new(Vector2::new(0.25, 0.75))),
+        ));
+        let file = std::env::temp_dir().join("erlking_scene_round_trip.ron");
+        registry.save(&mut world).unwrap().write(&file).unwrap();
+        assert!(read_to_string(&file).unwrap().starts_with('('));
+
+        let mut loaded_world = self::world();
+        let loaded = registry
+            .load(&mut loaded_world, SceneFile::read(&file).unwrap())
+            .unwrap();
+        let entity = loaded_world.entity(loaded[0]);
+        assert_eq!(
+            entity.get::<Position>().unwrap().0,
+            Vec3::new(1.0, -2.0, 20.0)
+        );
+        assert_eq!(
+            entity.get::<Rotation>().unwrap().0,
+            Quat::from_rotation_z(0.5)
+        );
+        assert_eq!(entity.get::<Scale>().unwrap().0, Vec3::new(2.0, 0.5, 1.0));
+        let sprite = entity.get::<Sprite>().unwrap();
+        assert_eq!((sprite.id(), sprite.flip_x), (0, true));
+        assert_eq!(
+            entity.get::<Collider>().unwrap().0.half_extents,
+            Vector2::new(0.25, 0.75)
+        );
+    }
+
+    #[test]
+    fn children_stay_relative_to_their_parents() {
+        let file = std::env::temp_dir().join("erlking_scene_hierarchy.ron");
+        let mut game = Game::new();
+        let parent = game.spawn((Position(Vec3::new(10.0, 0.0, 0.0)),));
+        game.spawn_child(parent, (Position(Vec3::new(1.0, 2.0, 0.0)),));
+        game.save_scene(&file).unwrap();
+
+        let mut game = Game::new();
+        let loaded = game.load_scene(&file).unwrap();
+        game.update();
+
+        let child = loaded
+            .iter()
+            .find(|entity| game.world.get::<Parent>(**entity).is_some())
+            .unwrap();
+        assert_eq!(
+            game.world.get::<GlobalTransform>(*child).unwrap().position,
+            Vec3::new(11.0, 2.0, 0.0)
+        );
+        let parent = game.world.get::<Parent>(*child).unwrap().0;
+        assert_eq!(game.world.get::<Children>(parent).unwrap().0, vec![*child]);
+    }
+}
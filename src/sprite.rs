@@ -1,14 +1,38 @@
-use serde::Deserialize;
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct KeyFrame {
     pub png: PathBuf,
     pub time: f32,
     pub view: View,
+    #[serde(default)]
+    pub pivot: Option<Pivot>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// The point of a frame that sits on the entity position, as a fraction of the frame size
+/// measured from the top left corner. (0.5, 0.5) is the centre and (0.5, 1.0) is the middle of
+/// the bottom edge.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Pivot {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Pivot {
+    pub fn centre() -> Self {
+        Pivot { x: 0.5, y: 0.5 }
+    }
+}
+
+impl From<Pivot> for Vec2 {
+    fn from(pivot: Pivot) -> Self {
+        Vec2::new(pivot.x, pivot.y)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct View {
     pub x: u32,
     pub y: u32,
@@ -20,6 +44,13 @@ pub struct View {
 pub struct Sprite {
     id: usize,
     pub anim_frame_index: u8,
+    /// Translucent sprites are alpha blended and drawn back to front after the
+    /// opaque ones instead of being alpha tested.
+    pub translucent: bool,
+    /// Mirror the texture horizontally.
+    pub flip_x: bool,
+    /// Mirror the texture vertically.
+    pub flip_y: bool,
 }
 
 impl Sprite {
@@ -27,6 +58,16 @@ impl Sprite {
         Self {
             id,
             anim_frame_index: 0,
+            translucent: false,
+            flip_x: false,
+            flip_y: false,
+        }
+    }
+
+    pub fn translucent(id: usize) -> Self {
+        Self {
+            translucent: true,
+            ..Self::new(id)
         }
     }
 
@@ -35,7 +76,7 @@ impl Sprite {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AnimTimeline(pub Vec<Vec<KeyFrame>>);
 
 impl AnimTimeline {
@@ -0,0 +1,310 @@
+use crate::asset::SpriteId;
+use crate::renderer::TEXTURE_ARRAY_SIZE;
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+#[derive(Debug)]
+pub enum FontError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Image(image::ImageError),
+    /// Each character is a frame of the font sprite, so there can be at most
+    /// `TEXTURE_ARRAY_SIZE` of them.
+    TooManyCharacters(usize),
+}
+
+impl fmt::Display for FontError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FontError::Io(e) => write!(f, "could not access font file: {}", e),
+            FontError::Json(e) => write!(f, "invalid font metrics: {}", e),
+            FontError::Image(e) => write!(f, "could not load font image: {}", e),
+            FontError::TooManyCharacters(characters) => write!(
+                f,
+                "font has {} characters but at most {} can be drawn",
+                characters, TEXTURE_ARRAY_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FontError {}
+
+impl From<std::io::Error> for FontError {
+    fn from(e: std::io::Error) -> Self {
+        FontError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for FontError {
+    fn from(e: serde_json::Error) -> Self {
+        FontError::Json(e)
+    }
+}
+
+impl From<image::ImageError> for FontError {
+    fn from(e: image::ImageError) -> Self {
+        FontError::Image(e)
+    }
+}
+
+/// Layout of a bitmap font image, read from the font's metrics file.
+#[derive(Deserialize)]
+pub(crate) struct FontFile {
+    pub image: String,
+    pub cell_width: u32,
+    pub cell_height: u32,
+    /// The characters in the image, row by row from the top left.
+    pub characters: String,
+    /// Distance in pixels from one character to the next.
+    pub advance: u32,
+    /// Advances of characters that differ from `advance`.
+    #[serde(default)]
+    pub advances: BTreeMap<char, u32>,
+    /// Distance in pixels from one line to the next, the cell height if not set.
+    pub line_height: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Glyph {
+    frame: u8,
+    advance: u32,
+}
+
+/// Which frame of the font sprite draws each character and how far apart characters and lines
+/// are, in pixels.
+#[derive(Clone, Debug)]
+pub struct FontMetrics {
+    glyphs: HashMap<char, Glyph>,
+    cell_width: u32,
+    cell_height: u32,
+    line_height: u32,
+    /// Used for characters the font does not have.
+    fallback_advance: u32,
+}
+
+impl FontMetrics {
+    pub(crate) fn new(file: &FontFile) -> Result<Self, FontError> {
+        let characters = file.characters.chars().count();
+        if characters > TEXTURE_ARRAY_SIZE {
+            return Err(FontError::TooManyCharacters(characters));
+        }
+
+        let glyphs = file
+            .characters
+            .chars()
+            .enumerate()
+            .map(|(frame, character)| {
+                let advance = file
+                    .advances
+                    .get(&character)
+                    .copied()
+                    .unwrap_or(file.advance);
+                (
+                    character,
+                    Glyph {
+                        frame: frame as u8,
+                        advance,
+                    },
+                )
+            })
+            .collect();
+
+        Ok(FontMetrics {
+            glyphs,
+            cell_width: file.cell_width,
+            cell_height: file.cell_height,
+            line_height: file.line_height.unwrap_or(file.cell_height),
+            fallback_advance: file.advance,
+        })
+    }
+
+    pub fn line_height(&self) -> u32 {
+        self.line_height
+    }
+
+    /// Width of the widest line and height of all lines, in pixels.
+    pub fn measure(&self, text: &str) -> Vec2 {
+        let width = text
+            .lines()
+            .map(|line| self.line_width(line))
+            .fold(0.0, f32::max);
+        let lines = text.lines().count().max(1);
+        Vec2::new(width, (lines as u32 * self.line_height) as f32)
+    }
+
+    fn line_width(&self, line: &str) -> f32 {
+        line.chars()
+            .map(|character| {
+                self.glyphs
+                    .get(&character)
+                    .map(|glyph| glyph.advance)
+                    .unwrap_or(self.fallback_advance)
+            })
+            .sum::<u32>() as f32
+    }
+
+    /// The frame and cell centre of every visible character, in pixels relative to the anchor
+    /// with y up. The anchor is on the top edge of the first line, at its left end, centre or
+    /// right end depending on `align`.
+    pub(crate) fn layout(&self, text: &str, align: TextAlign) -> Vec<(u8, Vec2)> {
+        let mut glyphs = vec![];
+        let half_cell = Vec2::new(self.cell_width as f32, -(self.cell_height as f32)) / 2.0;
+
+        for (row, line) in text.lines().enumerate() {
+            let mut pen = Vec2::new(
+                match align {
+                    TextAlign::Left => 0.0,
+                    TextAlign::Centre => -self.line_width(line) / 2.0,
+                    TextAlign::Right => -self.line_width(line),
+                },
+                -((row as u32 * self.line_height) as f32),
+            );
+
+            for character in line.chars() {
+                match self.glyphs.get(&character) {
+                    Some(glyph) => {
+                        if !character.is_whitespace() {
+                            glyphs.push((glyph.frame, pen + half_cell));
+                        }
+                        pen.x += glyph.advance as f32;
+                    }
+                    None => pen.x += self.fallback_advance as f32,
+                }
+            }
+        }
+
+        glyphs
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TextAlign {
+    #[default]
+    Left,
+    Centre,
+    Right,
+}
+
+/// Text placed in the world by the entity's `Position`, `Rotation` and `Scale`, with the same
+/// parallax as sprites at its depth. `font` is a sprite loaded with `SpriteData::load_font`.
+#[derive(Clone, Debug)]
+pub struct Text {
+    pub font: SpriteId,
+    pub value: String,
+    pub align: TextAlign,
+}
+
+impl Text {
+    pub fn new(font: SpriteId, value: impl Into<String>) -> Self {
+        Text {
+            font,
+            value: value.into(),
+            align: TextAlign::Left,
+        }
+    }
+}
+
+/// Text drawn over the world, unaffected by the camera. `position` is in pixels from the top
+/// left of the window, or of the entity's `UiRect` if it has one.
+#[derive(Clone, Debug)]
+pub struct ScreenText {
+    pub font: SpriteId,
+    pub value: String,
+    pub align: TextAlign,
+    pub position: Vec2,
+    /// Multiplies the font's pixel size.
+    pub scale: f32,
+}
+
+impl ScreenText {
+    pub fn new(font: SpriteId, value: impl Into<String>, position: Vec2) -> Self {
+        ScreenText {
+            font,
+            value: value.into(),
+            align: TextAlign::Left,
+            position,
+            scale: 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 8 x 10 pixel cells advancing 6 pixels, except `i` which advances 3.
+    fn font(characters: &str) -> FontFile {
+        FontFile {
+            image: "font.png".to_string(),
+            cell_width: 8,
+            cell_height: 10,
+            characters: characters.to_string(),
+            advance: 6,
+            advances: vec![('i', 3)].into_iter().collect(),
+            line_height: Some(12),
+        }
+    }
+
+    #[test]
+    fn fonts_have_at_most_one_character_per_frame() {
+        let characters: String = (0..=TEXTURE_ARRAY_SIZE as u32)
+            .map(|c| std::char::from_u32(0x100 + c).unwrap())
+            .collect();
+        assert!(matches!(
+            FontMetrics::new(&font(&characters)),
+            Err(FontError::TooManyCharacters(count)) if count == TEXTURE_ARRAY_SIZE + 1
+        ));
+        let characters: String = characters.chars().skip(1).collect();
+        assert!(FontMetrics::new(&font(&characters)).is_ok());
+    }
+
+    #[test]
+    fn lines_are_measured_by_their_advances() {
+        let metrics = FontMetrics::new(&font("abi ")).unwrap();
+        assert_eq!(metrics.line_height(), 12);
+        assert_eq!(metrics.measure("ab"), Vec2::new(12.0, 12.0));
+        assert_eq!(metrics.measure("ai\nbbb"), Vec2::new(18.0, 24.0));
+        // Missing characters advance by the default advance.
+        assert_eq!(metrics.measure("a?i"), Vec2::new(15.0, 12.0));
+        // Empty text is still one line high.
+        assert_eq!(metrics.measure(""), Vec2::new(0.0, 12.0));
+
+        let mut file = font("a");
+        file.line_height = None;
+        assert_eq!(FontMetrics::new(&file).unwrap().line_height(), 10);
+    }
+
+    #[test]
+    fn glyphs_are_laid_out_along_lines_from_the_anchor() {
+        let metrics = FontMetrics::new(&font("abi ")).unwrap();
+        // Cell centres are half a cell right of and below the pen.
+        assert_eq!(
+            metrics.layout("ia b\n?a", TextAlign::Left),
+            vec![
+                (2, Vec2::new(4.0, -5.0)),
+                (0, Vec2::new(7.0, -5.0)),
+                // The space and the missing character advance without a glyph.
+                (1, Vec2::new(19.0, -5.0)),
+                (0, Vec2::new(10.0, -17.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_are_aligned_on_their_own_width() {
+        let metrics = FontMetrics::new(&font("abi ")).unwrap();
+        let x = |align| -> Vec<f32> {
+            metrics
+                .layout("ab\nb", align)
+                .iter()
+                .map(|(_, centre)| centre.x)
+                .collect()
+        };
+        assert_eq!(x(TextAlign::Left), vec![4.0, 10.0, 4.0]);
+        assert_eq!(x(TextAlign::Centre), vec![-2.0, 4.0, 1.0]);
+        assert_eq!(x(TextAlign::Right), vec![-8.0, -2.0, -2.0]);
+    }
+}
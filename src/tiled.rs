@@ -0,0 +1,1209 @@
+//! Loads levels made in the [Tiled](https://www.mapeditor.org) map editor, from either JSON
+//! (`.tmj`/`.json`) or XML (`.tmx`) map files.
+//!
+//! * Tile layers become [`Tilemap`]s, one per tileset used by the layer. Tiles are solid if the
+//!   layer has a `solid` property or the tile has one in its tileset.
+//! * Objects in object layers are spawned with a `Position` at their centre, a `Collider` the
+//!   size of the object and `Terrain` unless they set `terrain` to false. Tile objects and
+//!   objects with a `sprite` property naming a sprite in the [`SpriteRegistry`] also get a
+//!   `Sprite`.
+//! * A `depth` property on a layer, or on the map, sets the parallax depth (`Position.z`) of
+//!   everything in the layer. Layer offsets move the layer, as they do in Tiled.
+//! * Tiles and tile objects flipped horizontally or vertically are mirrored. Diagonal flips,
+//!   which Tiled uses for rotated tiles, are ignored.
+//!
+//! Tileset images that are not already in the registry are loaded under the tileset's name, and
+//! may have at most [`TEXTURE_ARRAY_SIZE`] tiles. They are only registered once the whole map
+//! has been checked. Tile layer data must be stored as CSV (the
+//! default), not base64.
+
+use crate::asset::{SpriteData, SpriteId, SpriteRegistry};
+use crate::renderer::sprite::PIXELS_PER_METRE;
+use crate::renderer::TEXTURE_ARRAY_SIZE;
+use crate::sprite::Sprite;
+use crate::tilemap::{Tile, Tilemap};
+use crate::{Collider, Game, Position, Rotation, Scale, Terrain};
+use glam::{Quat, Vec2, Vec3};
+use parry2d::na::Vector2;
+use parry2d::shape::Cuboid;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+/// Tiled stores tile flips in the top bits of a gid.
+const GID_MASK: u32 = 0x1fff_ffff;
+const FLIPPED_HORIZONTALLY: u32 = 0x8000_0000;
+const FLIPPED_VERTICALLY: u32 = 0x4000_0000;
+
+#[derive(Debug)]
+pub enum TiledError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Xml(roxmltree::Error),
+    Image(image::ImageError),
+    MissingAttribute {
+        element: String,
+        attribute: String,
+    },
+    InvalidNumber(String),
+    /// A tile layer without a `<data>` element.
+    MissingData(String),
+    /// A tile layer whose data is not stored as CSV.
+    UnsupportedEncoding(String),
+    /// A tileset that is not in the sprite registry and has no single image to load.
+    MissingImage(String),
+    TooManyTiles {
+        tileset: String,
+        tiles: usize,
+    },
+    /// A gid past the last tile of its tileset.
+    UnknownTile {
+        tileset: String,
+        id: u32,
+    },
+    UnknownSprite {
+        object: String,
+        sprite: String,
+    },
+}
+
+impl fmt::Display for TiledError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TiledError::Io(e) => write!(f, "could not access tiled file: {}", e),
+            TiledError::Json(e) => write!(f, "invalid tiled json: {}", e),
+            TiledError::Xml(e) => write!(f, "invalid tiled xml: {}", e),
+            TiledError::Image(e) => write!(f, "could not load tileset image: {}", e),
+            TiledError::MissingAttribute { element, attribute } => {
+                write!(f, "<{}> has no {} attribute", element, attribute)
+            }
+            TiledError::InvalidNumber(value) => write!(f, "{} is not a valid number", value),
+            TiledError::MissingData(layer) => write!(f, "tile layer {} has no data", layer),
+            TiledError::UnsupportedEncoding(layer) => {
+                write!(f, "tile layer {} must use the CSV tile layer format", layer)
+            }
+            TiledError::MissingImage(tileset) => write!(f, "tileset {} has no image", tileset),
+            TiledError::TooManyTiles { tileset, tiles } => write!(
+                f,
+                "tileset {} has {} tiles but at most {} can be drawn",
+                tileset, tiles, TEXTURE_ARRAY_SIZE
+            ),
+            TiledError::UnknownTile { tileset, id } => {
+                write!(f, "tileset {} has no tile {}", tileset, id)
+            }
+            TiledError::UnknownSprite { object, sprite } => {
+                write!(f, "object {} uses unknown sprite {}", object, sprite)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TiledError {}
+
+impl From<std::io::Error> for TiledError {
+    fn from(e: std::io::Error) -> Self {
+        TiledError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for TiledError {
+    fn from(e: serde_json::Error) -> Self {
+        TiledError::Json(e)
+    }
+}
+
+impl From<roxmltree::Error> for TiledError {
+    fn from(e: roxmltree::Error) -> Self {
+        TiledError::Xml(e)
+    }
+}
+
+impl From<image::ImageError> for TiledError {
+    fn from(e: image::ImageError) -> Self {
+        TiledError::Image(e)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Property {
+    Bool(bool),
+    Number(f32),
+    String(String),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Properties(pub HashMap<String, Property>);
+
+impl Properties {
+    pub fn bool(&self, name: &str) -> Option<bool> {
+        match self.0.get(name) {
+            Some(Property::Bool(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn number(&self, name: &str) -> Option<f32> {
+        match self.0.get(name) {
+            Some(Property::Number(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn string(&self, name: &str) -> Option<&str> {
+        match self.0.get(name) {
+            Some(Property::String(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Adds the properties of an enclosing group that are not overridden.
+    fn inherit(&mut self, parent: &Properties) {
+        for (name, value) in parent.0.iter() {
+            self.0.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Tileset {
+    pub first_gid: u32,
+    pub name: String,
+    pub image: Option<PathBuf>,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub solid_tiles: HashSet<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MapObject {
+    pub name: String,
+    /// Position and size in pixels, as stored by Tiled.
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    /// Clockwise, in degrees.
+    pub rotation: f32,
+    pub gid: Option<u32>,
+    pub properties: Properties,
+}
+
+#[derive(Clone, Debug)]
+pub enum Layer {
+    Tiles {
+        name: String,
+        width: usize,
+        data: Vec<u32>,
+        /// In pixels, including the offsets of enclosing groups.
+        offset: Vec2,
+        properties: Properties,
+    },
+    Objects {
+        name: String,
+        objects: Vec<MapObject>,
+        /// In pixels, including the offsets of enclosing groups.
+        offset: Vec2,
+        properties: Properties,
+    },
+}
+
+/// A Tiled map with group layers flattened.
+#[derive(Clone, Debug)]
+pub struct TiledMap {
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub properties: Properties,
+    pub tilesets: Vec<Tileset>,
+    pub layers: Vec<Layer>,
+}
+
+/// The sprite a tileset is drawn with and how many of its frames tiles can use.
+#[derive(Clone, Copy, Debug)]
+struct TilesetSprite {
+    id: SpriteId,
+    frames: usize,
+}
+
+impl TiledMap {
+    /// Picks the format from the file extension, `.tmx` is XML and anything else is JSON.
+    pub fn load(file: impl AsRef<Path>) -> Result<Self, TiledError> {
+        let path = file.as_ref();
+        let src = read_to_string(path)?;
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("tmx") => xml::parse_map(&src, dir),
+            _ => json::parse_map(&src, dir),
+        }
+    }
+
+    /// Spawns every layer of the map into `game`. The top left corner of the map is placed at
+    /// the origin and layers without a `depth` property are placed at `default_depth`. Nothing
+    /// is spawned or registered if any tileset, tile or object of the map is invalid.
+    pub fn spawn(
+        &self,
+        game: &mut Game,
+        sprites: &mut SpriteRegistry,
+        default_depth: f32,
+    ) -> Result<(), TiledError> {
+        let mut loaded = vec![];
+        let tileset_sprites = self
+            .tilesets
+            .iter()
+            .map(|tileset| tileset_sprite(tileset, sprites, &mut loaded))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut tilemaps = vec![];
+        let mut objects = vec![];
+        for layer in self.layers.iter() {
+            match layer {
+                Layer::Tiles {
+                    width,
+                    data,
+                    offset,
+                    properties,
+                    ..
+                } => {
+                    let depth = self.depth(properties, default_depth);
+                    let position = to_metres(*offset).extend(depth);
+                    for tilemap in self.tilemaps(*width, data, properties, &tileset_sprites)? {
+                        tilemaps.push((Position(position), tilemap));
+                    }
+                }
+                Layer::Objects {
+                    objects: layer_objects,
+                    offset,
+                    properties,
+                    ..
+                } => {
+                    let depth = self.depth(properties, default_depth);
+                    for object in layer_objects.iter() {
+                        let sprite =
+                            self.object_sprite(sprites, &loaded, &tileset_sprites, object)?;
+                        objects.push((object, *offset, depth, sprite));
+                    }
+                }
+            }
+        }
+
+        for data in loaded {
+            sprites.insert(data);
+        }
+        for tilemap in tilemaps {
+            game.spawn(tilemap);
+        }
+        for (object, offset, depth, sprite) in objects {
+            spawn_object(game, object, offset, depth, sprite);
+        }
+        Ok(())
+    }
+
+    fn depth(&self, layer: &Properties, default_depth: f32) -> f32 {
+        layer
+            .number("depth")
+            .or_else(|| self.properties.number("depth"))
+            .unwrap_or(default_depth)
+    }
+
+    /// Finds the tileset a gid belongs to and the tile's frame in the tileset's sprite.
+    fn tile(
+        &self,
+        gid: u32,
+        tileset_sprites: &[TilesetSprite],
+    ) -> Result<Option<(usize, u8)>, TiledError> {
+        let gid = gid & GID_MASK;
+        if gid == 0 {
+            return Ok(None);
+        }
+        let tileset = self
+            .tilesets
+            .iter()
+            .enumerate()
+            .filter(|(_, tileset)| tileset.first_gid <= gid)
+            .max_by_key(|(_, tileset)| tileset.first_gid);
+
+        match tileset {
+            Some((index, tileset)) => {
+                let id = gid - tileset.first_gid;
+                if id as usize >= tileset_sprites[index].frames {
+                    return Err(TiledError::UnknownTile {
+                        tileset: tileset.name.clone(),
+                        id,
+                    });
+                }
+                Ok(Some((index, id as u8)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn tilemaps(
+        &self,
+        width: usize,
+        data: &[u32],
+        properties: &Properties,
+        tileset_sprites: &[TilesetSprite],
+    ) -> Result<Vec<Tilemap>, TiledError> {
+        let layer_solid = properties.bool("solid").unwrap_or(false);
+        let tile_size =
+            Vec2::new(self.tile_width as f32, self.tile_height as f32) / PIXELS_PER_METRE as f32;
+
+        // One tilemap for each tileset the layer uses.
+        let mut tilesets: Vec<Option<Vec<Option<Tile>>>> = vec![None; self.tilesets.len()];
+        for (index, gid) in data.iter().enumerate() {
+            if let Some((tileset, frame)) = self.tile(*gid, tileset_sprites)? {
+                let (flip_x, flip_y) = flips(*gid);
+                let tiles = tilesets[tileset].get_or_insert_with(|| vec![None; data.len()]);
+                tiles[index] = Some(Tile {
+                    frame,
+                    solid: layer_solid
+                        || self.tilesets[tileset].solid_tiles.contains(&(frame as u32)),
+                    flip_x,
+                    flip_y,
+                });
+            }
+        }
+
+        Ok(tilesets
+            .into_iter()
+            .zip(tileset_sprites.iter())
+            .filter_map(|(tiles, sprite)| {
+                tiles.map(|tiles| Tilemap::from_tiles(sprite.id, width, tiles, tile_size))
+            })
+            .collect())
+    }
+
+    /// Tile objects are drawn with their tile, other objects with the sprite they name, which
+    /// may be one of the tilesets `loaded` for this map.
+    fn object_sprite(
+        &self,
+        sprites: &SpriteRegistry,
+        loaded: &[SpriteData],
+        tileset_sprites: &[TilesetSprite],
+        object: &MapObject,
+    ) -> Result<Option<Sprite>, TiledError> {
+        if let Some(gid) = object.gid {
+            if let Some((tileset, frame)) = self.tile(gid, tileset_sprites)? {
+                let mut sprite = Sprite::new(tileset_sprites[tileset].id);
+                sprite.anim_frame_index = frame;
+                let (flip_x, flip_y) = flips(gid);
+                sprite.flip_x = flip_x;
+                sprite.flip_y = flip_y;
+                return Ok(Some(sprite));
+            }
+        }
+
+        match object.properties.string("sprite") {
+            Some(name) => match find_sprite(sprites, loaded, name) {
+                Some(id) => Ok(Some(Sprite::new(id))),
+                None => Err(TiledError::UnknownSprite {
+                    object: object.name.clone(),
+                    sprite: name.to_string(),
+                }),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+fn spawn_object(
+    game: &mut Game,
+    object: &MapObject,
+    offset: Vec2,
+    depth: f32,
+    sprite: Option<Sprite>,
+) {
+    let size = Vec2::new(object.width, object.height) / PIXELS_PER_METRE as f32;
+    let corner = to_metres(offset + Vec2::new(object.x, object.y));
+    // Tile objects are anchored at their bottom left corner, everything else at the top left.
+    let centre = match object.gid {
+        Some(_) => corner + Vec2::new(size.x, size.y) / 2.0,
+        None => corner + Vec2::new(size.x, -size.y) / 2.0,
+    };
+
+    let entity = game.spawn((
+        Position(centre.extend(depth)),
+        Rotation(Quat::from_rotation_z(-object.rotation.to_radians())),
+        Scale(Vec3::one()),
+    ));
+
+    if size.x > 0.0 && size.y > 0.0 {
+        game.insert(
+            entity,
+            Collider(Cuboid::new(Vector2::new(size.x, size.y) / 2.0)),
+        );
+    }
+    if let Some(sprite) = sprite {
+        game.insert(entity, sprite);
+    }
+    if object.properties.bool("terrain").unwrap_or(true) {
+        game.insert(entity, Terrain);
+    }
+}
+
+/// Converts a position in Tiled's pixels, where y runs downwards, to metres.
+fn to_metres(pixels: Vec2) -> Vec2 {
+    Vec2::new(pixels.x, -pixels.y) / PIXELS_PER_METRE as f32
+}
+
+fn flips(gid: u32) -> (bool, bool) {
+    (
+        gid & FLIPPED_HORIZONTALLY != 0,
+        gid & FLIPPED_VERTICALLY != 0,
+    )
+}
+
+/// Looks a sprite up in the registry, then in the sprites that will be registered after it.
+fn find_sprite(sprites: &SpriteRegistry, loaded: &[SpriteData], name: &str) -> Option<SpriteId> {
+    sprites.find(name).or_else(|| {
+        loaded
+            .iter()
+            .position(|data| data.id == name)
+            .map(|index| sprites.iter().len() + index)
+    })
+}
+
+/// Finds the tileset's sprite, or loads its image into `loaded` under the id it will have once
+/// `loaded` is added to the registry.
+fn tileset_sprite(
+    tileset: &Tileset,
+    sprites: &SpriteRegistry,
+    loaded: &mut Vec<SpriteData>,
+) -> Result<TilesetSprite, TiledError> {
+    let id = match find_sprite(sprites, loaded, &tileset.name) {
+        Some(id) => id,
+        None => {
+            let path = tileset
+                .image
+                .as_ref()
+                .ok_or_else(|| TiledError::MissingImage(tileset.name.clone()))?;
+            let data = SpriteData::from_tileset_image(
+                &tileset.name,
+                image::open(path)?.into_rgba8(),
+                tileset.tile_width,
+                tileset.tile_height,
+            );
+            if data.frames.len() > TEXTURE_ARRAY_SIZE {
+                return Err(TiledError::TooManyTiles {
+                    tileset: tileset.name.clone(),
+                    tiles: data.frames.len(),
+                });
+            }
+            loaded.push(data);
+            sprites.iter().len() + loaded.len() - 1
+        }
+    };
+
+    let frames = sprites
+        .iter()
+        .chain(loaded.iter())
+        .nth(id)
+        .map_or(0, |data| data.frames.len().min(TEXTURE_ARRAY_SIZE));
+    Ok(TilesetSprite { id, frames })
+}
+
+fn parse_csv(data: &str) -> Result<Vec<u32>, TiledError> {
+    data.split(',')
+        .map(str::trim)
+        .filter(|gid| !gid.is_empty())
+        .map(|gid| {
+            gid.parse()
+                .map_err(|_| TiledError::InvalidNumber(gid.to_string()))
+        })
+        .collect()
+}
+
+mod json {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct JsonProperty {
+        name: String,
+        value: serde_json::Value,
+    }
+
+    #[derive(Deserialize)]
+    struct JsonTile {
+        id: u32,
+        #[serde(default)]
+        properties: Vec<JsonProperty>,
+    }
+
+    #[derive(Deserialize)]
+    struct JsonTileset {
+        #[serde(default)]
+        firstgid: u32,
+        source: Option<PathBuf>,
+        #[serde(default)]
+        name: String,
+        image: Option<PathBuf>,
+        #[serde(default)]
+        tilewidth: u32,
+        #[serde(default)]
+        tileheight: u32,
+        #[serde(default)]
+        tiles: Vec<JsonTile>,
+    }
+
+    #[derive(Deserialize)]
+    struct JsonObject {
+        #[serde(default)]
+        name: String,
+        x: f32,
+        y: f32,
+        #[serde(default)]
+        width: f32,
+        #[serde(default)]
+        height: f32,
+        #[serde(default)]
+        rotation: f32,
+        gid: Option<u32>,
+        #[serde(default)]
+        properties: Vec<JsonProperty>,
+    }
+
+    #[derive(Deserialize)]
+    struct JsonLayer {
+        #[serde(rename = "type")]
+        kind: String,
+        #[serde(default)]
+        name: String,
+        #[serde(default)]
+        width: usize,
+        data: Option<serde_json::Value>,
+        #[serde(default)]
+        offsetx: f32,
+        #[serde(default)]
+        offsety: f32,
+        #[serde(default)]
+        objects: Vec<JsonObject>,
+        #[serde(default)]
+        layers: Vec<JsonLayer>,
+        #[serde(default)]
+        properties: Vec<JsonProperty>,
+    }
+
+    #[derive(Deserialize)]
+    struct JsonMap {
+        tilewidth: u32,
+        tileheight: u32,
+        #[serde(default)]
+        tilesets: Vec<JsonTileset>,
+        #[serde(default)]
+        layers: Vec<JsonLayer>,
+        #[serde(default)]
+        properties: Vec<JsonProperty>,
+    }
+
+    pub fn parse_map(src: &str, dir: &Path) -> Result<TiledMap, TiledError> {
+        let map: JsonMap = serde_json::from_str(src)?;
+
+        let mut layers = vec![];
+        for layer in map.layers {
+            flatten(layer, &Properties::default(), Vec2::zero(), &mut layers)?;
+        }
+
+        Ok(TiledMap {
+            tile_width: map.tilewidth,
+            tile_height: map.tileheight,
+            properties: properties(map.properties),
+            tilesets: map
+                .tilesets
+                .into_iter()
+                .map(|tileset| parse_tileset(tileset, dir))
+                .collect::<Result<_, _>>()?,
+            layers,
+        })
+    }
+
+    fn parse_tileset(tileset: JsonTileset, dir: &Path) -> Result<Tileset, TiledError> {
+        if let Some(source) = tileset.source {
+            let path = dir.join(source);
+            let external: JsonTileset = serde_json::from_str(&read_to_string(&path)?)?;
+            let tileset_dir = path.parent().unwrap_or(dir);
+            return parse_tileset(
+                JsonTileset {
+                    firstgid: tileset.firstgid,
+                    ..external
+                },
+                tileset_dir,
+            );
+        }
+
+        Ok(Tileset {
+            first_gid: tileset.firstgid,
+            name: tileset.name,
+            image: tileset.image.map(|image| dir.join(image)),
+            tile_width: tileset.tilewidth,
+            tile_height: tileset.tileheight,
+            solid_tiles: tileset
+                .tiles
+                .into_iter()
+                .filter(|tile| properties_ref(&tile.properties).bool("solid") == Some(true))
+                .map(|tile| tile.id)
+                .collect(),
+        })
+    }
+
+    fn flatten(
+        layer: JsonLayer,
+        parent: &Properties,
+        parent_offset: Vec2,
+        layers: &mut Vec<Layer>,
+    ) -> Result<(), TiledError> {
+        let mut props = properties(layer.properties);
+        props.inherit(parent);
+        let offset = parent_offset + Vec2::new(layer.offsetx, layer.offsety);
+
+        match layer.kind.as_str() {
+            "tilelayer" => {
+                let data = match layer.data {
+                    Some(serde_json::Value::Array(gids)) => gids
+                        .iter()
+                        .map(|gid| {
+                            gid.as_u64()
+                                .map(|gid| gid as u32)
+                                .ok_or_else(|| TiledError::InvalidNumber(gid.to_string()))
+                        })
+                        .collect::<Result<_, _>>()?,
+                    _ => return Err(TiledError::UnsupportedEncoding(layer.name)),
+                };
+                layers.push(Layer::Tiles {
+                    name: layer.name,
+                    width: layer.width,
+                    data,
+                    offset,
+                    properties: props,
+                });
+            }
+            "objectgroup" => layers.push(Layer::Objects {
+                name: layer.name,
+                objects: layer
+                    .objects
+                    .into_iter()
+                    .map(|object| MapObject {
+                        name: object.name,
+                        x: object.x,
+                        y: object.y,
+                        width: object.width,
+                        height: object.height,
+                        rotation: object.rotation,
+                        gid: object.gid,
+                        properties: properties(object.properties),
+                    })
+                    .collect(),
+                offset,
+                properties: props,
+            }),
+            "group" => {
+                for child in layer.layers {
+                    flatten(child, &props, offset, layers)?;
+                }
+            }
+            // Image layers are not supported.
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn properties(properties: Vec<JsonProperty>) -> Properties {
+        properties_ref(&properties)
+    }
+
+    fn properties_ref(properties: &[JsonProperty]) -> Properties {
+        Properties(
+            properties
+                .iter()
+                .filter_map(|property| {
+                    let value = match &property.value {
+                        serde_json::Value::Bool(value) => Property::Bool(*value),
+                        serde_json::Value::Number(value) => {
+                            Property::Number(value.as_f64()? as f32)
+                        }
+                        serde_json::Value::String(value) => Property::String(value.clone()),
+                        _ => return None,
+                    };
+                    Some((property.name.clone(), value))
+                })
+                .collect(),
+        )
+    }
+}
+
+mod xml {
+    use super::*;
+    use roxmltree::{Document, Node};
+
+    pub fn parse_map(src: &str, dir: &Path) -> Result<TiledMap, TiledError> {
+        let doc = Document::parse(src)?;
+        let map = doc.root_element();
+
+        let mut layers = vec![];
+        flatten(map, &Properties::default(), Vec2::zero(), &mut layers)?;
+
+        Ok(TiledMap {
+            tile_width: attribute(map, "tilewidth")?,
+            tile_height: attribute(map, "tileheight")?,
+            properties: properties(map)?,
+            tilesets: elements(map, "tileset")
+                .map(|tileset| parse_tileset(tileset, attribute(tileset, "firstgid")?, dir))
+                .collect::<Result<_, _>>()?,
+            layers,
+        })
+    }
+
+    fn parse_tileset(tileset: Node, first_gid: u32, dir: &Path) -> Result<Tileset, TiledError> {
+        if let Some(source) = tileset.attribute("source") {
+            let path = dir.join(source);
+            let src = read_to_string(&path)?;
+            let doc = Document::parse(&src)?;
+            return parse_tileset(doc.root_element(), first_gid, path.parent().unwrap_or(dir));
+        }
+
+        let mut solid_tiles = HashSet::new();
+        for tile in elements(tileset, "tile") {
+            if properties(tile)?.bool("solid") == Some(true) {
+                solid_tiles.insert(attribute(tile, "id")?);
+            }
+        }
+
+        Ok(Tileset {
+            first_gid,
+            name: tileset.attribute("name").unwrap_or_default().to_string(),
+            image: elements(tileset, "image")
+                .next()
+                .and_then(|image| image.attribute("source"))
+                .map(|image| dir.join(image)),
+            tile_width: attribute(tileset, "tilewidth")?,
+            tile_height: attribute(tileset, "tileheight")?,
+            solid_tiles,
+        })
+    }
+
+    fn flatten(
+        parent: Node,
+        inherited: &Properties,
+        parent_offset: Vec2,
+        layers: &mut Vec<Layer>,
+    ) -> Result<(), TiledError> {
+        for node in parent.children().filter(Node::is_element) {
+            let mut props = properties(node)?;
+            props.inherit(inherited);
+            let name = node.attribute("name").unwrap_or_default().to_string();
+            let offset = parent_offset
+                + Vec2::new(
+                    optional(node, "offsetx", 0.0)?,
+                    optional(node, "offsety", 0.0)?,
+                );
+
+            match node.tag_name().name() {
+                "layer" => {
+                    let data = match elements(node, "data").next() {
+                        Some(data) => data,
+                        None => return Err(TiledError::MissingData(name)),
+                    };
+                    let gids = match data.attribute("encoding") {
+                        Some("csv") => parse_csv(data.text().unwrap_or_default())?,
+                        None => elements(data, "tile")
+                            .map(|tile| optional(tile, "gid", 0))
+                            .collect::<Result<_, _>>()?,
+                        Some(_) => return Err(TiledError::UnsupportedEncoding(name)),
+                    };
+                    layers.push(Layer::Tiles {
+                        name,
+                        width: attribute(node, "width")?,
+                        data: gids,
+                        offset,
+                        properties: props,
+                    });
+                }
+                "objectgroup" => layers.push(Layer::Objects {
+                    name,
+                    objects: elements(node, "object")
+                        .map(|object| {
+                            Ok(MapObject {
+                                name: object.attribute("name").unwrap_or_default().to_string(),
+                                x: attribute(object, "x")?,
+                                y: attribute(object, "y")?,
+                                width: optional(object, "width", 0.0)?,
+                                height: optional(object, "height", 0.0)?,
+                                rotation: optional(object, "rotation", 0.0)?,
+                                gid: object.attribute("gid").map(parse).transpose()?,
+                                properties: properties(object)?,
+                            })
+                        })
+                        .collect::<Result<_, TiledError>>()?,
+                    offset,
+                    properties: props,
+                }),
+                "group" => flatten(node, &props, offset, layers)?,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn properties(node: Node) -> Result<Properties, TiledError> {
+        let mut properties = HashMap::new();
+        for property in elements(node, "properties").flat_map(|node| elements(node, "property")) {
+            let value = property.attribute("value").unwrap_or_default();
+            let value = match property.attribute("type") {
+                Some("bool") => Property::Bool(value == "true"),
+                Some("float") | Some("int") => Property::Number(parse(value)?),
+                _ => Property::String(value.to_string()),
+            };
+            properties.insert(
+                property.attribute("name").unwrap_or_default().to_string(),
+                value,
+            );
+        }
+        Ok(Properties(properties))
+    }
+
+    fn elements<'a, 'input: 'a>(
+        node: Node<'a, 'input>,
+        name: &'a str,
+    ) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+        node.children()
+            .filter(move |child| child.has_tag_name(name))
+    }
+
+    fn attribute<T: std::str::FromStr>(node: Node, name: &str) -> Result<T, TiledError> {
+        match node.attribute(name) {
+            Some(value) => parse(value),
+            None => Err(TiledError::MissingAttribute {
+                element: node.tag_name().name().to_string(),
+                attribute: name.to_string(),
+            }),
+        }
+    }
+
+    fn optional<T: std::str::FromStr>(node: Node, name: &str, default: T) -> Result<T, TiledError> {
+        node.attribute(name).map_or(Ok(default), parse)
+    }
+
+    fn parse<T: std::str::FromStr>(value: &str) -> Result<T, TiledError> {
+        value
+            .trim()
+            .parse()
+            .map_err(|_| TiledError::InvalidNumber(value.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+
+    const JSON_MAP: &str = r#"{
+        "tilewidth": 16,
+        "tileheight": 16,
+        "properties": [{ "name": "depth", "type": "float", "value": 5 }],
+        "tilesets": [
+            {
+                "firstgid": 1,
+                "name": "ground",
+                "tilewidth": 16,
+                "tileheight": 16,
+                "tiles": [
+                    { "id": 1, "properties": [{ "name": "solid", "type": "bool", "value": true }] }
+                ]
+            },
+            { "firstgid": 5, "name": "props", "tilewidth": 16, "tileheight": 16 }
+        ],
+        "layers": [
+            {
+                "type": "group",
+                "name": "front",
+                "offsetx": 32,
+                "offsety": 16,
+                "properties": [{ "name": "depth", "type": "float", "value": 2 }],
+                "layers": [
+                    {
+                        "type": "tilelayer",
+                        "name": "terrain",
+                        "width": 3,
+                        "height": 2,
+                        "data": [1, 2, 0, 2147483650, 0, 1073741829]
+                    }
+                ]
+            },
+            {
+                "type": "objectgroup",
+                "name": "things",
+                "objects": [
+                    { "name": "crate", "x": 64, "y": 32, "width": 16, "height": 16, "gid": 6 }
+                ]
+            }
+        ]
+    }"#;
+
+    const TMX_MAP: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<map version="1.4" orientation="orthogonal" width="3" height="2" tilewidth="16" tileheight="16">
+ <properties>
+  <property name="depth" type="float" value="5"/>
+ </properties>
+ <tileset firstgid="1" name="ground" tilewidth="16" tileheight="16" tilecount="4" columns="2">
+  <tile id="1">
+   <properties>
+    <property name="solid" type="bool" value="true"/>
+   </properties>
+  </tile>
+ </tileset>
+ <tileset firstgid="5" name="props" tilewidth="16" tileheight="16" tilecount="4" columns="2"/>
+ <group name="front" offsetx="32" offsety="16">
+  <properties>
+   <property name="depth" type="float" value="2"/>
+  </properties>
+  <layer name="terrain" width="3" height="2">
+   <data encoding="csv">
+1,2,0,
+2147483650,0,1073741829
+</data>
+  </layer>
+ </group>
+ <objectgroup name="things">
+  <object id="1" name="crate" gid="6" x="64" y="32" width="16" height="16"/>
+ </objectgroup>
+</map>"#;
+
+    const TILESET_SPRITES: [TilesetSprite; 2] = [
+        TilesetSprite { id: 0, frames: 4 },
+        TilesetSprite { id: 1, frames: 4 },
+    ];
+
+    fn tile(frame: u8, solid: bool, flip_x: bool, flip_y: bool) -> Option<Tile> {
+        Some(Tile {
+            frame,
+            solid,
+            flip_x,
+            flip_y,
+        })
+    }
+
+    /// Both example maps describe the same level.
+    fn check_example(map: &TiledMap) {
+        assert_eq!((map.tile_width, map.tile_height), (16, 16));
+        assert_eq!(map.tilesets.len(), 2);
+        assert_eq!(map.tilesets[1].first_gid, 5);
+        assert_eq!(map.layers.len(), 2);
+
+        match &map.layers[0] {
+            Layer::Tiles {
+                name,
+                width,
+                data,
+                offset,
+                properties,
+            } => {
+                assert_eq!(name, "terrain");
+                assert_eq!(*offset, Vec2::new(32.0, 16.0));
+                assert_eq!(map.depth(properties, 0.0), 2.0);
+
+                let tilemaps = map
+                    .tilemaps(*width, data, properties, &TILESET_SPRITES)
+                    .unwrap();
+                assert_eq!(tilemaps.len(), 2);
+                assert_eq!(
+                    tilemaps[0].tiles(),
+                    &[
+                        tile(0, false, false, false),
+                        tile(1, true, false, false),
+                        None,
+                        tile(1, true, true, false),
+                        None,
+                        None,
+                    ]
+                );
+                assert_eq!(tilemaps[1].sprite(), 1);
+                assert_eq!(tilemaps[1].get(2, 1), tile(0, false, false, true));
+                assert_eq!(tilemaps[0].tile_size(), Vec2::splat(0.5));
+            }
+            layer => panic!("expected a tile layer, found {:?}", layer),
+        }
+
+        match &map.layers[1] {
+            Layer::Objects {
+                objects,
+                properties,
+                ..
+            } => {
+                assert_eq!(map.depth(properties, 0.0), 5.0);
+                let sprite = map
+                    .object_sprite(&SpriteRegistry::new(), &[], &TILESET_SPRITES, &objects[0])
+                    .unwrap()
+                    .unwrap();
+                assert_eq!((sprite.id(), sprite.anim_frame_index), (1, 1));
+            }
+            layer => panic!("expected an object layer, found {:?}", layer),
+        }
+    }
+
+    #[test]
+    fn parses_json_maps() {
+        check_example(&json::parse_map(JSON_MAP, Path::new("")).unwrap());
+    }
+
+    #[test]
+    fn parses_tmx_maps() {
+        check_example(&xml::parse_map(TMX_MAP, Path::new("")).unwrap());
+    }
+
+    #[test]
+    fn tiles_past_the_end_of_a_tileset_are_errors() {
+        let map = json::parse_map(&JSON_MAP.replace("1073741829", "9"), Path::new("")).unwrap();
+        match &map.layers[0] {
+            Layer::Tiles { width, data, .. } => {
+                let result = map.tilemaps(*width, data, &Properties::default(), &TILESET_SPRITES);
+                match result {
+                    Err(TiledError::UnknownTile { tileset, id }) => {
+                        assert_eq!((tileset.as_str(), id), ("props", 4))
+                    }
+                    result => panic!("expected an unknown tile, found {:?}", result.err()),
+                }
+            }
+            layer => panic!("expected a tile layer, found {:?}", layer),
+        }
+    }
+
+    #[test]
+    fn malformed_maps_are_errors() {
+        let base64 = JSON_MAP.replace(
+            "[1, 2, 0, 2147483650, 0, 1073741829]",
+            r#""AQAAAA==", "encoding": "base64""#,
+        );
+        assert!(matches!(
+            json::parse_map(&base64, Path::new("")),
+            Err(TiledError::UnsupportedEncoding(layer)) if layer == "terrain"
+        ));
+
+        let no_height = TMX_MAP.replacen(r#" tileheight="16">"#, ">", 1);
+        assert!(matches!(
+            xml::parse_map(&no_height, Path::new("")),
+            Err(TiledError::MissingAttribute { element, attribute })
+                if element == "map" && attribute == "tileheight"
+        ));
+
+        let bad_gid = TMX_MAP.replace("2147483650", "two");
+        assert!(matches!(
+            xml::parse_map(&bad_gid, Path::new("")),
+            Err(TiledError::InvalidNumber(value)) if value == "two"
+        ));
+    }
+
+    fn tileset_data(name: &str) -> SpriteData {
+        SpriteData {
+            id: name.to_string(),
+            frames: vec![RgbaImage::new(16, 16); 4],
+            pivots: vec![Vec2::splat(0.5); 4],
+            font: None,
+        }
+    }
+
+    fn count<T: crate::Component>(game: &mut Game) -> usize {
+        game.world.query::<&T>().iter(&game.world).count()
+    }
+
+    #[test]
+    fn spawns_layers_and_objects() {
+        let map = json::parse_map(JSON_MAP, Path::new("")).unwrap();
+        let mut sprites = SpriteRegistry::new();
+        sprites.insert(tileset_data("ground"));
+        sprites.insert(tileset_data("props"));
+        let mut game = Game::new();
+
+        map.spawn(&mut game, &mut sprites, 0.0).unwrap();
+
+        let mut tilemaps = game.world.query::<(&Position, &Tilemap)>();
+        let positions: Vec<Vec3> = tilemaps
+            .iter(&game.world)
+            .map(|(position, _)| position.0)
+            .collect();
+        assert_eq!(positions, vec![Vec3::new(1.0, -0.5, 2.0); 2]);
+
+        let mut objects = game.world.query::<(&Position, &Sprite, &Terrain)>();
+        let (position, sprite, _) = objects.iter(&game.world).next().unwrap();
+        assert_eq!(position.0, Vec3::new(2.25, -0.75, 5.0));
+        assert_eq!(sprite.anim_frame_index, 1);
+    }
+
+    #[test]
+    fn invalid_maps_spawn_nothing() {
+        let map = json::parse_map(
+            &JSON_MAP.replace(
+                r#""gid": 6"#,
+                r#""properties": [{ "name": "sprite", "type": "string", "value": "missing" }]"#,
+            ),
+            Path::new(""),
+        )
+        .unwrap();
+        let mut sprites = SpriteRegistry::new();
+        sprites.insert(tileset_data("ground"));
+        sprites.insert(tileset_data("props"));
+        let mut game = Game::new();
+
+        assert!(matches!(
+            map.spawn(&mut game, &mut sprites, 0.0),
+            Err(TiledError::UnknownSprite { object, sprite })
+                if object == "crate" && sprite == "missing"
+        ));
+        assert_eq!(count::<Tilemap>(&mut game), 0);
+        assert_eq!(count::<Terrain>(&mut game), 0);
+    }
+
+    #[test]
+    fn tilesets_with_too_many_tiles_are_errors() {
+        let image = std::env::temp_dir().join("erlking_tiled_too_many_tiles.png");
+        let tiles = TEXTURE_ARRAY_SIZE as u32 + 1;
+        RgbaImage::new(16, 16 * tiles).save(&image).unwrap();
+        let tileset = Tileset {
+            first_gid: 1,
+            name: "tall".to_string(),
+            image: Some(image),
+            tile_width: 16,
+            tile_height: 16,
+            solid_tiles: HashSet::new(),
+        };
+
+        let mut loaded = vec![];
+        let result = tileset_sprite(&tileset, &SpriteRegistry::new(), &mut loaded);
+        assert!(matches!(
+            result,
+            Err(TiledError::TooManyTiles { tiles, .. }) if tiles == TEXTURE_ARRAY_SIZE + 1
+        ));
+        assert!(loaded.is_empty());
+    }
+
+    #[test]
+    fn tilesets_are_only_registered_once_the_map_is_valid() {
+        let image = std::env::temp_dir().join("erlking_tiled_props.png");
+        RgbaImage::new(32, 32).save(&image).unwrap();
+        let with_image = JSON_MAP.replace(
+            r#""name": "props","#,
+            &format!(r#""name": "props", "image": {:?},"#, image),
+        );
+        let mut sprites = SpriteRegistry::new();
+        sprites.insert(tileset_data("ground"));
+        let mut game = Game::new();
+
+        let invalid = json::parse_map(
+            &with_image.replace(
+                r#""gid": 6"#,
+                r#""properties": [{ "name": "sprite", "type": "string", "value": "missing" }]"#,
+            ),
+            Path::new(""),
+        )
+        .unwrap();
+        assert!(invalid.spawn(&mut game, &mut sprites, 0.0).is_err());
+        assert_eq!(sprites.iter().len(), 1);
+
+        let valid = json::parse_map(&with_image, Path::new("")).unwrap();
+        valid.spawn(&mut game, &mut sprites, 0.0).unwrap();
+        assert_eq!(sprites.find("props"), Some(1));
+        let mut objects = game.world.query::<(&Sprite, &Terrain)>();
+        let (sprite, _) = objects.iter(&game.world).next().unwrap();
+        assert_eq!((sprite.id(), sprite.anim_frame_index), (1, 1));
+    }
+}
@@ -0,0 +1,544 @@
+use crate::asset::{SpriteCatalog, SpriteId};
+use crate::renderer::gpu_primitives::{Instance, InstanceRaw};
+use crate::renderer::sprite::PIXELS_PER_METRE;
+use crate::transform::GlobalTransform;
+use crate::{Position, Scale};
+use bevy_ecs::prelude::{Query, Res};
+use glam::{Quat, Vec2, Vec3};
+use parry2d::na::Vector2;
+use parry2d::shape::Cuboid;
+use serde::{Deserialize, Serialize};
+
+/// Width and height of a chunk in tiles.
+pub const CHUNK_SIZE: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Tile {
+    /// Frame of the tileset sprite to draw.
+    pub frame: u8,
+    pub solid: bool,
+    /// Mirror the frame horizontally.
+    #[serde(default)]
+    pub flip_x: bool,
+    /// Mirror the frame vertically.
+    #[serde(default)]
+    pub flip_y: bool,
+}
+
+/// A merged collider covering a rectangle of solid tiles, relative to the tilemap position.
+#[derive(Clone, Copy, Debug)]
+pub struct TileCollider {
+    pub centre: Vec2,
+    pub shape: Cuboid,
+}
+
+impl TileCollider {
+    /// World space centre and shape of the collider on a tilemap placed at `transform`.
+    pub fn placed(&self, transform: &GlobalTransform) -> (Vec3, Cuboid) {
+        let scale = transform.scale.truncate();
+        let half_extents = self.shape.half_extents;
+        let centre = transform.position + (self.centre * scale).extend(0.0);
+        let shape = Cuboid::new(Vector2::new(
+            half_extents.x * scale.x.abs(),
+            half_extents.y * scale.y.abs(),
+        ));
+        (centre, shape)
+    }
+}
+
+struct Chunk {
+    dirty: bool,
+    origin: Vec3,
+    /// Scale of the whole map, from its `Scale` and parents.
+    map_scale: Vec2,
+    /// Scale of each tile's quad, which stretches the tileset frames over `tile_size`.
+    scale: Vec2,
+    /// A unit quad stretched over the whole chunk, for testing if any of it can be seen.
+    bounds: InstanceRaw,
+    instances: Vec<InstanceRaw>,
+}
+
+/// A grid of tiles drawn from the frames of one tileset sprite. The entity's `Position` is the
+/// top left corner of the map and rows run downwards. `Scale` and the transforms of parents
+/// stretch the map, but it is not rotated so that its colliders stay axis aligned.
+///
+/// Tiles are drawn in chunks of [`CHUNK_SIZE`] that are only rebuilt when one of their tiles
+/// changes, and chunks outside every camera's view are skipped as a whole. Frames of the tileset
+/// are stretched to `tile_size`. Solid tiles are merged into as few rectangular colliders as
+/// possible.
+pub struct Tilemap {
+    sprite: SpriteId,
+    width: usize,
+    height: usize,
+    tile_size: Vec2,
+    tiles: Vec<Option<Tile>>,
+    chunks: Vec<Chunk>,
+    colliders: Vec<TileCollider>,
+    colliders_dirty: bool,
+}
+
+impl Tilemap {
+    pub fn new(sprite: SpriteId, width: usize, height: usize, tile_size: Vec2) -> Self {
+        Self::from_tiles(sprite, width, vec![None; width * height], tile_size)
+    }
+
+    /// `tiles` is row major, starting at the top left.
+    pub fn from_tiles(
+        sprite: SpriteId,
+        width: usize,
+        tiles: Vec<Option<Tile>>,
+        tile_size: Vec2,
+    ) -> Self {
+        assert!(
+            width > 0 && tiles.len().is_multiple_of(width),
+            "tilemap must be a whole number of rows"
+        );
+        let height = tiles.len() / width;
+        let chunk_count = chunks_along(width) * chunks_along(height);
+
+        let mut tilemap = Tilemap {
+            sprite,
+            width,
+            height,
+            tile_size,
+            tiles,
+            chunks: (0..chunk_count)
+                .map(|_| Chunk {
+                    dirty: true,
+                    origin: Vec3::zero(),
+                    map_scale: Vec2::one(),
+                    scale: Vec2::one(),
+                    bounds: InstanceRaw::from(Instance {
+                        position: Vec3::zero(),
+                        rotation: Quat::identity(),
+                        scale: Vec3::zero(),
+                        frame_id: 0,
+                        flip_x: false,
+                        flip_y: false,
+                        pivot_offset: Vec2::zero(),
+                    }),
+                    instances: vec![],
+                })
+                .collect(),
+            colliders: vec![],
+            colliders_dirty: true,
+        };
+        tilemap.rebuild_colliders();
+        tilemap
+    }
+
+    pub fn sprite(&self) -> SpriteId {
+        self.sprite
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn tile_size(&self) -> Vec2 {
+        self.tile_size
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<Tile> {
+        if x < self.width && y < self.height {
+            self.tiles[y * self.width + x]
+        } else {
+            None
+        }
+    }
+
+    /// Changes are drawn and collided with once [`update_tilemaps`] has run.
+    pub fn set(&mut self, x: usize, y: usize, tile: Option<Tile>) {
+        assert!(x < self.width && y < self.height, "tile out of bounds");
+        self.tiles[y * self.width + x] = tile;
+        let chunk = (y / CHUNK_SIZE) * chunks_along(self.width) + x / CHUNK_SIZE;
+        self.chunks[chunk].dirty = true;
+        self.colliders_dirty = true;
+    }
+
+    /// Row major, starting at the top left.
+    pub fn tiles(&self) -> &[Option<Tile>] {
+        &self.tiles
+    }
+
+    pub fn colliders(&self) -> &[TileCollider] {
+        &self.colliders
+    }
+
+    /// Centre of a tile relative to the tilemap position.
+    pub fn tile_centre(&self, x: usize, y: usize) -> Vec2 {
+        Vec2::new(
+            (x as f32 + 0.5) * self.tile_size.x,
+            -(y as f32 + 0.5) * self.tile_size.y,
+        )
+    }
+
+    /// Each chunk's tile instances, with a unit quad instance covering the chunk.
+    pub(crate) fn chunks(&self) -> impl Iterator<Item = (&InstanceRaw, &[InstanceRaw])> {
+        self.chunks
+            .iter()
+            .map(|chunk| (&chunk.bounds, chunk.instances.as_slice()))
+    }
+
+    /// Rebuilds the instances of chunks with changed tiles, or of every chunk when the map has
+    /// moved, has been stretched by a different `map_scale` or its frames are drawn at a
+    /// different `scale`.
+    fn rebuild_chunks(&mut self, origin: Vec3, map_scale: Vec2, scale: Vec2) {
+        let chunks_x = chunks_along(self.width);
+
+        for index in 0..self.chunks.len() {
+            let chunk = &self.chunks[index];
+            if !chunk.dirty
+                && chunk.origin == origin
+                && chunk.map_scale == map_scale
+                && chunk.scale == scale
+            {
+                continue;
+            }
+
+            let (chunk_x, chunk_y) = (index % chunks_x, index / chunks_x);
+            let columns = chunk_x * CHUNK_SIZE..((chunk_x + 1) * CHUNK_SIZE).min(self.width);
+            let rows = chunk_y * CHUNK_SIZE..((chunk_y + 1) * CHUNK_SIZE).min(self.height);
+            let mut instances = vec![];
+
+            for y in rows.clone() {
+                for x in columns.clone() {
+                    if let Some(tile) = self.get(x, y) {
+                        let centre = self.tile_centre(x, y) * map_scale;
+                        instances.push(InstanceRaw::from(Instance {
+                            position: origin + centre.extend(0.0),
+                            rotation: Quat::identity(),
+                            scale: (scale * map_scale).extend(1.0),
+                            frame_id: tile.frame,
+                            flip_x: tile.flip_x,
+                            flip_y: tile.flip_y,
+                            pivot_offset: Vec2::zero(),
+                        }));
+                    }
+                }
+            }
+
+            let tiles = Vec2::new(columns.len() as f32, rows.len() as f32);
+            let top_left = Vec2::new(columns.start as f32, -(rows.start as f32)) * self.tile_size;
+            let centre = top_left + Vec2::new(tiles.x, -tiles.y) * self.tile_size / 2.0;
+            let bounds = InstanceRaw::from(Instance {
+                position: origin + (centre * map_scale).extend(0.0),
+                rotation: Quat::identity(),
+                scale: (tiles * self.tile_size * map_scale).extend(1.0),
+                frame_id: 0,
+                flip_x: false,
+                flip_y: false,
+                pivot_offset: Vec2::zero(),
+            });
+
+            let chunk = &mut self.chunks[index];
+            chunk.instances = instances;
+            chunk.bounds = bounds;
+            chunk.origin = origin;
+            chunk.map_scale = map_scale;
+            chunk.scale = scale;
+            chunk.dirty = false;
+        }
+    }
+
+    /// Greedily merges solid tiles, first into horizontal runs and then into rectangles by
+    /// growing each run downwards while the rows below are solid across the same span.
+    fn rebuild_colliders(&mut self) {
+        let solid = |x: usize, y: usize| self.get(x, y).map(|t| t.solid).unwrap_or(false);
+        let mut merged = vec![false; self.width * self.height];
+        let mut colliders = vec![];
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if merged[y * self.width + x] || !solid(x, y) {
+                    continue;
+                }
+
+                let mut x_end = x;
+                while x_end + 1 < self.width
+                    && solid(x_end + 1, y)
+                    && !merged[y * self.width + x_end + 1]
+                {
+                    x_end += 1;
+                }
+
+                let mut y_end = y;
+                while y_end + 1 < self.height
+                    && (x..=x_end)
+                        .all(|i| solid(i, y_end + 1) && !merged[(y_end + 1) * self.width + i])
+                {
+                    y_end += 1;
+                }
+
+                for j in y..=y_end {
+                    for i in x..=x_end {
+                        merged[j * self.width + i] = true;
+                    }
+                }
+
+                let columns = (x_end - x + 1) as f32;
+                let rows = (y_end - y + 1) as f32;
+                colliders.push(TileCollider {
+                    centre: Vec2::new(
+                        (x as f32 + columns / 2.0) * self.tile_size.x,
+                        -(y as f32 + rows / 2.0) * self.tile_size.y,
+                    ),
+                    shape: Cuboid::new(Vector2::new(
+                        columns * self.tile_size.x / 2.0,
+                        rows * self.tile_size.y / 2.0,
+                    )),
+                });
+            }
+        }
+
+        self.colliders = colliders;
+        self.colliders_dirty = false;
+    }
+}
+
+fn chunks_along(tiles: usize) -> usize {
+    tiles.div_ceil(CHUNK_SIZE)
+}
+
+pub fn update_tilemaps(
+    catalog: Option<Res<SpriteCatalog>>,
+    mut query: Query<(
+        &Position,
+        Option<&Scale>,
+        Option<&GlobalTransform>,
+        &mut Tilemap,
+    )>,
+) {
+    for (pos, map_scale, global, mut tilemap) in query.iter_mut() {
+        let global = global
+            .copied()
+            .unwrap_or_else(|| GlobalTransform::from_local(pos, None, map_scale));
+        // Without the tileset's size its frames are drawn at their own size.
+        let scale = catalog
+            .as_ref()
+            .and_then(|catalog| catalog.get(tilemap.sprite))
+            .filter(|info| info.width > 0 && info.height > 0)
+            .map(|info| {
+                let frame = Vec2::new(info.width as f32, info.height as f32);
+                tilemap.tile_size * PIXELS_PER_METRE as f32 / frame
+            })
+            .unwrap_or_else(Vec2::one);
+        tilemap.rebuild_chunks(global.position, global.scale.truncate(), scale);
+        if tilemap.colliders_dirty {
+            tilemap.rebuild_colliders();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asset::SpriteInfo;
+    use bevy_ecs::prelude::{IntoSystem, Stage, SystemStage, World};
+
+    fn ground(frame: u8) -> Option<Tile> {
+        Some(Tile {
+            frame,
+            solid: true,
+            flip_x: false,
+            flip_y: false,
+        })
+    }
+
+    fn scale_of(instance: &InstanceRaw) -> Vec2 {
+        let model = instance.model();
+        Vec2::new(model.x_axis.x, model.y_axis.y)
+    }
+
+    #[test]
+    fn only_dirty_chunks_are_rebuilt() {
+        let mut tilemap = Tilemap::from_tiles(0, 40, vec![ground(0); 40 * 20], Vec2::one());
+        tilemap.rebuild_chunks(Vec3::zero(), Vec2::one(), Vec2::one());
+        assert!(tilemap.chunks.iter().all(|chunk| !chunk.dirty));
+        let before: Vec<Vec<u32>> = tilemap
+            .chunks
+            .iter()
+            .map(|chunk| chunk.instances.iter().map(|i| i.frame_id()).collect())
+            .collect();
+
+        // (20, 3) is in the second chunk across on the first row of chunks.
+        tilemap.set(20, 3, ground(7));
+        let dirty: Vec<usize> = (0..tilemap.chunks.len())
+            .filter(|i| tilemap.chunks[*i].dirty)
+            .collect();
+        assert_eq!(dirty, vec![1]);
+
+        tilemap.rebuild_chunks(Vec3::zero(), Vec2::one(), Vec2::one());
+        for (index, chunk) in tilemap.chunks.iter().enumerate() {
+            let frames: Vec<u32> = chunk.instances.iter().map(|i| i.frame_id()).collect();
+            if index == 1 {
+                assert_eq!(frames.iter().filter(|frame| **frame == 7).count(), 1);
+            } else {
+                assert_eq!(frames, before[index]);
+            }
+        }
+    }
+
+    #[test]
+    fn moving_or_rescaling_rebuilds_every_chunk() {
+        let mut tilemap = Tilemap::from_tiles(0, 20, vec![ground(0); 20 * 20], Vec2::one());
+        tilemap.rebuild_chunks(Vec3::zero(), Vec2::one(), Vec2::one());
+
+        let origin = Vec3::new(3.0, -2.0, 1.0);
+        tilemap.rebuild_chunks(origin, Vec2::one(), Vec2::splat(2.0));
+        assert!(tilemap.chunks.iter().all(|chunk| chunk.origin == origin));
+        for (_, instances) in tilemap.chunks() {
+            assert!(instances.iter().all(|i| scale_of(i) == Vec2::splat(2.0)));
+        }
+        let first = tilemap.chunks[0].instances[0].model().w_axis.truncate();
+        assert_eq!(first, origin + tilemap.tile_centre(0, 0).extend(0.0));
+    }
+
+    #[test]
+    fn flipped_tiles_are_drawn_flipped() {
+        let flipped = Some(Tile {
+            frame: 2,
+            solid: false,
+            flip_x: true,
+            flip_y: false,
+        });
+        let mut tilemap = Tilemap::from_tiles(0, 2, vec![flipped, ground(0)], Vec2::one());
+        tilemap.rebuild_chunks(Vec3::zero(), Vec2::one(), Vec2::one());
+
+        let flips: Vec<(bool, bool)> = tilemap.chunks[0]
+            .instances
+            .iter()
+            .map(|i| i.flip())
+            .collect();
+        assert_eq!(flips, vec![(true, false), (false, false)]);
+    }
+
+    #[test]
+    fn tiles_are_stretched_to_tile_size() {
+        let mut world = World::default();
+        // A 16 x 8 pixel frame is 0.5 x 0.25 metres.
+        world.insert_resource(SpriteCatalog(vec![SpriteInfo {
+            id: "tiles".to_string(),
+            width: 16,
+            height: 8,
+            pivots: vec![],
+            font: None,
+        }]));
+        let tilemap = Tilemap::from_tiles(0, 2, vec![ground(0); 4], Vec2::new(1.0, 0.5));
+        let entity = world
+            .spawn()
+            .insert_bundle((Position(Vec3::zero()), tilemap))
+            .id();
+
+        let mut stage = SystemStage::single_threaded();
+        stage.add_system(update_tilemaps.system());
+        stage.run(&mut world);
+
+        let tilemap = world.get::<Tilemap>(entity).unwrap();
+        for (bounds, instances) in tilemap.chunks() {
+            assert_eq!(scale_of(bounds), Vec2::new(2.0, 1.0));
+            assert!(instances.iter().all(|i| scale_of(i) == Vec2::new(2.0, 2.0)));
+        }
+    }
+
+    #[test]
+    fn scaled_maps_stretch_their_tiles_and_colliders() {
+        let mut world = World::default();
+        let tilemap = Tilemap::from_tiles(0, 2, vec![ground(0); 2], Vec2::one());
+        let entity = world
+            .spawn()
+            .insert_bundle((
+                Position(Vec3::new(1.0, 0.0, 0.0)),
+                Scale(Vec3::new(2.0, 3.0, 1.0)),
+                tilemap,
+            ))
+            .id();
+
+        let mut stage = SystemStage::single_threaded();
+        stage.add_system(update_tilemaps.system());
+        stage.run(&mut world);
+
+        let tilemap = world.get::<Tilemap>(entity).unwrap();
+        let first = &tilemap.chunks[0].instances[0];
+        assert_eq!(first.model().w_axis.truncate(), Vec3::new(2.0, -1.5, 0.0));
+        assert_eq!(scale_of(first), Vec2::new(2.0, 3.0));
+
+        let transform = GlobalTransform {
+            position: Vec3::new(1.0, 0.0, 0.0),
+            rotation: Quat::identity(),
+            scale: Vec3::new(2.0, 3.0, 1.0),
+        };
+        let (centre, shape) = tilemap.colliders()[0].placed(&transform);
+        assert_eq!(centre, Vec3::new(3.0, -1.5, 0.0));
+        assert_eq!(shape.half_extents, Vector2::new(2.0, 1.5));
+    }
+
+    #[test]
+    fn chunk_bounds_cover_their_tiles() {
+        let mut tilemap = Tilemap::from_tiles(0, 20, vec![None; 20 * 4], Vec2::new(0.5, 1.0));
+        tilemap.rebuild_chunks(Vec3::new(1.0, 1.0, 0.0), Vec2::one(), Vec2::one());
+
+        // The second chunk across holds columns 16 to 19.
+        let bounds = tilemap.chunks[1].bounds.model();
+        assert_eq!(
+            bounds.w_axis.truncate(),
+            Vec3::new(1.0 + 9.0, 1.0 - 2.0, 0.0)
+        );
+        assert_eq!(
+            Vec2::new(bounds.x_axis.x, bounds.y_axis.y),
+            Vec2::new(2.0, 4.0)
+        );
+    }
+
+    #[test]
+    fn solid_tiles_merge_into_rectangles() {
+        let (o, x) = (None, ground(0));
+        #[rustfmt::skip]
+        let tiles = vec![
+            x, x, x, o,
+            x, x, x, o,
+            o, o, o, o,
+            x, o, x, x,
+        ];
+        let tilemap = Tilemap::from_tiles(0, 4, tiles, Vec2::splat(2.0));
+        let colliders: Vec<(Vec2, Vec2)> = tilemap
+            .colliders()
+            .iter()
+            .map(|c| {
+                let half = c.shape.half_extents;
+                (c.centre, Vec2::new(half.x, half.y))
+            })
+            .collect();
+
+        assert_eq!(
+            colliders,
+            vec![
+                (Vec2::new(3.0, -2.0), Vec2::new(3.0, 2.0)),
+                (Vec2::new(1.0, -7.0), Vec2::new(1.0, 1.0)),
+                (Vec2::new(6.0, -7.0), Vec2::new(2.0, 1.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn non_solid_tiles_and_edits_update_colliders() {
+        let decoration = Some(Tile {
+            frame: 1,
+            solid: false,
+            flip_x: false,
+            flip_y: false,
+        });
+        let mut tilemap = Tilemap::from_tiles(0, 3, vec![decoration; 3], Vec2::one());
+        assert!(tilemap.colliders().is_empty());
+
+        tilemap.set(1, 0, ground(0));
+        assert!(tilemap.colliders_dirty);
+        tilemap.rebuild_colliders();
+        assert_eq!(tilemap.colliders().len(), 1);
+        assert_eq!(tilemap.colliders()[0].centre, Vec2::new(1.5, -0.5));
+    }
+}
@@ -3,6 +3,7 @@ use std::time::{Duration, Instant};
 pub struct Timer {
     tick: Instant,
     elapsed: Duration,
+    fixed_step: Option<Duration>,
 }
 
 impl Timer {
@@ -10,15 +11,44 @@ impl Timer {
         Timer {
             tick: Instant::now(),
             elapsed: Duration::from_secs(0),
+            fixed_step: None,
+        }
+    }
+    /// A timer whose last frame took `elapsed`, ticking from now.
+    pub fn from_elapsed(elapsed: Duration) -> Self {
+        Timer {
+            tick: Instant::now(),
+            elapsed,
+            fixed_step: None,
         }
     }
     pub fn tick(&mut self) {
-        let tock = Instant::now();
-        let elapsed = tock.duration_since(self.tick);
+        match self.fixed_step {
+            Some(step) => self.advance(step),
+            None => {
+                let tock = Instant::now();
+                let elapsed = tock.duration_since(self.tick);
 
-        self.tick = tock;
+                self.tick = tock;
+                self.elapsed = elapsed;
+            }
+        }
+    }
+    /// Moves the clock forward by `elapsed` regardless of how much real time has passed.
+    pub fn advance(&mut self, elapsed: Duration) {
+        self.tick += elapsed;
         self.elapsed = elapsed;
     }
+    /// Makes every tick take exactly `step`, or follow the wall clock again when `None`.
+    pub fn set_fixed_step(&mut self, step: Option<Duration>) {
+        if step.is_none() && self.fixed_step.is_some() {
+            self.tick = Instant::now();
+        }
+        self.fixed_step = step;
+    }
+    pub fn fixed_step(&self) -> Option<Duration> {
+        self.fixed_step
+    }
     pub fn elapsed(&self) -> Duration {
         self.elapsed
     }
@@ -0,0 +1,234 @@
+use crate::{Position, Rotation, Scale};
+use bevy_ecs::entity::{Entity, EntityMap, MapEntities, MapEntitiesError};
+use bevy_ecs::prelude::{Query, With, Without};
+use glam::{Quat, Vec3};
+use serde::{Deserialize, Serialize};
+
+/// Makes the entity's `Position`, `Rotation` and `Scale` relative to another entity.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Parent(#[serde(with = "crate::serialization::entity_id")] pub Entity);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Children(#[serde(with = "crate::serialization::entity_ids")] pub Vec<Entity>);
+
+impl MapEntities for Parent {
+    fn map_entities(&mut self, entity_map: &EntityMap) -> Result<(), MapEntitiesError> {
+        self.0 = entity_map.get(self.0)?;
+        Ok(())
+    }
+}
+
+/// Children that no longer exist are dropped rather than stopping their parent being saved.
+impl MapEntities for Children {
+    fn map_entities(&mut self, entity_map: &EntityMap) -> Result<(), MapEntitiesError> {
+        self.0 = self
+            .0
+            .iter()
+            .filter_map(|child| entity_map.get(*child).ok())
+            .collect();
+        Ok(())
+    }
+}
+
+/// World space transform of an entity that is part of a hierarchy. Entities without one are
+/// drawn directly from their `Position`, `Rotation` and `Scale`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GlobalTransform {
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl GlobalTransform {
+    pub fn identity() -> Self {
+        GlobalTransform {
+            position: Vec3::zero(),
+            rotation: Quat::identity(),
+            scale: Vec3::one(),
+        }
+    }
+
+    pub fn from_local(pos: &Position, rot: Option<&Rotation>, scale: Option<&Scale>) -> Self {
+        GlobalTransform {
+            position: pos.0,
+            rotation: rot.map(|rot| rot.0).unwrap_or_else(Quat::identity),
+            scale: scale.map(|scale| scale.0).unwrap_or_else(Vec3::one),
+        }
+    }
+
+    /// Places `local`, given relative to this transform, in world space.
+    pub fn mul_transform(&self, local: GlobalTransform) -> Self {
+        GlobalTransform {
+            position: self.position + self.rotation * (self.scale * local.position),
+            rotation: self.rotation * local.rotation,
+            scale: self.scale * local.scale,
+        }
+    }
+}
+
+impl Default for GlobalTransform {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+type LocalTransform<'a> = (
+    Option<&'a Position>,
+    Option<&'a Rotation>,
+    Option<&'a Scale>,
+    &'a mut GlobalTransform,
+);
+
+/// Entities without a `Position`, eg. ones that only group others, are placed at the origin of
+/// their parent so their children are still placed.
+fn local_transform(
+    pos: Option<&Position>,
+    rot: Option<&Rotation>,
+    scale: Option<&Scale>,
+) -> GlobalTransform {
+    let origin = Position(Vec3::zero());
+    GlobalTransform::from_local(pos.unwrap_or(&origin), rot, scale)
+}
+
+pub fn propagate_transforms(
+    mut root_query: Query<(LocalTransform, Option<&Children>), Without<Parent>>,
+    mut child_query: Query<LocalTransform, With<Parent>>,
+    children_query: Query<Option<&Children>, With<Parent>>,
+) {
+    for ((pos, rot, scale, mut global), children) in root_query.iter_mut() {
+        *global = local_transform(pos, rot, scale);
+
+        if let Some(children) = children {
+            for child in children.0.iter() {
+                propagate_recursive(&global, &mut child_query, &children_query, *child);
+            }
+        }
+    }
+}
+
+fn propagate_recursive(
+    parent: &GlobalTransform,
+    child_query: &mut Query<LocalTransform, With<Parent>>,
+    children_query: &Query<Option<&Children>, With<Parent>>,
+    entity: Entity,
+) {
+    // Children that have been despawned are skipped.
+    let global = match child_query.get_mut(entity) {
+        Ok((pos, rot, scale, mut global)) => {
+            *global = parent.mul_transform(local_transform(pos, rot, scale));
+            *global
+        }
+        Err(_) => return,
+    };
+
+    if let Ok(Some(children)) = children_query.get(entity) {
+        for child in children.0.iter() {
+            propagate_recursive(&global, child_query, children_query, *child);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Game;
+
+    fn transform(position: Vec3, rotation: Quat, scale: Vec3) -> (Position, Rotation, Scale) {
+        (Position(position), Rotation(rotation), Scale(scale))
+    }
+
+    fn assert_near(actual: &GlobalTransform, expected: &GlobalTransform) {
+        let near = (actual.position - expected.position).abs().max_element() < 1e-5
+            && actual.rotation.abs_diff_eq(expected.rotation, 1e-5)
+            && (actual.scale - expected.scale).abs().max_element() < 1e-5;
+        assert!(near, "{:?} is not {:?}", actual, expected);
+    }
+
+    #[test]
+    fn children_are_placed_relative_to_their_parents() {
+        let mut game = Game::new();
+        let quarter_turn = Quat::from_rotation_z(std::f32::consts::FRAC_PI_2);
+        let parent = game.spawn(transform(
+            Vec3::new(1.0, 2.0, 10.0),
+            quarter_turn,
+            Vec3::new(2.0, 2.0, 1.0),
+        ));
+        let child = game.spawn_child(
+            parent,
+            transform(Vec3::new(1.0, 0.0, 0.0), Quat::identity(), Vec3::one()),
+        );
+        let grandchild = game.spawn_child(
+            child,
+            transform(
+                Vec3::new(0.0, 1.0, 0.0),
+                quarter_turn,
+                Vec3::new(0.5, 0.5, 1.0),
+            ),
+        );
+
+        game.update();
+
+        // The child is 2 metres along the parent's rotated x axis, which points up.
+        assert_near(
+            game.world.get::<GlobalTransform>(child).unwrap(),
+            &GlobalTransform {
+                position: Vec3::new(1.0, 4.0, 10.0),
+                rotation: quarter_turn,
+                scale: Vec3::new(2.0, 2.0, 1.0),
+            },
+        );
+        // And the grandchild 2 metres along the child's y axis, which points left.
+        assert_near(
+            game.world.get::<GlobalTransform>(grandchild).unwrap(),
+            &GlobalTransform {
+                position: Vec3::new(-1.0, 4.0, 10.0),
+                rotation: quarter_turn * quarter_turn,
+                scale: Vec3::new(1.0, 1.0, 1.0),
+            },
+        );
+
+        // Moving the parent moves its descendants on the next tick.
+        game.world.get_mut::<Position>(parent).unwrap().0.x = 3.0;
+        game.update();
+        assert_eq!(
+            game.world
+                .get::<GlobalTransform>(grandchild)
+                .unwrap()
+                .position
+                .x
+                .round(),
+            1.0
+        );
+    }
+
+    #[test]
+    fn despawned_children_are_skipped() {
+        let mut game = Game::new();
+        let parent = game.spawn((Position(Vec3::new(1.0, 0.0, 0.0)),));
+        let despawned = game.spawn_child(parent, (Position(Vec3::zero()),));
+        let child = game.spawn_child(parent, (Position(Vec3::new(0.0, 1.0, 0.0)),));
+        game.world.despawn(despawned);
+
+        game.update();
+
+        assert_eq!(
+            game.world.get::<GlobalTransform>(child).unwrap().position,
+            Vec3::new(1.0, 1.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn parents_without_a_position_are_at_the_origin() {
+        let mut game = Game::new();
+        let group = game.spawn((Scale(Vec3::new(2.0, 1.0, 1.0)),));
+        let middle = game.spawn_child(group, ());
+        let child = game.spawn_child(middle, (Position(Vec3::new(3.0, 1.0, 0.0)),));
+
+        game.update();
+
+        assert_eq!(
+            game.world.get::<GlobalTransform>(child).unwrap().position,
+            Vec3::new(6.0, 1.0, 0.0)
+        );
+    }
+}
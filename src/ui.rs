@@ -0,0 +1,335 @@
+use crate::app::WindowSize;
+use crate::asset::{SpriteCatalog, SpriteId, SpriteInfo};
+use crate::renderer::gpu_primitives::{Instance, InstanceRaw};
+use crate::renderer::sprite::PIXELS_PER_METRE;
+use crate::text::ScreenText;
+use bevy_ecs::world::World;
+use glam::{Quat, Vec2, Vec3};
+use serde::{Deserialize, Serialize};
+
+/// A point on the window, or on a widget, that widgets are placed relative to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Centre,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    /// How far across and down the anchor is, from 0 at the top left to 1 at the bottom right.
+    pub fn fraction(self) -> Vec2 {
+        match self {
+            Anchor::TopLeft => Vec2::new(0.0, 0.0),
+            Anchor::Top => Vec2::new(0.5, 0.0),
+            Anchor::TopRight => Vec2::new(1.0, 0.0),
+            Anchor::Left => Vec2::new(0.0, 0.5),
+            Anchor::Centre => Vec2::new(0.5, 0.5),
+            Anchor::Right => Vec2::new(1.0, 0.5),
+            Anchor::BottomLeft => Vec2::new(0.0, 1.0),
+            Anchor::Bottom => Vec2::new(0.5, 1.0),
+            Anchor::BottomRight => Vec2::new(1.0, 1.0),
+        }
+    }
+}
+
+/// Where a widget is on the screen, in pixels with y down. The widget's `anchor` point is placed
+/// on the window's `anchor` point and then moved by `offset`, so `Anchor::BottomRight` with an
+/// offset of (-16, -16) keeps a widget 16 pixels inside the bottom right corner at any window
+/// size. Widgets on higher layers are drawn over lower ones.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UiRect {
+    pub anchor: Anchor,
+    pub offset: Vec2,
+    pub size: Vec2,
+    pub layer: i32,
+}
+
+impl UiRect {
+    pub fn new(anchor: Anchor, offset: Vec2, size: Vec2) -> Self {
+        UiRect {
+            anchor,
+            offset,
+            size,
+            layer: 0,
+        }
+    }
+
+    pub fn with_layer(mut self, layer: i32) -> Self {
+        self.layer = layer;
+        self
+    }
+
+    /// Top left corner in pixels from the top left of the window.
+    pub fn top_left(&self, window: &WindowSize) -> Vec2 {
+        let window = Vec2::new(window.width as f32, window.height as f32);
+        let fraction = self.anchor.fraction();
+        window * fraction - self.size * fraction + self.offset
+    }
+
+    pub fn contains(&self, window: &WindowSize, point: Vec2) -> bool {
+        let min = self.top_left(window);
+        let max = min + self.size;
+        point.x >= min.x && point.y >= min.y && point.x < max.x && point.y < max.y
+    }
+}
+
+/// Stretches a frame of a sprite over the entity's `UiRect`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UiImage {
+    pub sprite: SpriteId,
+    pub frame: u8,
+}
+
+impl UiImage {
+    pub fn new(sprite: SpriteId) -> Self {
+        UiImage { sprite, frame: 0 }
+    }
+}
+
+/// Fills the entity's `UiRect` with a panel that keeps its corners at their drawn size. The
+/// sprite is a tileset of 3 x 3 frames, eg. loaded with `SpriteData::load_tileset` using a third
+/// of the image size: the corners are drawn as they are, the edges are stretched along the
+/// panel's sides and the centre fills the rest. Panels smaller than two corners shrink their
+/// corners to half the panel, leaving out the edges and centre.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NineSlice {
+    pub sprite: SpriteId,
+}
+
+impl NineSlice {
+    pub fn new(sprite: SpriteId) -> Self {
+        NineSlice { sprite }
+    }
+}
+
+/// Instances for every widget and `ScreenText`, in the order they are drawn. Panels and images
+/// are drawn before text on the same layer. Text with a `UiRect` is positioned from the rect's
+/// top left and drawn on its layer, text without one is on layer 0.
+pub(crate) fn build_overlay(world: &mut World) -> Vec<(SpriteId, InstanceRaw)> {
+    let mut image_query = world.query::<(&UiRect, &UiImage)>();
+    let mut panel_query = world.query::<(&UiRect, &NineSlice)>();
+    let mut text_query = world.query::<(&ScreenText, Option<&UiRect>)>();
+
+    let (catalog, window) = match (
+        world.get_resource::<SpriteCatalog>(),
+        world.get_resource::<WindowSize>(),
+    ) {
+        (Some(catalog), Some(window)) => (catalog, window),
+        _ => return vec![],
+    };
+
+    let mut overlay: Vec<(i32, SpriteId, InstanceRaw)> = vec![];
+
+    for (rect, panel) in panel_query.iter(world) {
+        if let Some(info) = catalog.get(panel.sprite) {
+            let top_left = rect.top_left(window);
+            let cell = Vec2::new(info.width as f32, info.height as f32)
+                .min(rect.size.max(Vec2::zero()) / 2.0);
+            let middle = rect.size - cell * 2.0;
+            let columns = [
+                (top_left.x, cell.x),
+                (top_left.x + cell.x, middle.x),
+                (top_left.x + cell.x + middle.x, cell.x),
+            ];
+            let rows = [
+                (top_left.y, cell.y),
+                (top_left.y + cell.y, middle.y),
+                (top_left.y + cell.y + middle.y, cell.y),
+            ];
+
+            for (row, (y, height)) in rows.iter().enumerate() {
+                for (column, (x, width)) in columns.iter().enumerate() {
+                    if *width <= 0.0 || *height <= 0.0 {
+                        continue;
+                    }
+                    let frame = (row * 3 + column) as u8;
+                    let quad = quad(info, frame, Vec2::new(*x, *y), Vec2::new(*width, *height));
+                    overlay.push((rect.layer, panel.sprite, quad));
+                }
+            }
+        }
+    }
+
+    for (rect, image) in image_query.iter(world) {
+        if let Some(info) = catalog.get(image.sprite) {
+            let quad = quad(info, image.frame, rect.top_left(window), rect.size);
+            overlay.push((rect.layer, image.sprite, quad));
+        }
+    }
+
+    for (text, rect) in text_query.iter(world) {
+        let metrics = match catalog.get(text.font).and_then(|info| info.font.as_ref()) {
+            Some(metrics) => metrics,
+            None => continue,
+        };
+        let (origin, layer) = rect
+            .map(|rect| (rect.top_left(window), rect.layer))
+            .unwrap_or((Vec2::zero(), 0));
+        let position = origin + text.position;
+
+        for (frame, offset) in metrics.layout(&text.value, text.align) {
+            overlay.push((
+                layer,
+                text.font,
+                InstanceRaw::from(Instance {
+                    position: Vec3::new(position.x, -position.y, 0.5),
+                    rotation: Quat::identity(),
+                    scale: Vec3::new(text.scale, text.scale, 1.0) * PIXELS_PER_METRE as f32,
+                    frame_id: frame,
+                    flip_x: false,
+                    flip_y: false,
+                    pivot_offset: offset / PIXELS_PER_METRE as f32,
+                }),
+            ));
+        }
+    }
+
+    // A stable sort keeps panels, then images, then text within each layer.
+    overlay.sort_by_key(|(layer, ..)| *layer);
+    overlay
+        .into_iter()
+        .map(|(_, id, instance)| (id, instance))
+        .collect()
+}
+
+/// A frame stretched over the rectangle at `top_left` of `size`, in window pixels.
+fn quad(info: &SpriteInfo, frame: u8, top_left: Vec2, size: Vec2) -> InstanceRaw {
+    let centre = top_left + size / 2.0;
+    let scale = size / Vec2::new(info.width as f32, info.height as f32) * PIXELS_PER_METRE as f32;
+    InstanceRaw::from(Instance {
+        position: Vec3::new(centre.x, -centre.y, 0.5),
+        rotation: Quat::identity(),
+        scale: scale.extend(1.0),
+        frame_id: frame,
+        flip_x: false,
+        flip_y: false,
+        pivot_offset: Vec2::zero(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: WindowSize = WindowSize {
+        width: 320,
+        height: 200,
+    };
+
+    /// A world with an 8 x 8 pixel panel tileset.
+    fn world() -> World {
+        let mut world = World::default();
+        world.insert_resource(SpriteCatalog(vec![SpriteInfo {
+            id: "panel".to_string(),
+            width: 8,
+            height: 8,
+            pivots: vec![],
+            font: None,
+        }]));
+        world.insert_resource(WINDOW);
+        world
+    }
+
+    /// Frame, top left and size in window pixels of each quad.
+    fn quads(world: &mut World) -> Vec<(u32, Vec2, Vec2)> {
+        build_overlay(world)
+            .iter()
+            .map(|(_, instance)| {
+                let model = instance.model();
+                let centre = model.transform_point3(Vec3::zero());
+                let size =
+                    Vec2::new(model.x_axis.x, model.y_axis.y) * 8.0 / PIXELS_PER_METRE as f32;
+                let top_left = Vec2::new(centre.x, -centre.y) - size / 2.0;
+                (instance.frame_id(), top_left, size)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rects_are_placed_by_their_anchor() {
+        let size = Vec2::new(40.0, 20.0);
+        let top_left = |anchor, offset| UiRect::new(anchor, offset, size).top_left(&WINDOW);
+        assert_eq!(
+            top_left(Anchor::TopLeft, Vec2::new(4.0, 2.0)),
+            Vec2::new(4.0, 2.0)
+        );
+        assert_eq!(top_left(Anchor::Top, Vec2::zero()), Vec2::new(140.0, 0.0));
+        assert_eq!(
+            top_left(Anchor::Centre, Vec2::zero()),
+            Vec2::new(140.0, 90.0)
+        );
+        assert_eq!(
+            top_left(Anchor::Right, Vec2::zero()),
+            Vec2::new(280.0, 90.0)
+        );
+        assert_eq!(
+            top_left(Anchor::BottomRight, Vec2::new(-16.0, -16.0)),
+            Vec2::new(264.0, 164.0)
+        );
+
+        let rect = UiRect::new(Anchor::BottomLeft, Vec2::zero(), size);
+        assert!(rect.contains(&WINDOW, Vec2::new(0.0, 180.0)));
+        assert!(rect.contains(&WINDOW, Vec2::new(39.0, 199.0)));
+        assert!(!rect.contains(&WINDOW, Vec2::new(40.0, 190.0)));
+        assert!(!rect.contains(&WINDOW, Vec2::new(10.0, 179.0)));
+    }
+
+    #[test]
+    fn panels_keep_their_corners_and_stretch_the_rest() {
+        let mut world = world();
+        let rect = UiRect::new(
+            Anchor::TopLeft,
+            Vec2::new(10.0, 20.0),
+            Vec2::new(40.0, 24.0),
+        );
+        world.spawn().insert_bundle((rect, NineSlice::new(0)));
+
+        let quads = quads(&mut world);
+        assert_eq!(quads.len(), 9);
+        assert_eq!(quads[0], (0, Vec2::new(10.0, 20.0), Vec2::new(8.0, 8.0)));
+        assert_eq!(quads[1], (1, Vec2::new(18.0, 20.0), Vec2::new(24.0, 8.0)));
+        assert_eq!(quads[4], (4, Vec2::new(18.0, 28.0), Vec2::new(24.0, 8.0)));
+        assert_eq!(quads[8], (8, Vec2::new(42.0, 36.0), Vec2::new(8.0, 8.0)));
+    }
+
+    #[test]
+    fn small_panels_shrink_their_corners_to_fit() {
+        let mut world = world();
+        let rect = UiRect::new(
+            Anchor::TopLeft,
+            Vec2::new(10.0, 20.0),
+            Vec2::new(10.0, 30.0),
+        );
+        world.spawn().insert_bundle((rect, NineSlice::new(0)));
+
+        // The columns are too narrow for two corners, so the corners take half the width each
+        // and only the left and right edges are stretched between them.
+        let quads = quads(&mut world);
+        assert_eq!(
+            quads,
+            vec![
+                (0, Vec2::new(10.0, 20.0), Vec2::new(5.0, 8.0)),
+                (2, Vec2::new(15.0, 20.0), Vec2::new(5.0, 8.0)),
+                (3, Vec2::new(10.0, 28.0), Vec2::new(5.0, 14.0)),
+                (5, Vec2::new(15.0, 28.0), Vec2::new(5.0, 14.0)),
+                (6, Vec2::new(10.0, 42.0), Vec2::new(5.0, 8.0)),
+                (8, Vec2::new(15.0, 42.0), Vec2::new(5.0, 8.0)),
+            ]
+        );
+
+        // Empty panels draw nothing.
+        world
+            .query::<&mut UiRect>()
+            .iter_mut(&mut world)
+            .for_each(|mut rect| {
+                rect.size = Vec2::zero();
+            });
+        assert!(self::quads(&mut world).is_empty());
+    }
+}
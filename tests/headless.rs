@@ -0,0 +1,131 @@
+use erlking::asset::{SpriteData, SpriteRegistry};
+use erlking::camera::{ActiveCamera, CameraTarget, ParallaxCamera, Viewport};
+use erlking::sprite::Sprite;
+use erlking::tilemap::{Tile, Tilemap};
+use erlking::{Game, Headless, Position, Rotation, Scale};
+use glam::{Quat, Vec2, Vec3};
+use image::{Rgba, RgbaImage};
+
+/// Draws with a GPU adapter, which may be a software one. Machines without any adapter skip the
+/// test.
+fn headless(game: &mut Game, sprites: SpriteRegistry) -> Option<Headless> {
+    let headless = futures::executor::block_on(Headless::new(game, sprites, 320, 240));
+    if headless.is_none() {
+        eprintln!("No wgpu adapter, skipping");
+    }
+    headless
+}
+
+#[test]
+fn draws_more_tiles_than_the_initial_instance_buffer_holds() {
+    let mut sprites = SpriteRegistry::new();
+    let tileset = sprites.insert(SpriteData {
+        id: "tiles".to_string(),
+        frames: vec![RgbaImage::from_pixel(8, 8, Rgba([200, 120, 40, 255]))],
+        pivots: vec![Vec2::new(0.5, 0.5)],
+        font: None,
+    });
+
+    let mut game = Game::new();
+    let mut headless = match headless(&mut game, sprites) {
+        Some(headless) => headless,
+        None => return,
+    };
+
+    // 1200 tiles of a quarter metre, all in view.
+    let tile = Some(Tile {
+        frame: 0,
+        solid: false,
+        flip_x: false,
+        flip_y: false,
+    });
+    let tilemap = Tilemap::from_tiles(tileset, 40, vec![tile; 40 * 30], Vec2::splat(0.25));
+    game.spawn((Position(Vec3::new(-5.0, 3.75, 0.0)), tilemap));
+    game.spawn((
+        ActiveCamera,
+        ParallaxCamera::new(
+            Vec3::new(0.0, 0.0, -10.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            45f32.to_radians(),
+            0.1,
+            100.0,
+        ),
+    ));
+    game.update();
+
+    let image = headless.capture(&mut game);
+    assert_eq!(image.dimensions(), (320, 240));
+    assert_eq!(game.render_stats().visible, 1200);
+    assert_eq!(*image.get_pixel(160, 120), Rgba([200, 120, 40, 255]));
+}
+
+#[test]
+fn cameras_draw_into_sprites_they_can_see() {
+    let mut sprites = SpriteRegistry::new();
+    let block = sprites.insert(SpriteData {
+        id: "block".to_string(),
+        frames: vec![RgbaImage::from_pixel(16, 16, Rgba([200, 120, 40, 255]))],
+        pivots: vec![Vec2::new(0.5, 0.5)],
+        font: None,
+    });
+    let monitor = sprites.insert(SpriteData {
+        id: "monitor".to_string(),
+        frames: vec![RgbaImage::from_pixel(64, 64, Rgba([40, 40, 220, 255]))],
+        pivots: vec![Vec2::new(0.5, 0.5)],
+        font: None,
+    });
+
+    let mut game = Game::new();
+    let mut headless = match headless(&mut game, sprites) {
+        Some(headless) => headless,
+        None => return,
+    };
+
+    let camera = ParallaxCamera::new(
+        Vec3::new(0.0, 0.0, -10.0),
+        Vec3::new(0.0, 0.0, 1.0),
+        45f32.to_radians(),
+        0.1,
+        100.0,
+    );
+    // Both cameras see the monitor in front of the block. Drawing the monitor into itself
+    // would read and write its texture in the same pass.
+    let sprite_at = |z: f32, sprite: Sprite| {
+        (
+            Position(Vec3::new(0.0, 0.0, z)),
+            Rotation(Quat::identity()),
+            Scale(Vec3::one()),
+            sprite,
+        )
+    };
+    game.spawn(sprite_at(0.0, Sprite::new(block)));
+    game.spawn(sprite_at(-5.0, Sprite::new(monitor)));
+    game.spawn((ActiveCamera, camera));
+    game.spawn((
+        ActiveCamera,
+        camera,
+        Viewport::default().with_target(CameraTarget::Sprite(monitor)),
+    ));
+    game.update();
+
+    let image = headless.capture(&mut game);
+    assert_eq!(*image.get_pixel(160, 120), Rgba([200, 120, 40, 255]));
+}
+
+#[test]
+fn captures_save_as_png() {
+    let mut game = Game::new();
+    let mut headless = match headless(&mut game, SpriteRegistry::new()) {
+        Some(headless) => headless,
+        None => return,
+    };
+
+    // Without cameras the frame is only the clear colour.
+    let image = headless.capture(&mut game);
+    let file = std::env::temp_dir().join("erlking_headless_capture.png");
+    image.save(&file).unwrap();
+
+    let saved = image::open(&file).unwrap().into_rgba8();
+    assert_eq!(saved.dimensions(), (320, 240));
+    assert_eq!(saved, image);
+}